@@ -0,0 +1,15 @@
+-- Custom OAuth provider definitions (built-in providers are defined in code)
+CREATE TABLE oauth_providers (
+    id INTEGER PRIMARY KEY,
+    provider_key TEXT NOT NULL UNIQUE,
+    display_name TEXT NOT NULL,
+    auth_url TEXT NOT NULL,
+    token_url TEXT NOT NULL,
+    revoke_url TEXT,
+    default_scopes TEXT NOT NULL DEFAULT '',
+    extra_params TEXT NOT NULL DEFAULT '{}',
+    client_secret_in_body BOOLEAN NOT NULL DEFAULT 0
+);
+
+-- Provider selected per credential; existing rows were always treated as Google
+ALTER TABLE service_credentials ADD COLUMN provider TEXT NOT NULL DEFAULT 'google';
@@ -0,0 +1,2 @@
+-- Scopes requested for this credential (space separated). NULL means the provider's default scopes
+ALTER TABLE service_credentials ADD COLUMN scopes TEXT;
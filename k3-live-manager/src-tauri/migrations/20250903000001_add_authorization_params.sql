@@ -0,0 +1,5 @@
+-- Per-credential authorization parameter overrides (JSON object, e.g. {"prompt":"consent","login_hint":"..."})
+ALTER TABLE service_credentials ADD COLUMN auth_params TEXT;
+
+-- Whether a flow for this provider must return a refresh token
+ALTER TABLE oauth_providers ADD COLUMN requires_refresh_token BOOLEAN NOT NULL DEFAULT 1;
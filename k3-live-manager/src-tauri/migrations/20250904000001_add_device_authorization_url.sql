@@ -0,0 +1,2 @@
+-- RFC 8628 device authorization endpoint; NULL when the provider has no device flow
+ALTER TABLE oauth_providers ADD COLUMN device_authorization_url TEXT;
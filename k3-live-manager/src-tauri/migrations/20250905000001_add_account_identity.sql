@@ -0,0 +1,8 @@
+-- Identity of the account a token was issued for (OIDC userinfo claims)
+ALTER TABLE oauth_tokens ADD COLUMN account_subject TEXT;
+ALTER TABLE oauth_tokens ADD COLUMN account_email TEXT;
+ALTER TABLE oauth_tokens ADD COLUMN account_name TEXT;
+ALTER TABLE oauth_tokens ADD COLUMN account_picture TEXT;
+
+-- OIDC userinfo endpoint; NULL when the provider has none
+ALTER TABLE oauth_providers ADD COLUMN userinfo_url TEXT;
@@ -0,0 +1,34 @@
+-- Linked accounts: one OAuth client credential can hold tokens for several accounts (e.g. a main channel and sub-channels)
+CREATE TABLE oauth_accounts (
+    id INTEGER PRIMARY KEY,
+    credentials_id INTEGER NOT NULL,
+    subject TEXT,
+    email TEXT,
+    name TEXT,
+    picture TEXT,
+    FOREIGN KEY (credentials_id) REFERENCES service_credentials (id) ON DELETE CASCADE
+);
+-- The same provider account is linked at most once per credential (accounts with an unknown subject are not deduplicated)
+CREATE UNIQUE INDEX idx_oauth_accounts_credentials_subject ON oauth_accounts(credentials_id, subject);
+
+-- Every existing token becomes an account with the same id, keeping the identity recorded on it
+INSERT INTO oauth_accounts (id, credentials_id, subject, email, name, picture)
+SELECT id, credentials_id, account_subject, account_email, account_name, account_picture FROM oauth_tokens;
+
+-- Tokens now belong to an account. SQLite cannot drop the uniquely indexed credentials_id, so the table is rebuilt
+CREATE TABLE oauth_tokens_new (
+    id INTEGER PRIMARY KEY,
+    account_id INTEGER NOT NULL,
+    access_token TEXT NOT NULL,
+    refresh_token TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    scope TEXT,
+    FOREIGN KEY (account_id) REFERENCES oauth_accounts (id) ON DELETE CASCADE
+);
+INSERT INTO oauth_tokens_new (id, account_id, access_token, refresh_token, expires_at, scope)
+SELECT id, id, access_token, refresh_token, expires_at, scope FROM oauth_tokens;
+DROP TABLE oauth_tokens;
+ALTER TABLE oauth_tokens_new RENAME TO oauth_tokens;
+
+-- One token per account
+CREATE UNIQUE INDEX idx_oauth_tokens_account_id ON oauth_tokens(account_id);
@@ -0,0 +1,6 @@
+-- Health of a stored token: 'active', or 'needs_reauth' once the provider rejected its refresh_token (invalid_grant)
+ALTER TABLE oauth_tokens ADD COLUMN status TEXT NOT NULL DEFAULT 'active';
+
+-- Last refresh failure (also set for transient errors while the status stays 'active'); cleared on success
+ALTER TABLE oauth_tokens ADD COLUMN last_error TEXT;
+ALTER TABLE oauth_tokens ADD COLUMN last_error_at TEXT;
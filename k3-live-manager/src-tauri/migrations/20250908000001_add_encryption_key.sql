@@ -0,0 +1,11 @@
+-- Data key that encrypts service_credentials.client_secret and oauth_tokens.access_token/refresh_token,
+-- wrapped by a key provider (passphrase + Argon2id). At most one row.
+-- Existing plaintext values are encrypted by the application when the key is first created or unlocked.
+CREATE TABLE encryption_key (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    provider TEXT NOT NULL,
+    params TEXT NOT NULL,
+    wrapped_key TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT (datetime('now')),
+    updated_at TEXT NOT NULL DEFAULT (datetime('now'))
+);
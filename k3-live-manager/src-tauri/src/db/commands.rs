@@ -1,97 +1,295 @@
-use crate::db::models::{AddCredentialPayload, ServiceCredential};
-use crate::db::setup::AppState;
-use tauri::State;
-use tokio::sync::oneshot;
-use crate::oauth_server;
-use serde::Serialize;
+// Tauri entry points. Each command resolves the app state and delegates to the same-named fn in `handlers`.
+use super::handlers::{self, AccessTokenStatus, CredentialDeletion, PassphraseSet};
+use crate::db::models::{
+    AddCredentialPayload, AddOAuthProviderPayload, ImportClientSecretPayload, OauthAccount, ServiceCredentialView, TokenAttention, UpdateCredentialPayload,
+};
+use crate::db::setup::AppRuntime;
+use crate::error::AppError;
+use crate::services::backup_service::{BackupInfo, RestoreStaged};
+use crate::services::client_secret::ClientSecretImport;
+use crate::services::encryption_service::EncryptionState;
+use crate::services::oauth_flow::{DeviceFlowStarted, OAuthFlowSnapshot, OAuthFlowStarted, DEFAULT_FLOW_TIMEOUT_SECS};
+use crate::services::oauth_provider::OAuthProvider;
+use crate::services::oauth_service::{LinkTarget, RevocationOutcome, ScopeCheck};
+use crate::services::startup::AppStatus;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tauri::{AppHandle, State};
+
+// --- App Commands ---
+/// Startup phase: `initializing`, `ready`, or `failed` with the error. Available before the app is ready;
+/// other commands return `not_ready`/`startup_failed` until then. Changes are also sent as `app-status` events.
+#[tauri::command]
+pub async fn get_app_status(
+    runtime: State<'_, AppRuntime>,
+) -> Result<AppStatus, AppError> {
+    Ok(runtime.status())
+}
+
+// --- Encryption Commands ---
+/// Whether the data key exists and is unlocked. Secrets can only be read or stored while `unlocked`.
+#[tauri::command]
+pub async fn get_encryption_state(
+    runtime: State<'_, AppRuntime>,
+) -> Result<EncryptionState, AppError> {
+    handlers::get_encryption_state(runtime.state()?).await
+}
+
+/// Set the first database passphrase: creates the data key, encrypts existing secrets and unlocks.
+/// Lists the backups and `.pre-restore` file made before, which still hold the secrets in plaintext.
+#[tauri::command]
+pub async fn set_database_passphrase(
+    passphrase: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<PassphraseSet, AppError> {
+    handlers::set_database_passphrase(runtime.state()?, &passphrase).await
+}
+
+#[tauri::command]
+pub async fn unlock_database(
+    passphrase: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<(), AppError> {
+    handlers::unlock_database(runtime.state()?, &passphrase).await
+}
+
+/// Re-wrap the data key with a new passphrase. Encrypted values are not rewritten.
+#[tauri::command]
+pub async fn change_database_passphrase(
+    current_passphrase: String,
+    new_passphrase: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<(), AppError> {
+    handlers::change_database_passphrase(runtime.state()?, &current_passphrase, &new_passphrase).await
+}
 
 // --- Credential Commands ---
 #[tauri::command]
 pub async fn get_service_credentials(
-    state: State<'_, AppState>,
-) -> Result<Vec<ServiceCredential>, String> {
-    state.credential_service.get_all_credentials().await.map_err(|e| e.to_string())
+    runtime: State<'_, AppRuntime>,
+) -> Result<Vec<ServiceCredentialView>, AppError> {
+    handlers::get_service_credentials(runtime.state()?).await
 }
 
 #[tauri::command]
 pub async fn add_service_credential(
     payload: AddCredentialPayload,
-    state: State<'_, AppState>,
-) -> Result<ServiceCredential, String> {
-    state.credential_service.add_credential(payload).await.map_err(|e| e.to_string())
+    runtime: State<'_, AppRuntime>,
+) -> Result<ServiceCredentialView, AppError> {
+    handlers::add_service_credential(runtime.state()?, payload).await
+}
+
+/// Return a credential's client secret. Only called when the user explicitly asks to see it.
+#[tauri::command]
+pub async fn reveal_client_secret(
+    credential_id: i64,
+    runtime: State<'_, AppRuntime>,
+) -> Result<String, AppError> {
+    handlers::reveal_client_secret(runtime.state()?, credential_id).await
+}
+
+/// Correct a credential's service name or client id; omitted fields are kept.
+#[tauri::command]
+pub async fn update_service_credential(
+    credential_id: i64,
+    payload: UpdateCredentialPayload,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ServiceCredentialView, AppError> {
+    handlers::update_service_credential(runtime.state()?, credential_id, payload).await
+}
+
+/// Replace a credential's client secret. Linked accounts and their tokens are kept.
+#[tauri::command]
+pub async fn rotate_client_secret(
+    credential_id: i64,
+    client_secret: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ServiceCredentialView, AppError> {
+    handlers::rotate_client_secret(runtime.state()?, credential_id, client_secret).await
+}
+
+/// Delete a credential together with its linked accounts and tokens.
+/// With `revoke_tokens`, every account's grant is revoked at the provider first (failures are reported per account).
+/// If an account could not be unlinked, the credential is kept and the error lists every account's outcome in `details`.
+#[tauri::command]
+pub async fn delete_service_credential(
+    credential_id: i64,
+    revoke_tokens: bool,
+    runtime: State<'_, AppRuntime>,
+) -> Result<CredentialDeletion, AppError> {
+    handlers::delete_service_credential(runtime.state()?, credential_id, revoke_tokens).await
+}
+
+/// Import a Google `client_secret_*.json` ("installed" or "web" client) as a credential.
+/// Returns the new credential with warnings about redirect URIs that still need registering.
+#[tauri::command]
+pub async fn import_client_secret_json(
+    payload: ImportClientSecretPayload,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ClientSecretImport, AppError> {
+    handlers::import_client_secret_json(runtime.state()?, payload).await
+}
+
+/// Set the scopes requested for a credential. An empty list falls back to the provider's defaults.
+#[tauri::command]
+pub async fn set_credential_scopes(
+    credential_id: i64,
+    scopes: Vec<String>,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ServiceCredentialView, AppError> {
+    handlers::set_credential_scopes(runtime.state()?, credential_id, scopes).await
+}
+
+/// Set per-credential authorization parameters (access_type, prompt, include_granted_scopes, login_hint, ...).
+/// An empty value removes a provider default; an empty map clears all overrides.
+#[tauri::command]
+pub async fn set_credential_auth_params(
+    credential_id: i64,
+    auth_params: BTreeMap<String, String>,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ServiceCredentialView, AppError> {
+    handlers::set_credential_auth_params(runtime.state()?, credential_id, auth_params).await
+}
+
+// --- Provider Commands ---
+#[tauri::command]
+pub async fn get_oauth_providers(
+    runtime: State<'_, AppRuntime>,
+) -> Result<Vec<OAuthProvider>, AppError> {
+    handlers::get_oauth_providers(runtime.state()?).await
+}
+
+#[tauri::command]
+pub async fn add_oauth_provider(
+    payload: AddOAuthProviderPayload,
+    runtime: State<'_, AppRuntime>,
+) -> Result<OAuthProvider, AppError> {
+    handlers::add_oauth_provider(runtime.state()?, payload).await
 }
 
 // --- OAuth Commands ---
+/// Link an account to the credential through the browser. Pass `account_id` to re-authorize an existing
+/// account; otherwise the token is stored under the account the user signs in as (added if new).
 #[tauri::command]
 pub async fn start_oauth_flow(
     credential_id: i64,
-    state: State<'_, AppState>,
-) -> Result<String, String> {
-    let (tx, rx) = oneshot::channel();
-    let port = 1421; // fixed by design
-    let redirect_url = format!("http://localhost:{}/oauth/callback", port);
-
-    // Generate the auth URL with state and the temporary server's redirect URL
-    let (auth_url, expected_state) = state
-        .oauth_service
-        .generate_auth_url(credential_id, &redirect_url)
-        .await
-        .map_err(|e| e.to_string())?;
-
-    // Spawn the server in a background task
-    tauri::async_runtime::spawn(async move {
-        // Start the OAuth server
-        if let Err(e) = oauth_server::start_oauth_server(tx, port).await {
-            eprintln!("OAuth server error: {:?}", e);
-            return;
-        }
-    });
-
-    // Spawn another task to wait for the callback and process the token
-    let oauth_service_clone = state.oauth_service.clone();
-    let expected_state_clone = expected_state.clone();
-    tauri::async_runtime::spawn(async move {
-        // Wait for the code and state from the server
-        match rx.await {
-            Ok((code, state_val)) => {
-                if state_val != expected_state_clone {
-                    eprintln!("State mismatch in OAuth callback. Potential CSRF.");
-                    return;
-                }
-                // Finalize OAuth with the received code
-                if let Err(e) = oauth_service_clone.exchange_code_and_save_token(code, credential_id, &redirect_url).await {
-                    eprintln!("Failed to exchange OAuth code: {}", e);
-                }
-            }
-            Err(e) => {
-                eprintln!("Failed to receive OAuth code: {}", e);
-            }
-        }
-    });
-
-    // Return the auth URL immediately
-    Ok(auth_url)
-}
-
-#[derive(Serialize)]
-pub struct AccessTokenInfo {
-    pub access_token: String,
-    pub expires_at: String,
-}
-
-/// Ensure a valid access token is available for the given credential.
+    account_id: Option<i64>,
+    timeout_secs: Option<u64>,
+    runtime: State<'_, AppRuntime>,
+) -> Result<OAuthFlowStarted, AppError> {
+    let timeout = Duration::from_secs(timeout_secs.unwrap_or(DEFAULT_FLOW_TIMEOUT_SECS));
+    handlers::start_oauth_flow(runtime.state()?, LinkTarget { credential_id, account_id }, timeout).await
+}
+
+/// Start an RFC 8628 device flow. The UI shows `user_code` and `verification_uri`;
+/// the backend polls the token endpoint and reports the outcome as `oauth-flow` events.
+/// `account_id` selects the account to re-authorize, as in `start_oauth_flow`.
+#[tauri::command]
+pub async fn start_device_flow(
+    credential_id: i64,
+    account_id: Option<i64>,
+    runtime: State<'_, AppRuntime>,
+) -> Result<DeviceFlowStarted, AppError> {
+    handlers::start_device_flow(runtime.state()?, LinkTarget { credential_id, account_id }).await
+}
+
+/// Cancel a flow that is still waiting for its callback. The callback listener is shut down.
+#[tauri::command]
+pub async fn cancel_oauth_flow(
+    flow_id: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<OAuthFlowSnapshot, AppError> {
+    handlers::cancel_oauth_flow(runtime.state()?, &flow_id)
+}
+
+#[tauri::command]
+pub async fn get_oauth_flow_status(
+    flow_id: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<OAuthFlowSnapshot, AppError> {
+    handlers::get_oauth_flow_status(runtime.state()?, &flow_id)
+}
+
+/// Report whether the account's token grants the scopes a feature needs.
+/// `needs_reconsent` tells the UI to prompt the user to authenticate again.
+#[tauri::command]
+pub async fn check_token_scopes(
+    account_id: i64,
+    required_scopes: Vec<String>,
+    runtime: State<'_, AppRuntime>,
+) -> Result<ScopeCheck, AppError> {
+    handlers::check_token_scopes(runtime.state()?, account_id, required_scopes).await
+}
+
+/// Accounts linked to the credential with their identity (subject, email, name, avatar) when known
+#[tauri::command]
+pub async fn get_linked_accounts(
+    credential_id: i64,
+    runtime: State<'_, AppRuntime>,
+) -> Result<Vec<OauthAccount>, AppError> {
+    handlers::get_linked_accounts(runtime.state()?, credential_id).await
+}
+
+/// Linked accounts that need attention: `needs_reauth` tokens (re-authenticate to fix) and
+/// tokens whose last refresh failed, with the credential they belong to
+#[tauri::command]
+pub async fn get_credentials_needing_attention(
+    runtime: State<'_, AppRuntime>,
+) -> Result<Vec<TokenAttention>, AppError> {
+    handlers::get_credentials_needing_attention(runtime.state()?).await
+}
+
+/// Unlink an account: revoke its token at the provider and delete the account and token locally.
+/// The local data is removed even if the provider call fails; see `provider_error` in the outcome.
+#[tauri::command]
+pub async fn revoke_account_token(
+    account_id: i64,
+    runtime: State<'_, AppRuntime>,
+) -> Result<RevocationOutcome, AppError> {
+    handlers::revoke_account_token(runtime.state()?, account_id).await
+}
+
+/// Ensure a valid access token is available for the given linked account.
 /// If the current token is expired or within skew seconds to expire, it will be refreshed.
 #[tauri::command]
 pub async fn ensure_valid_access_token(
-    credential_id: i64,
+    account_id: i64,
     skew_secs: i64,
-    state: State<'_, AppState>,
-) -> Result<AccessTokenInfo, String> {
-    let skew = if skew_secs < 0 { 0 } else { skew_secs as u64 };
-    let (access_token, expires_at) = state
-        .oauth_service
-        .ensure_valid_access_token(credential_id, skew)
-        .await
-        .map_err(|e| e.to_string())?;
-    Ok(AccessTokenInfo { access_token, expires_at })
-}
\ No newline at end of file
+    runtime: State<'_, AppRuntime>,
+) -> Result<AccessTokenStatus, AppError> {
+    handlers::ensure_valid_access_token(runtime.state()?, account_id, skew_secs).await
+}
+
+// --- Backup Commands ---
+/// Write a consistent copy of the database to the backup directory while the app keeps running.
+#[tauri::command]
+pub async fn create_backup(
+    runtime: State<'_, AppRuntime>,
+) -> Result<BackupInfo, AppError> {
+    handlers::create_backup(runtime.state()?).await
+}
+
+/// Manual and scheduled backups, newest first.
+#[tauri::command]
+pub async fn list_backups(
+    runtime: State<'_, AppRuntime>,
+) -> Result<Vec<BackupInfo>, AppError> {
+    handlers::list_backups(runtime.state()?)
+}
+
+/// Validate a backup (integrity and migration history) and stage it to replace the database.
+/// The swap happens on the next start; call `restart_app` to apply it right away.
+#[tauri::command]
+pub async fn restore_backup(
+    file_name: String,
+    runtime: State<'_, AppRuntime>,
+) -> Result<RestoreStaged, AppError> {
+    handlers::restore_backup(runtime.state()?, &file_name).await
+}
+
+/// Restart the app, e.g. to apply a staged restore. The restart goes through `RunEvent::Exit`,
+/// so the exit handler stops background tasks and lets a backup being written finish first.
+#[tauri::command]
+pub fn restart_app(app_handle: AppHandle) {
+    app_handle.request_restart();
+}
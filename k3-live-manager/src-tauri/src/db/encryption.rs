@@ -0,0 +1,284 @@
+use super::models::WrappedKey;
+use crate::error::{AppError, ErrorCode};
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroizing;
+
+// Stored form of an encrypted column: `enc:v1:<base64(nonce || ciphertext || tag)>`
+pub const ENCRYPTED_PREFIX: &str = "enc:v1:";
+
+// Secret columns. The column name is the AAD, so a value copied into another column fails to decrypt.
+pub const CLIENT_SECRET_COLUMN: &str = "service_credentials.client_secret";
+pub const ACCESS_TOKEN_COLUMN: &str = "oauth_tokens.access_token";
+pub const REFRESH_TOKEN_COLUMN: &str = "oauth_tokens.refresh_token";
+
+// AAD of the wrapped data key
+const WRAPPED_KEY_AAD: &[u8] = b"k3-live-manager data key";
+const NONCE_LEN: usize = 12;
+
+pub fn is_encrypted(value: &str) -> bool {
+    value.starts_with(ENCRYPTED_PREFIX)
+}
+
+// Returned while the data key is not available; commands surface it so the UI can ask for the passphrase
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DatabaseLocked;
+
+impl std::fmt::Display for DatabaseLocked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The database is locked; unlock it with the passphrase first")
+    }
+}
+
+impl std::error::Error for DatabaseLocked {}
+
+// 256-bit key that encrypts the secret columns. Never stored unwrapped.
+pub struct DataKey(Zeroizing<[u8; 32]>);
+
+impl DataKey {
+    pub fn generate() -> Self {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(bytes.as_mut());
+        Self(bytes)
+    }
+
+    fn from_slice(bytes: &[u8]) -> anyhow::Result<Self> {
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| anyhow::anyhow!("Data key has the wrong length"))?;
+        Ok(Self(Zeroizing::new(bytes)))
+    }
+}
+
+// Seal/open `data` with AES-256-GCM under `key`; the output is nonce || ciphertext || tag
+fn seal(key: &[u8; 32], aad: &[u8], data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, Payload { msg: data, aad })
+        .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+    Ok([nonce.as_slice(), &ciphertext].concat())
+}
+
+fn open(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Option<Zeroizing<Vec<u8>>> {
+    if sealed.len() < NONCE_LEN {
+        return None;
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad }).ok().map(Zeroizing::new)
+}
+
+// Encrypts and decrypts column values with the unwrapped data key
+pub struct FieldCipher {
+    key: DataKey,
+}
+
+impl FieldCipher {
+    pub fn new(key: DataKey) -> Self {
+        Self { key }
+    }
+
+    pub fn encrypt(&self, column: &str, plaintext: &str) -> anyhow::Result<String> {
+        let sealed = seal(&self.key.0, column.as_bytes(), plaintext.as_bytes())?;
+        Ok(format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(sealed)))
+    }
+
+    // Plaintext values are rejected: every row is encrypted before the key is handed to the repository
+    pub fn decrypt(&self, column: &str, stored: &str) -> anyhow::Result<String> {
+        let encoded = stored
+            .strip_prefix(ENCRYPTED_PREFIX)
+            .ok_or_else(|| anyhow::anyhow!("{} is not encrypted", column))?;
+        let sealed = BASE64.decode(encoded).map_err(|_| anyhow::anyhow!("{} is not valid ciphertext", column))?;
+        let plaintext = open(&self.key.0, column.as_bytes(), &sealed)
+            .ok_or_else(|| anyhow::anyhow!("{} could not be decrypted (wrong key or tampered value)", column))?;
+        Ok(String::from_utf8(plaintext.to_vec())?)
+    }
+}
+
+// Holds the field cipher once the data key has been unwrapped. Shared by the repository and the encryption service.
+#[derive(Default)]
+pub struct KeyVault {
+    cipher: RwLock<Option<Arc<FieldCipher>>>,
+}
+
+impl KeyVault {
+    pub fn locked() -> Self {
+        Self::default()
+    }
+
+    pub fn unlocked(key: DataKey) -> Self {
+        let vault = Self::default();
+        vault.unlock(FieldCipher::new(key));
+        vault
+    }
+
+    pub fn unlock(&self, cipher: FieldCipher) {
+        *self.cipher.write().unwrap() = Some(Arc::new(cipher));
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.cipher.read().unwrap().is_some()
+    }
+
+    pub fn cipher(&self) -> Result<Arc<FieldCipher>, DatabaseLocked> {
+        self.cipher.read().unwrap().clone().ok_or(DatabaseLocked)
+    }
+}
+
+// Protects the data key. The wrapped key and the provider's parameters (salt, KDF cost, ...) are stored
+// in the `encryption_key` table; other providers (OS keychain, hardware token) can implement this trait.
+pub trait KeyProvider: Send + Sync {
+    // Stored in `encryption_key.provider` so the right provider is asked to unwrap
+    fn kind(&self) -> &'static str;
+    fn wrap(&self, key: &DataKey) -> anyhow::Result<WrappedKey>;
+    fn unwrap(&self, wrapped: &WrappedKey) -> anyhow::Result<DataKey>;
+}
+
+pub const PASSPHRASE_PROVIDER: &str = "passphrase";
+
+// Minimum passphrase length accepted when setting or changing the passphrase
+pub const MIN_PASSPHRASE_LEN: usize = 8;
+
+// Argon2id cost parameters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    // Memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    // OWASP's recommended Argon2id configuration (19 MiB, 2 iterations, 1 lane)
+    fn default() -> Self {
+        Self { m_cost: 19 * 1024, t_cost: 2, p_cost: 1 }
+    }
+}
+
+// Cheap KDF cost so tests stay fast in debug builds
+#[cfg(test)]
+pub const TEST_KDF: KdfParams = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+
+#[derive(Serialize, Deserialize)]
+struct PassphraseParams {
+    kdf: String,
+    #[serde(flatten)]
+    cost: KdfParams,
+    salt: String,
+}
+
+// Wraps the data key with a key derived from the user's passphrase (Argon2id)
+pub struct PassphraseKeyProvider {
+    passphrase: Zeroizing<String>,
+    params: KdfParams,
+}
+
+impl PassphraseKeyProvider {
+    pub fn new(passphrase: &str) -> Self {
+        Self::with_params(passphrase, KdfParams::default())
+    }
+
+    // Cost parameters only apply when wrapping; unwrapping uses the ones stored with the key
+    pub fn with_params(passphrase: &str, params: KdfParams) -> Self {
+        Self { passphrase: Zeroizing::new(passphrase.to_string()), params }
+    }
+
+    fn derive(&self, cost: KdfParams, salt: &[u8]) -> anyhow::Result<Zeroizing<[u8; 32]>> {
+        let params = Params::new(cost.m_cost, cost.t_cost, cost.p_cost, Some(32)).map_err(|e| anyhow::anyhow!("Invalid KDF parameters: {}", e))?;
+        let mut kek = Zeroizing::new([0u8; 32]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(self.passphrase.as_bytes(), salt, kek.as_mut())
+            .map_err(|e| anyhow::anyhow!("Key derivation failed: {}", e))?;
+        Ok(kek)
+    }
+}
+
+impl KeyProvider for PassphraseKeyProvider {
+    fn kind(&self) -> &'static str {
+        PASSPHRASE_PROVIDER
+    }
+
+    fn wrap(&self, key: &DataKey) -> anyhow::Result<WrappedKey> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let kek = self.derive(self.params, &salt)?;
+        let sealed = seal(&kek, WRAPPED_KEY_AAD, key.0.as_ref())?;
+        let params = PassphraseParams { kdf: "argon2id".to_string(), cost: self.params, salt: BASE64.encode(salt) };
+        Ok(WrappedKey {
+            provider: PASSPHRASE_PROVIDER.to_string(),
+            params: serde_json::to_string(&params)?,
+            wrapped_key: BASE64.encode(sealed),
+        })
+    }
+
+    fn unwrap(&self, wrapped: &WrappedKey) -> anyhow::Result<DataKey> {
+        let params: PassphraseParams = serde_json::from_str(&wrapped.params)?;
+        if params.kdf != "argon2id" {
+            anyhow::bail!("Unsupported key derivation function '{}'", params.kdf);
+        }
+        let salt = BASE64.decode(&params.salt)?;
+        let kek = self.derive(params.cost, &salt)?;
+        let sealed = BASE64.decode(&wrapped.wrapped_key)?;
+        let key = open(&kek, WRAPPED_KEY_AAD, &sealed).ok_or_else(|| AppError::new(ErrorCode::IncorrectPassphrase, "Incorrect passphrase"))?;
+        DataKey::from_slice(&key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_values_round_trip_and_are_bound_to_their_column() {
+        let cipher = FieldCipher::new(DataKey::generate());
+        let stored = cipher.encrypt(ACCESS_TOKEN_COLUMN, "ya29.token").unwrap();
+        assert!(is_encrypted(&stored));
+        assert!(!stored.contains("ya29"));
+        assert_eq!(cipher.decrypt(ACCESS_TOKEN_COLUMN, &stored).unwrap(), "ya29.token");
+        // A fresh nonce per value
+        assert_ne!(stored, cipher.encrypt(ACCESS_TOKEN_COLUMN, "ya29.token").unwrap());
+
+        assert!(cipher.decrypt(REFRESH_TOKEN_COLUMN, &stored).is_err());
+        assert!(cipher.decrypt(ACCESS_TOKEN_COLUMN, "ya29.token").is_err());
+        assert!(FieldCipher::new(DataKey::generate()).decrypt(ACCESS_TOKEN_COLUMN, &stored).is_err());
+
+        // Flipping a ciphertext bit fails authentication
+        let mut sealed = BASE64.decode(stored.strip_prefix(ENCRYPTED_PREFIX).unwrap()).unwrap();
+        sealed[NONCE_LEN] ^= 1;
+        let tampered = format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(sealed));
+        assert!(cipher.decrypt(ACCESS_TOKEN_COLUMN, &tampered).is_err());
+    }
+
+    #[test]
+    fn passphrase_wraps_and_unwraps_the_data_key() {
+        let key = DataKey::generate();
+        let stored = FieldCipher::new(DataKey::from_slice(key.0.as_ref()).unwrap()).encrypt(CLIENT_SECRET_COLUMN, "secret").unwrap();
+
+        let provider = PassphraseKeyProvider::with_params("correct horse", TEST_KDF);
+        let wrapped = provider.wrap(&key).unwrap();
+        assert_eq!(wrapped.provider, PASSPHRASE_PROVIDER);
+        assert!(wrapped.params.contains(r#""kdf":"argon2id""#));
+
+        let unwrapped = provider.unwrap(&wrapped).unwrap();
+        assert_eq!(FieldCipher::new(unwrapped).decrypt(CLIENT_SECRET_COLUMN, &stored).unwrap(), "secret");
+
+        let err = PassphraseKeyProvider::with_params("wrong horse", TEST_KDF).unwrap(&wrapped).err().unwrap();
+        assert_eq!(err.to_string(), "Incorrect passphrase");
+
+        // Every wrap uses a new salt
+        assert_ne!(provider.wrap(&key).unwrap().params, wrapped.params);
+    }
+
+    #[test]
+    fn vault_reports_locked_until_a_key_is_supplied() {
+        let vault = KeyVault::locked();
+        assert_eq!(vault.cipher().err(), Some(DatabaseLocked));
+        vault.unlock(FieldCipher::new(DataKey::generate()));
+        assert!(vault.is_unlocked());
+    }
+}
@@ -0,0 +1,200 @@
+// Bodies of the Tauri commands in `commands.rs`, as plain async fns over `AppState`.
+// Each returns exactly what its command sends to the webview, so tests can check the real responses.
+use crate::db::models::{
+    AddCredentialPayload, AddOAuthProviderPayload, ImportClientSecretPayload, OauthAccount, ServiceCredentialView, TokenAttention, UpdateCredentialPayload,
+};
+use crate::db::setup::AppState;
+use crate::error::AppError;
+use crate::services::backup_service::{BackupInfo, BackupKind, RestoreStaged};
+use crate::services::client_secret::ClientSecretImport;
+use crate::services::encryption_service::EncryptionState;
+use crate::services::oauth_flow::{DeviceFlowStarted, OAuthFlowSnapshot, OAuthFlowStarted};
+use crate::services::oauth_provider::OAuthProvider;
+use crate::services::oauth_service::{LinkTarget, RevocationOutcome, ScopeCheck};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// --- Encryption ---
+pub async fn get_encryption_state(state: &AppState) -> Result<EncryptionState, AppError> {
+    state.encryption_service.state().await.map_err(AppError::from)
+}
+
+// Copies of the database written before the passphrase was set; the UI asks the user to delete them
+#[derive(Debug, Serialize)]
+pub struct PassphraseSet {
+    pub unencrypted_copies: Vec<String>,
+}
+
+pub async fn set_database_passphrase(state: &AppState, passphrase: &str) -> Result<PassphraseSet, AppError> {
+    state.encryption_service.set_passphrase(passphrase).await.map_err(AppError::from)?;
+    // The passphrase is already set at this point, so a listing failure is only logged
+    let copies = state.backup_service.database_copies().unwrap_or_else(|e| {
+        eprintln!("Failed to list copies of the database: {:#}", e);
+        Vec::new()
+    });
+    Ok(PassphraseSet { unencrypted_copies: copies.iter().map(|p| p.display().to_string()).collect() })
+}
+
+pub async fn unlock_database(state: &AppState, passphrase: &str) -> Result<(), AppError> {
+    state.encryption_service.unlock_with_passphrase(passphrase).await.map_err(AppError::from)
+}
+
+pub async fn change_database_passphrase(state: &AppState, current_passphrase: &str, new_passphrase: &str) -> Result<(), AppError> {
+    state
+        .encryption_service
+        .change_passphrase(current_passphrase, new_passphrase)
+        .await
+        .map_err(AppError::from)
+}
+
+// --- Credentials ---
+// Credentials are returned as ServiceCredentialView: the client secret is never sent to the webview
+// except through `reveal_client_secret`.
+pub async fn get_service_credentials(state: &AppState) -> Result<Vec<ServiceCredentialView>, AppError> {
+    let creds = state.credential_service.get_all_credentials().await.map_err(AppError::from)?;
+    Ok(creds.into_iter().map(ServiceCredentialView::from).collect())
+}
+
+pub async fn add_service_credential(state: &AppState, payload: AddCredentialPayload) -> Result<ServiceCredentialView, AppError> {
+    state.credential_service.add_credential(payload).await.map(Into::into).map_err(AppError::from)
+}
+
+pub async fn reveal_client_secret(state: &AppState, credential_id: i64) -> Result<String, AppError> {
+    state.credential_service.reveal_client_secret(credential_id).await.map_err(AppError::from)
+}
+
+pub async fn update_service_credential(
+    state: &AppState,
+    credential_id: i64,
+    payload: UpdateCredentialPayload,
+) -> Result<ServiceCredentialView, AppError> {
+    state.credential_service.update_credential(credential_id, payload).await.map(Into::into).map_err(AppError::from)
+}
+
+pub async fn rotate_client_secret(state: &AppState, credential_id: i64, client_secret: String) -> Result<ServiceCredentialView, AppError> {
+    state.credential_service.rotate_client_secret(credential_id, client_secret).await.map(Into::into).map_err(AppError::from)
+}
+
+#[derive(Debug, Serialize)]
+pub struct CredentialDeletion {
+    pub credential_id: i64,
+    // One outcome per linked account when `revoke_tokens` was set
+    pub revocations: Vec<RevocationOutcome>,
+}
+
+pub async fn delete_service_credential(state: &AppState, credential_id: i64, revoke_tokens: bool) -> Result<CredentialDeletion, AppError> {
+    let revocations = if revoke_tokens {
+        state.oauth_service.revoke_credential_accounts(credential_id).await.map_err(AppError::from)?
+    } else {
+        Vec::new()
+    };
+    // Keep the credential while an account is still linked: a retry needs its client id and secret to revoke it
+    if let Some(error) = revocations.iter().find_map(|o| o.error.as_ref()) {
+        return Err(AppError::new(error.code, "Some linked accounts could not be unlinked; the credential was kept")
+            .retryable(error.retryable)
+            .with_details(serde_json::json!({ "credential_id": credential_id, "revocations": revocations })));
+    }
+    state.credential_service.delete_credential(credential_id).await.map_err(AppError::from)?;
+    Ok(CredentialDeletion { credential_id, revocations })
+}
+
+pub async fn import_client_secret_json(state: &AppState, payload: ImportClientSecretPayload) -> Result<ClientSecretImport, AppError> {
+    state.credential_service.import_client_secret(payload, &state.callback_ports).await.map_err(AppError::from)
+}
+
+pub async fn set_credential_scopes(state: &AppState, credential_id: i64, scopes: Vec<String>) -> Result<ServiceCredentialView, AppError> {
+    state.credential_service.set_credential_scopes(credential_id, scopes).await.map(Into::into).map_err(AppError::from)
+}
+
+pub async fn set_credential_auth_params(
+    state: &AppState,
+    credential_id: i64,
+    auth_params: BTreeMap<String, String>,
+) -> Result<ServiceCredentialView, AppError> {
+    state.credential_service.set_credential_auth_params(credential_id, auth_params).await.map(Into::into).map_err(AppError::from)
+}
+
+// --- Providers ---
+pub async fn get_oauth_providers(state: &AppState) -> Result<Vec<OAuthProvider>, AppError> {
+    state.provider_registry.get_all_providers().await.map_err(AppError::from)
+}
+
+pub async fn add_oauth_provider(state: &AppState, payload: AddOAuthProviderPayload) -> Result<OAuthProvider, AppError> {
+    state.provider_registry.add_custom_provider(payload).await.map_err(AppError::from)
+}
+
+// --- OAuth ---
+pub async fn start_oauth_flow(state: &AppState, target: LinkTarget, timeout: Duration) -> Result<OAuthFlowStarted, AppError> {
+    state
+        .oauth_flows
+        .start_authorization(state.oauth_service.clone(), &state.callback_ports, target, timeout)
+        .await
+        .map_err(AppError::from)
+}
+
+pub async fn start_device_flow(state: &AppState, target: LinkTarget) -> Result<DeviceFlowStarted, AppError> {
+    state
+        .oauth_flows
+        .start_device_authorization(state.oauth_service.clone(), target)
+        .await
+        .map_err(AppError::from)
+}
+
+pub fn cancel_oauth_flow(state: &AppState, flow_id: &str) -> Result<OAuthFlowSnapshot, AppError> {
+    state.oauth_flows.cancel(flow_id).map_err(AppError::from)
+}
+
+pub fn get_oauth_flow_status(state: &AppState, flow_id: &str) -> Result<OAuthFlowSnapshot, AppError> {
+    state.oauth_flows.get(flow_id).ok_or_else(|| AppError::not_found("OAuth flow"))
+}
+
+pub async fn check_token_scopes(state: &AppState, account_id: i64, required_scopes: Vec<String>) -> Result<ScopeCheck, AppError> {
+    state
+        .oauth_service
+        .check_token_scopes(account_id, required_scopes)
+        .await
+        .map_err(AppError::from)
+}
+
+pub async fn get_linked_accounts(state: &AppState, credential_id: i64) -> Result<Vec<OauthAccount>, AppError> {
+    state.oauth_service.list_accounts(credential_id).await.map_err(AppError::from)
+}
+
+pub async fn get_credentials_needing_attention(state: &AppState) -> Result<Vec<TokenAttention>, AppError> {
+    state.oauth_service.list_tokens_needing_attention().await.map_err(AppError::from)
+}
+
+pub async fn revoke_account_token(state: &AppState, account_id: i64) -> Result<RevocationOutcome, AppError> {
+    state.oauth_service.revoke_token(account_id).await.map_err(AppError::from)
+}
+
+// The token itself stays in the backend, which makes the API calls
+#[derive(Debug, Serialize)]
+pub struct AccessTokenStatus {
+    pub account_id: i64,
+    pub expires_at: String,
+}
+
+pub async fn ensure_valid_access_token(state: &AppState, account_id: i64, skew_secs: i64) -> Result<AccessTokenStatus, AppError> {
+    let skew = if skew_secs < 0 { 0 } else { skew_secs as u64 };
+    let (_, expires_at) = state
+        .oauth_service
+        .ensure_valid_access_token(account_id, skew)
+        .await
+        .map_err(AppError::from)?;
+    Ok(AccessTokenStatus { account_id, expires_at })
+}
+
+// --- Backups ---
+pub async fn create_backup(state: &AppState) -> Result<BackupInfo, AppError> {
+    state.backup_service.create_backup(BackupKind::Manual).await.map_err(AppError::from)
+}
+
+pub fn list_backups(state: &AppState) -> Result<Vec<BackupInfo>, AppError> {
+    state.backup_service.list_backups().map_err(AppError::from)
+}
+
+pub async fn restore_backup(state: &AppState, file_name: &str) -> Result<RestoreStaged, AppError> {
+    state.backup_service.stage_restore(file_name).await.map_err(AppError::from)
+}
@@ -0,0 +1,321 @@
+use super::setup::validate_database;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+pub const DB_FILE_NAME: &str = "app.sqlite";
+
+// Overrides the database file, e.g. `K3_DB_PATH=/tmp/k3.sqlite`
+pub const DB_PATH_ENV: &str = "K3_DB_PATH";
+
+// Same override on the command line: `--db-path <file>` or `--db-path=<file>`. Wins over the env var.
+pub const DB_PATH_ARG: &str = "--db-path";
+
+// Where earlier builds put the database: relative to the working directory
+pub const LEGACY_DB_PATH: &str = "../app.sqlite";
+
+// SQLite keeps uncommitted pages next to the main file; they move with it
+const SIDECAR_SUFFIXES: &[&str] = &["-wal", "-shm", "-journal"];
+
+// Validated backup waiting to replace the database on the next start
+const STAGED_RESTORE_SUFFIX: &str = ".restore";
+
+// The database a restore replaced, kept until the next restore
+const PRE_RESTORE_SUFFIX: &str = ".pre-restore";
+
+// Backups live next to the database
+pub const BACKUP_DIR_NAME: &str = "backups";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DatabaseSource {
+    CommandLine,
+    Environment,
+    AppData,
+}
+
+// Resolved location of the application database file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DatabaseLocation {
+    pub path: PathBuf,
+    pub source: DatabaseSource,
+}
+
+impl DatabaseLocation {
+    // `--db-path` beats `K3_DB_PATH`, which beats `<app data dir>/app.sqlite`
+    pub fn resolve<I>(args: I, env: Option<OsString>, app_data_dir: &Path) -> anyhow::Result<Self>
+    where
+        I: IntoIterator<Item = OsString>,
+    {
+        if let Some(path) = path_from_args(args)? {
+            return Ok(Self { path, source: DatabaseSource::CommandLine });
+        }
+        match env {
+            Some(path) if path.is_empty() => anyhow::bail!("{} is set but empty", DB_PATH_ENV),
+            Some(path) => Ok(Self { path: PathBuf::from(path), source: DatabaseSource::Environment }),
+            None => Ok(Self { path: app_data_dir.join(DB_FILE_NAME), source: DatabaseSource::AppData }),
+        }
+    }
+
+    // Location for this process: its arguments and environment, with `app_data_dir` as the default
+    pub fn from_env(app_data_dir: &Path) -> anyhow::Result<Self> {
+        Self::resolve(std::env::args_os().skip(1), std::env::var_os(DB_PATH_ENV), app_data_dir)
+    }
+
+    // Open read-write and create the file when missing (`mode=rwc`).
+    // Built from the path rather than a URL so `?`/`%` in directory names need no escaping.
+    pub fn connect_options(&self) -> SqliteConnectOptions {
+        SqliteConnectOptions::new().filename(&self.path).create_if_missing(true)
+    }
+
+    // Create the directory the database lives in; the app data dir does not exist on first launch
+    pub fn ensure_parent_dir(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)
+                .map_err(|e| anyhow::anyhow!("Failed to create database directory {}: {}", parent.display(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn backup_dir(&self) -> PathBuf {
+        self.path.parent().unwrap_or_else(|| Path::new("")).join(BACKUP_DIR_NAME)
+    }
+
+    pub fn staged_restore_path(&self) -> PathBuf {
+        with_suffix(&self.path, STAGED_RESTORE_SUFFIX)
+    }
+
+    pub fn pre_restore_path(&self) -> PathBuf {
+        with_suffix(&self.path, PRE_RESTORE_SUFFIX)
+    }
+
+    // Swap in a restore staged by `BackupService` before the pool opens the file.
+    // The current database and its sidecars become `<db>.pre-restore`. Returns whether a restore was applied.
+    pub fn apply_staged_restore(&self) -> anyhow::Result<bool> {
+        let staged = self.staged_restore_path();
+        if !staged.is_file() {
+            return Ok(false);
+        }
+        let previous = self.pre_restore_path();
+        for path in std::iter::once(previous.clone()).chain(SIDECAR_SUFFIXES.iter().map(|s| with_suffix(&previous, s))) {
+            if path.exists() {
+                std::fs::remove_file(&path).map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", path.display(), e))?;
+            }
+        }
+        if self.path.exists() {
+            move_file(&self.path, &previous)?;
+        }
+        // Pages in the old WAL belong to the old file, never to the restored one
+        for suffix in SIDECAR_SUFFIXES {
+            let sidecar = with_suffix(&self.path, suffix);
+            if sidecar.exists() {
+                move_file(&sidecar, &with_suffix(&previous, suffix))?;
+            }
+        }
+        move_file(&staged, &self.path)?;
+        Ok(true)
+    }
+
+    // One-time move of a database left at `legacy` by earlier builds.
+    // Only the default location adopts it, never over an existing database, and only when it is a database of this app:
+    // `legacy` is relative to the working directory, which may hold another program's `app.sqlite`.
+    // Returns whether a file was moved.
+    pub async fn migrate_legacy(&self, legacy: &Path) -> anyhow::Result<bool> {
+        if self.source != DatabaseSource::AppData || self.path.exists() || !legacy.is_file() {
+            return Ok(false);
+        }
+        if let Err(e) = validate_database(legacy).await {
+            eprintln!("Not adopting {} as the legacy database: {:#}", legacy.display(), e);
+            return Ok(false);
+        }
+        self.ensure_parent_dir()?;
+        move_file(legacy, &self.path)?;
+        for suffix in SIDECAR_SUFFIXES {
+            let sidecar = with_suffix(legacy, suffix);
+            if sidecar.exists() {
+                move_file(&sidecar, &with_suffix(&self.path, suffix))?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+fn path_from_args<I>(args: I) -> anyhow::Result<Option<PathBuf>>
+where
+    I: IntoIterator<Item = OsString>,
+{
+    let mut args = args.into_iter();
+    let mut path = None;
+    while let Some(arg) = args.next() {
+        let value = if arg == DB_PATH_ARG {
+            args.next().ok_or_else(|| anyhow::anyhow!("{} requires a file path", DB_PATH_ARG))?
+        } else if let Some(value) = arg.to_str().and_then(|a| a.strip_prefix(DB_PATH_ARG)?.strip_prefix('=')) {
+            OsString::from(value)
+        } else {
+            continue;
+        };
+        if value.is_empty() {
+            anyhow::bail!("{} requires a file path", DB_PATH_ARG);
+        }
+        // The last occurrence wins, as with most CLIs
+        path = Some(PathBuf::from(value));
+    }
+    Ok(path)
+}
+
+pub(crate) fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// Rename, falling back to copy + remove when the app data dir is on another filesystem
+fn move_file(from: &Path, to: &Path) -> anyhow::Result<()> {
+    if std::fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    std::fs::copy(from, to)
+        .map_err(|e| anyhow::anyhow!("Failed to copy {} to {}: {}", from.display(), to.display(), e))?;
+    std::fs::remove_file(from).map_err(|e| anyhow::anyhow!("Failed to remove {}: {}", from.display(), e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn args(list: &[&str]) -> Vec<OsString> {
+        list.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn resolves_app_data_dir_by_default() {
+        let location = DatabaseLocation::resolve(args(&["--other"]), None, Path::new("/data/k3")).unwrap();
+        assert_eq!(location.path, Path::new("/data/k3").join("app.sqlite"));
+        assert_eq!(location.source, DatabaseSource::AppData);
+    }
+
+    #[test]
+    fn command_line_beats_env_var() {
+        let env = Some(OsString::from("/env/k3.sqlite"));
+        let from_env = DatabaseLocation::resolve(args(&[]), env.clone(), Path::new("/data")).unwrap();
+        assert_eq!((from_env.path.as_path(), from_env.source), (Path::new("/env/k3.sqlite"), DatabaseSource::Environment));
+
+        let split = DatabaseLocation::resolve(args(&["--db-path", "/cli/a.sqlite"]), env.clone(), Path::new("/data")).unwrap();
+        assert_eq!((split.path.as_path(), split.source), (Path::new("/cli/a.sqlite"), DatabaseSource::CommandLine));
+        let joined = DatabaseLocation::resolve(args(&["--db-path=/cli/b.sqlite"]), env, Path::new("/data")).unwrap();
+        assert_eq!(joined.path, Path::new("/cli/b.sqlite"));
+    }
+
+    #[test]
+    fn rejects_empty_overrides() {
+        assert!(DatabaseLocation::resolve(args(&["--db-path"]), None, Path::new("/data")).is_err());
+        assert!(DatabaseLocation::resolve(args(&["--db-path="]), None, Path::new("/data")).is_err());
+        assert!(DatabaseLocation::resolve(args(&[]), Some(OsString::new()), Path::new("/data")).is_err());
+    }
+
+    // A database of this app at `path`, with a marker row to tell copies apart
+    async fn k3_database(path: &Path, marker: &str) {
+        let pool = crate::db::setup::connect_with(SqliteConnectOptions::new().filename(path).create_if_missing(true)).await.unwrap();
+        sqlx::query("INSERT INTO oauth_providers (provider_key, display_name, auth_url, token_url) VALUES (?, 'P', 'a', 't')")
+            .bind(marker)
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+    }
+
+    async fn has_marker(path: &Path, marker: &str) -> bool {
+        let pool = crate::db::setup::connect_with(SqliteConnectOptions::new().filename(path)).await.unwrap();
+        let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM oauth_providers WHERE provider_key = ?")
+            .bind(marker)
+            .fetch_optional(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        found.is_some()
+    }
+
+    #[tokio::test]
+    async fn moves_legacy_database_once() {
+        let dir = TempDir::new("location-legacy");
+        let legacy = dir.path().join("legacy.sqlite");
+        k3_database(&legacy, "legacy").await;
+        std::fs::write(with_suffix(&legacy, "-journal"), b"").unwrap();
+        let location = DatabaseLocation::resolve(args(&[]), None, &dir.path().join("app-data")).unwrap();
+
+        assert!(location.migrate_legacy(&legacy).await.unwrap());
+        assert!(with_suffix(&location.path, "-journal").exists());
+        assert!(has_marker(&location.path, "legacy").await);
+        assert!(!legacy.exists());
+
+        // A later legacy file never replaces the database in use
+        k3_database(&legacy, "stale").await;
+        assert!(!location.migrate_legacy(&legacy).await.unwrap());
+        assert!(!has_marker(&location.path, "stale").await);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_programs_databases_alone() {
+        let dir = TempDir::new("location-foreign");
+        let location = DatabaseLocation::resolve(args(&[]), None, &dir.path().join("app-data")).unwrap();
+
+        // Another program's SQLite file, and a file that is not SQLite at all
+        let foreign = dir.path().join("foreign.sqlite");
+        let options = SqliteConnectOptions::new().filename(&foreign).create_if_missing(true);
+        let pool = sqlx::SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query("CREATE TABLE notes (body TEXT)").execute(&pool).await.unwrap();
+        pool.close().await;
+        let garbage = dir.path().join("garbage.sqlite");
+        std::fs::write(&garbage, b"not a database").unwrap();
+
+        for legacy in [&foreign, &garbage] {
+            assert!(!location.migrate_legacy(legacy).await.unwrap());
+            assert!(legacy.exists());
+            assert!(!location.path.exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn overridden_location_leaves_legacy_database_alone() {
+        let dir = TempDir::new("location-override");
+        let legacy = dir.path().join("legacy.sqlite");
+        k3_database(&legacy, "legacy").await;
+        let target = dir.path().join("override.sqlite");
+        let location = DatabaseLocation::resolve(args(&[]), Some(target.clone().into_os_string()), dir.path()).unwrap();
+
+        assert!(!location.migrate_legacy(&legacy).await.unwrap());
+        assert!(legacy.exists());
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn staged_restore_replaces_database_and_keeps_previous() {
+        let dir = TempDir::new("location-restore");
+        let location = DatabaseLocation::resolve(args(&[]), None, dir.path()).unwrap();
+        assert!(!location.apply_staged_restore().unwrap());
+
+        std::fs::write(&location.path, b"current").unwrap();
+        std::fs::write(with_suffix(&location.path, "-wal"), b"current wal").unwrap();
+        std::fs::write(location.staged_restore_path(), b"backup").unwrap();
+        assert!(location.apply_staged_restore().unwrap());
+
+        assert_eq!(std::fs::read(&location.path).unwrap(), b"backup");
+        assert!(!with_suffix(&location.path, "-wal").exists());
+        assert_eq!(std::fs::read(location.pre_restore_path()).unwrap(), b"current");
+        assert_eq!(std::fs::read(with_suffix(&location.pre_restore_path(), "-wal")).unwrap(), b"current wal");
+        assert!(!location.staged_restore_path().exists());
+        assert_eq!(location.backup_dir(), dir.path().join("backups"));
+    }
+
+    #[tokio::test]
+    async fn creates_missing_directory_and_database() {
+        let dir = TempDir::new("location-create");
+        let location = DatabaseLocation::resolve(args(&[]), None, &dir.path().join("nested").join("app-data")).unwrap();
+        location.ensure_parent_dir().unwrap();
+
+        let pool = crate::db::setup::connect_with(location.connect_options()).await.unwrap();
+        pool.close().await;
+        assert!(location.path.is_file());
+    }
+}
@@ -1,4 +1,7 @@
 pub mod models;
+pub mod encryption;
+pub mod location;
 pub mod setup;
 pub mod repositories;
+pub mod handlers;
 pub mod commands;
\ No newline at end of file
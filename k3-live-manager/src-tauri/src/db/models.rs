@@ -1,5 +1,6 @@
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use std::collections::BTreeMap;
 
 // users テーブルの構造体
 #[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
@@ -10,23 +11,180 @@ pub struct User {
 }
 
 // service_credentials テーブルの構造体
-#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+// 秘密を含むため Serialize しない。フロントエンドへは ServiceCredentialView を返す
+#[derive(FromRow, Clone)]
 pub struct ServiceCredential {
     pub id: i64,
     pub service_name: String,
     pub client_id: String,
     pub client_secret: String,
+    pub provider: String,
+    pub scopes: Option<String>,
+    pub auth_params: Option<String>,
 }
 
-// oauth_tokens テーブルの構造体
+impl std::fmt::Debug for ServiceCredential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServiceCredential")
+            .field("id", &self.id)
+            .field("service_name", &self.service_name)
+            .field("client_id", &self.client_id)
+            .field("client_secret", &REDACTED)
+            .field("provider", &self.provider)
+            .field("scopes", &self.scopes)
+            .field("auth_params", &self.auth_params)
+            .finish()
+    }
+}
+
+const REDACTED: &str = "[redacted]";
+
+// Secrets shorter than this get no hint, so the hint never gives away a large part of the value
+const SECRET_HINT_MIN_LEN: usize = 12;
+
+// 資格情報のフロントエンド向けビュー: client_secret は返さず、有無と末尾4文字のみ
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct ServiceCredentialView {
+    pub id: i64,
+    pub service_name: String,
+    pub client_id: String,
+    pub provider: String,
+    pub scopes: Option<String>,
+    pub auth_params: Option<String>,
+    pub has_secret: bool,
+    // Last 4 characters of the secret, for telling credentials apart
+    pub secret_hint: Option<String>,
+}
+
+impl From<ServiceCredential> for ServiceCredentialView {
+    fn from(cred: ServiceCredential) -> Self {
+        let secret: Vec<char> = cred.client_secret.chars().collect();
+        let secret_hint = (secret.len() >= SECRET_HINT_MIN_LEN).then(|| secret[secret.len() - 4..].iter().collect());
+        Self {
+            id: cred.id,
+            service_name: cred.service_name,
+            client_id: cred.client_id,
+            provider: cred.provider,
+            scopes: cred.scopes,
+            auth_params: cred.auth_params,
+            has_secret: !secret.is_empty(),
+            secret_hint,
+        }
+    }
+}
+
+// encryption_key テーブルの構造体 (キープロバイダでラップしたデータキー)
+#[derive(Debug, FromRow, Clone)]
+pub struct WrappedKey {
+    // キープロバイダの種類 (例: "passphrase")
+    pub provider: String,
+    // プロバイダ固有のパラメータ (JSON。KDF のコストや salt 等)
+    pub params: String,
+    pub wrapped_key: String,
+}
+
+// oauth_providers テーブルの構造体 (カスタムプロバイダ定義)
 #[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
-pub struct OauthToken {
+pub struct OAuthProviderRow {
+    pub id: i64,
+    pub provider_key: String,
+    pub display_name: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub revoke_url: Option<String>,
+    pub default_scopes: String,
+    pub extra_params: String,
+    pub client_secret_in_body: bool,
+    pub requires_refresh_token: bool,
+    pub device_authorization_url: Option<String>,
+    pub userinfo_url: Option<String>,
+}
+
+// oauth_accounts テーブルの構造体 (資格情報ごとの連携アカウント)
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct OauthAccount {
     pub id: i64,
     pub credentials_id: i64,
+    // Filled from userinfo after the code/device exchange; None when the provider has none or the lookup failed
+    pub subject: Option<String>,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+}
+
+// oauth_tokens テーブルの構造体
+// トークンを含むため Serialize しない
+#[derive(FromRow, Clone)]
+pub struct OauthToken {
+    pub id: i64,
+    pub account_id: i64,
     pub access_token: String,
     pub refresh_token: String,
     pub expires_at: String,
     pub scope: Option<String>,
+    pub status: TokenStatus,
+    // Last refresh failure and when it happened (UTC "YYYY-MM-DD HH:MM:SS"); cleared by the next success
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+}
+
+impl std::fmt::Debug for OauthToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OauthToken")
+            .field("id", &self.id)
+            .field("account_id", &self.account_id)
+            .field("access_token", &REDACTED)
+            .field("refresh_token", &REDACTED)
+            .field("expires_at", &self.expires_at)
+            .field("scope", &self.scope)
+            .field("status", &self.status)
+            .field("last_error", &self.last_error)
+            .field("last_error_at", &self.last_error_at)
+            .finish()
+    }
+}
+
+// oauth_tokens.status
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+pub enum TokenStatus {
+    Active,
+    // The provider rejected the refresh_token (invalid_grant); only a new authorization helps
+    NeedsReauth,
+}
+
+// Linked account whose token needs the user's attention, with the credential it belongs to
+#[derive(Debug, FromRow, Serialize, Deserialize, Clone)]
+pub struct TokenAttention {
+    pub credential_id: i64,
+    pub service_name: String,
+    pub account_id: i64,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub status: TokenStatus,
+    pub last_error: Option<String>,
+    pub last_error_at: Option<String>,
+}
+
+// Account a token belongs to, as reported by the provider's userinfo endpoint
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AccountIdentity {
+    pub subject: String,
+    pub email: Option<String>,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+}
+
+impl OauthAccount {
+    pub fn identity(&self) -> Option<AccountIdentity> {
+        self.subject.as_ref().map(|subject| AccountIdentity {
+            subject: subject.clone(),
+            email: self.email.clone(),
+            name: self.name.clone(),
+            picture: self.picture.clone(),
+        })
+    }
 }
 
 // フロントエンドからデータを受け取るための構造体 (ペイロード)
@@ -35,14 +193,121 @@ pub struct AddCredentialPayload {
     pub service_name: String,
     pub client_id: String,
     pub client_secret: String,
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+    #[serde(default)]
+    pub auth_params: Option<BTreeMap<String, String>>,
+}
+
+// 資格情報の修正用ペイロード。None の項目は変更しない
+// client_secret の変更は rotate_client_secret、scopes/auth_params は専用コマンドで行う
+#[derive(Debug, Deserialize, Default)]
+pub struct UpdateCredentialPayload {
+    #[serde(default)]
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub client_id: Option<String>,
+}
+
+// Google Cloud Console の client_secret_*.json を取り込むためのペイロード
+#[derive(Debug, Deserialize)]
+pub struct ImportClientSecretPayload {
+    // ファイルの中身（JSON文字列）
+    pub contents: String,
+    // 省略時は project_id を使用
+    #[serde(default)]
+    pub service_name: Option<String>,
+    #[serde(default)]
+    pub scopes: Option<Vec<String>>,
+}
+
+fn default_provider() -> String {
+    "google".to_string()
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddOAuthProviderPayload {
+    pub provider_key: String,
+    pub display_name: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub revoke_url: Option<String>,
+    #[serde(default)]
+    pub default_scopes: Vec<String>,
+    #[serde(default)]
+    pub extra_params: BTreeMap<String, String>,
+    #[serde(default)]
+    pub client_secret_in_body: bool,
+    #[serde(default = "default_true")]
+    pub requires_refresh_token: bool,
+    #[serde(default)]
+    pub device_authorization_url: Option<String>,
+    #[serde(default)]
+    pub userinfo_url: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 #[allow(dead_code)]
 pub struct AddTokenPayload {
-    pub credentials_id: i64,
+    pub account_id: i64,
     pub access_token: String,
     pub refresh_token: String,
     pub expires_at: String,
     pub scope: Option<String>,
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credential(secret: &str) -> ServiceCredential {
+        ServiceCredential {
+            id: 1,
+            service_name: "youtube".to_string(),
+            client_id: "id".to_string(),
+            client_secret: secret.to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
+        }
+    }
+
+    #[test]
+    fn view_shows_only_a_hint_of_long_secrets() {
+        let view = ServiceCredentialView::from(credential("GOCSPX-abcdefgh"));
+        assert!(view.has_secret);
+        assert_eq!(view.secret_hint.as_deref(), Some("efgh"));
+
+        let short = ServiceCredentialView::from(credential("short-secr"));
+        assert!(short.has_secret);
+        assert_eq!(short.secret_hint, None);
+
+        let empty = ServiceCredentialView::from(credential(""));
+        assert!(!empty.has_secret);
+    }
+
+    #[test]
+    fn debug_output_redacts_secrets() {
+        let cred = format!("{:?}", credential("GOCSPX-abcdefgh"));
+        assert!(!cred.contains("GOCSPX") && cred.contains(REDACTED), "{}", cred);
+
+        let token = OauthToken {
+            id: 1,
+            account_id: 1,
+            access_token: "access-value".to_string(),
+            refresh_token: "refresh-value".to_string(),
+            expires_at: "never".to_string(),
+            scope: None,
+            status: TokenStatus::Active,
+            last_error: None,
+            last_error_at: None,
+        };
+        let token = format!("{:?}", token);
+        assert!(!token.contains("access-value") && !token.contains("refresh-value"), "{}", token);
+    }
+}
@@ -1,6 +1,12 @@
-use super::models::{AddCredentialPayload, AddTokenPayload, OauthToken, ServiceCredential};
+use crate::error::AppError;
+use super::encryption::{FieldCipher, KeyVault, ACCESS_TOKEN_COLUMN, CLIENT_SECRET_COLUMN, ENCRYPTED_PREFIX, REFRESH_TOKEN_COLUMN};
+use super::models::{
+    AccountIdentity, AddCredentialPayload, AddOAuthProviderPayload, AddTokenPayload, OAuthProviderRow, OauthAccount, OauthToken, ServiceCredential,
+    TokenAttention, TokenStatus, UpdateCredentialPayload, WrappedKey,
+};
 use async_trait::async_trait;
 use sqlx::SqlitePool;
+use std::sync::Arc;
 
 // --- Credential Repository ---
 #[async_trait]
@@ -8,23 +14,113 @@ pub trait CredentialRepository {
     async fn get_all_credentials(&self) -> anyhow::Result<Vec<ServiceCredential>>;
     async fn add_credential(&self, payload: AddCredentialPayload) -> anyhow::Result<ServiceCredential>;
     async fn get_credential_by_id(&self, id: i64) -> anyhow::Result<Option<ServiceCredential>>;
+    async fn update_credential_scopes(&self, id: i64, scopes: Option<String>) -> anyhow::Result<Option<ServiceCredential>>;
+    async fn update_credential_auth_params(&self, id: i64, auth_params: Option<String>) -> anyhow::Result<Option<ServiceCredential>>;
+    // Fields left as None keep their value. Fails with a `Conflict` AppError when the new name is in use.
+    async fn update_credential(&self, id: i64, payload: UpdateCredentialPayload) -> anyhow::Result<Option<ServiceCredential>>;
+    async fn update_client_secret(&self, id: i64, client_secret: &str) -> anyhow::Result<Option<ServiceCredential>>;
+    // Also deletes the credential's accounts and their tokens. Returns false when there was no credential to delete
+    async fn delete_credential(&self, id: i64) -> anyhow::Result<bool>;
+}
+
+// --- Account Repository ---
+#[async_trait]
+pub trait AccountRepository {
+    async fn get_accounts_by_credential_id(&self, credential_id: i64) -> anyhow::Result<Vec<OauthAccount>>;
+    async fn get_account_by_id(&self, id: i64) -> anyhow::Result<Option<OauthAccount>>;
+    async fn get_account_by_subject(&self, credential_id: i64, subject: &str) -> anyhow::Result<Option<OauthAccount>>;
+    async fn add_account(&self, credential_id: i64, identity: Option<&AccountIdentity>) -> anyhow::Result<OauthAccount>;
+    // None clears the identity
+    async fn update_account_identity(&self, id: i64, identity: Option<&AccountIdentity>) -> anyhow::Result<()>;
+    // Also deletes the account's token. Returns false when there was no account to delete
+    async fn delete_account(&self, id: i64) -> anyhow::Result<bool>;
 }
 
 // --- Token Repository ---
 #[async_trait]
 pub trait TokenRepository {
     async fn upsert_token(&self, payload: AddTokenPayload) -> anyhow::Result<OauthToken>;
-    async fn get_token_by_credential_id(&self, credential_id: i64) -> anyhow::Result<Option<OauthToken>>;
+    async fn get_token_by_account_id(&self, account_id: i64) -> anyhow::Result<Option<OauthToken>>;
+    async fn get_all_tokens(&self) -> anyhow::Result<Vec<OauthToken>>;
+    // Record a failed refresh; the status stays `Active` for transient errors
+    async fn record_token_error(&self, account_id: i64, status: TokenStatus, error: &str) -> anyhow::Result<()>;
+    // Tokens that need re-authorization or whose last refresh failed
+    async fn get_tokens_needing_attention(&self) -> anyhow::Result<Vec<TokenAttention>>;
+}
+
+// --- Provider Repository ---
+#[async_trait]
+pub trait ProviderRepository {
+    async fn get_all_providers(&self) -> anyhow::Result<Vec<OAuthProviderRow>>;
+    async fn add_provider(&self, payload: AddOAuthProviderPayload) -> anyhow::Result<OAuthProviderRow>;
+    async fn get_provider_by_key(&self, provider_key: &str) -> anyhow::Result<Option<OAuthProviderRow>>;
+}
+
+// --- Encryption Key Repository ---
+#[async_trait]
+pub trait EncryptionKeyRepository {
+    async fn get_wrapped_key(&self) -> anyhow::Result<Option<WrappedKey>>;
+    // Fails when a key already exists
+    async fn insert_wrapped_key(&self, key: &WrappedKey) -> anyhow::Result<()>;
+    // Re-wrapped key (passphrase change); the data key itself is unchanged
+    async fn update_wrapped_key(&self, key: &WrappedKey) -> anyhow::Result<()>;
+    // Encrypt secret columns still stored as plaintext and purge the old values from the file; returns the number of rows changed
+    async fn encrypt_plaintext_secrets(&self, cipher: &FieldCipher) -> anyhow::Result<u64>;
+}
+
+// Turn a UNIQUE constraint violation into a readable `Conflict` error; other failures pass through
+fn unique_conflict(err: sqlx::Error, conflict: impl FnOnce() -> AppError) -> anyhow::Error {
+    match &err {
+        sqlx::Error::Database(db) if db.is_unique_violation() => conflict().into(),
+        _ => err.into(),
+    }
+}
+
+fn service_name_conflict(err: sqlx::Error, service_name: &str) -> anyhow::Error {
+    unique_conflict(err, || {
+        AppError::conflict(format!("A credential named '{}' already exists", service_name))
+            .with_details(serde_json::json!({ "service_name": service_name }))
+    })
 }
 
 // --- Concrete Implementation ---
+// Secret columns (client_secret, access_token, refresh_token) are encrypted on write and decrypted on read
+// with the vault's data key; while the vault is locked those reads and writes fail with `DatabaseLocked`.
 pub struct SqliteRepository {
     pool: SqlitePool,
+    vault: Arc<KeyVault>,
 }
 
 impl SqliteRepository {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+    pub fn new(pool: SqlitePool, vault: Arc<KeyVault>) -> Self {
+        Self { pool, vault }
+    }
+
+    fn cipher(&self) -> anyhow::Result<Arc<FieldCipher>> {
+        Ok(self.vault.cipher()?)
+    }
+
+    fn decrypt_credential(&self, mut cred: ServiceCredential) -> anyhow::Result<ServiceCredential> {
+        cred.client_secret = self.cipher()?.decrypt(CLIENT_SECRET_COLUMN, &cred.client_secret)?;
+        Ok(cred)
+    }
+
+    fn decrypt_token(&self, mut token: OauthToken) -> anyhow::Result<OauthToken> {
+        let cipher = self.cipher()?;
+        token.access_token = cipher.decrypt(ACCESS_TOKEN_COLUMN, &token.access_token)?;
+        token.refresh_token = cipher.decrypt(REFRESH_TOKEN_COLUMN, &token.refresh_token)?;
+        Ok(token)
+    }
+
+    // The rewritten rows' plaintext survives in freed space until it is overwritten: rebuild the file so a copy of
+    // the database no longer holds it. The checkpoint truncates the WAL if the file was switched to WAL mode.
+    async fn purge_freed_pages(&self) -> anyhow::Result<()> {
+        sqlx::query("VACUUM").execute(&self.pool).await?;
+        let (busy, _, _): (i64, i64, i64) = sqlx::query_as("PRAGMA wal_checkpoint(TRUNCATE)").fetch_one(&self.pool).await?;
+        if busy != 0 {
+            eprintln!("The WAL could not be truncated while other connections were reading; it is cleared on the next checkpoint");
+        }
+        Ok(())
     }
 }
 
@@ -34,19 +130,23 @@ impl CredentialRepository for SqliteRepository {
         let creds = sqlx::query_as::<_, ServiceCredential>("SELECT * FROM service_credentials")
             .fetch_all(&self.pool)
             .await?;
-        Ok(creds)
+        creds.into_iter().map(|c| self.decrypt_credential(c)).collect()
     }
 
     async fn add_credential(&self, payload: AddCredentialPayload) -> anyhow::Result<ServiceCredential> {
         let cred = sqlx::query_as::<_, ServiceCredential>(
-            "INSERT INTO service_credentials (service_name, client_id, client_secret) VALUES (?, ?, ?) RETURNING *",
+            "INSERT INTO service_credentials (service_name, client_id, client_secret, provider, scopes, auth_params) VALUES (?, ?, ?, ?, ?, ?) RETURNING *",
         )
-        .bind(payload.service_name)
+        .bind(&payload.service_name)
         .bind(payload.client_id)
-        .bind(payload.client_secret)
+        .bind(self.cipher()?.encrypt(CLIENT_SECRET_COLUMN, &payload.client_secret)?)
+        .bind(payload.provider)
+        .bind(payload.scopes.map(|s| s.join(" ")))
+        .bind(payload.auth_params.map(|p| serde_json::to_string(&p)).transpose()?)
         .fetch_one(&self.pool)
-        .await?;
-        Ok(cred)
+        .await
+        .map_err(|e| service_name_conflict(e, &payload.service_name))?;
+        self.decrypt_credential(cred)
     }
 
     async fn get_credential_by_id(&self, id: i64) -> anyhow::Result<Option<ServiceCredential>> {
@@ -54,58 +154,333 @@ impl CredentialRepository for SqliteRepository {
             .bind(id)
             .fetch_optional(&self.pool)
             .await?;
-        Ok(cred)
+        cred.map(|c| self.decrypt_credential(c)).transpose()
+    }
+
+    async fn update_credential_scopes(&self, id: i64, scopes: Option<String>) -> anyhow::Result<Option<ServiceCredential>> {
+        let cred = sqlx::query_as::<_, ServiceCredential>("UPDATE service_credentials SET scopes = ? WHERE id = ? RETURNING *")
+            .bind(scopes)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        cred.map(|c| self.decrypt_credential(c)).transpose()
+    }
+
+    async fn update_credential_auth_params(&self, id: i64, auth_params: Option<String>) -> anyhow::Result<Option<ServiceCredential>> {
+        let cred = sqlx::query_as::<_, ServiceCredential>("UPDATE service_credentials SET auth_params = ? WHERE id = ? RETURNING *")
+            .bind(auth_params)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        cred.map(|c| self.decrypt_credential(c)).transpose()
+    }
+
+    async fn update_credential(&self, id: i64, payload: UpdateCredentialPayload) -> anyhow::Result<Option<ServiceCredential>> {
+        let cred = sqlx::query_as::<_, ServiceCredential>(
+            "UPDATE service_credentials SET service_name = COALESCE(?, service_name), client_id = COALESCE(?, client_id) WHERE id = ? RETURNING *",
+        )
+        .bind(&payload.service_name)
+        .bind(&payload.client_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| service_name_conflict(e, payload.service_name.as_deref().unwrap_or_default()))?;
+        cred.map(|c| self.decrypt_credential(c)).transpose()
+    }
+
+    async fn update_client_secret(&self, id: i64, client_secret: &str) -> anyhow::Result<Option<ServiceCredential>> {
+        let cred = sqlx::query_as::<_, ServiceCredential>("UPDATE service_credentials SET client_secret = ? WHERE id = ? RETURNING *")
+            .bind(self.cipher()?.encrypt(CLIENT_SECRET_COLUMN, client_secret)?)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        cred.map(|c| self.decrypt_credential(c)).transpose()
+    }
+
+    async fn delete_credential(&self, id: i64) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM service_credentials WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+#[async_trait]
+impl AccountRepository for SqliteRepository {
+    async fn get_accounts_by_credential_id(&self, credential_id: i64) -> anyhow::Result<Vec<OauthAccount>> {
+        let accounts = sqlx::query_as::<_, OauthAccount>("SELECT * FROM oauth_accounts WHERE credentials_id = ? ORDER BY id")
+            .bind(credential_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(accounts)
+    }
+
+    async fn get_account_by_id(&self, id: i64) -> anyhow::Result<Option<OauthAccount>> {
+        let account = sqlx::query_as::<_, OauthAccount>("SELECT * FROM oauth_accounts WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(account)
+    }
+
+    async fn get_account_by_subject(&self, credential_id: i64, subject: &str) -> anyhow::Result<Option<OauthAccount>> {
+        let account = sqlx::query_as::<_, OauthAccount>("SELECT * FROM oauth_accounts WHERE credentials_id = ? AND subject = ?")
+            .bind(credential_id)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(account)
+    }
+
+    async fn add_account(&self, credential_id: i64, identity: Option<&AccountIdentity>) -> anyhow::Result<OauthAccount> {
+        let account = sqlx::query_as::<_, OauthAccount>(
+            "INSERT INTO oauth_accounts (credentials_id, subject, email, name, picture) VALUES (?, ?, ?, ?, ?) RETURNING *",
+        )
+        .bind(credential_id)
+        .bind(identity.map(|i| &i.subject))
+        .bind(identity.and_then(|i| i.email.as_ref()))
+        .bind(identity.and_then(|i| i.name.as_ref()))
+        .bind(identity.and_then(|i| i.picture.as_ref()))
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(account)
+    }
+
+    async fn update_account_identity(&self, id: i64, identity: Option<&AccountIdentity>) -> anyhow::Result<()> {
+        sqlx::query("UPDATE oauth_accounts SET subject = ?, email = ?, name = ?, picture = ? WHERE id = ?")
+            .bind(identity.map(|i| &i.subject))
+            .bind(identity.and_then(|i| i.email.as_ref()))
+            .bind(identity.and_then(|i| i.name.as_ref()))
+            .bind(identity.and_then(|i| i.picture.as_ref()))
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_account(&self, id: i64) -> anyhow::Result<bool> {
+        let result = sqlx::query("DELETE FROM oauth_accounts WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
     }
 }
 
 #[async_trait]
 impl TokenRepository for SqliteRepository {
+    // A freshly granted or refreshed token is healthy again, so the status and last error are reset
     async fn upsert_token(&self, payload: AddTokenPayload) -> anyhow::Result<OauthToken> {
+        let cipher = self.cipher()?;
         let token = sqlx::query_as::<_, OauthToken>(
             r#"
-            INSERT INTO oauth_tokens (credentials_id, access_token, refresh_token, expires_at, scope) 
+            INSERT INTO oauth_tokens (account_id, access_token, refresh_token, expires_at, scope) 
             VALUES (?, ?, ?, ?, ?)
-            ON CONFLICT(credentials_id) DO UPDATE SET
+            ON CONFLICT(account_id) DO UPDATE SET
                 access_token = excluded.access_token,
                 refresh_token = excluded.refresh_token,
                 expires_at = excluded.expires_at,
-                scope = excluded.scope
+                scope = excluded.scope,
+                status = 'active',
+                last_error = NULL,
+                last_error_at = NULL
             RETURNING *
             "#,
         )
-        .bind(payload.credentials_id)
-        .bind(payload.access_token)
-        .bind(payload.refresh_token)
+        .bind(payload.account_id)
+        .bind(cipher.encrypt(ACCESS_TOKEN_COLUMN, &payload.access_token)?)
+        .bind(cipher.encrypt(REFRESH_TOKEN_COLUMN, &payload.refresh_token)?)
         .bind(payload.expires_at)
         .bind(payload.scope)
         .fetch_one(&self.pool)
         .await?;
-        Ok(token)
+        self.decrypt_token(token)
     }
 
-    async fn get_token_by_credential_id(&self, credential_id: i64) -> anyhow::Result<Option<OauthToken>> {
-        let token = sqlx::query_as::<_, OauthToken>("SELECT * FROM oauth_tokens WHERE credentials_id = ?")
-            .bind(credential_id)
+    async fn get_token_by_account_id(&self, account_id: i64) -> anyhow::Result<Option<OauthToken>> {
+        let token = sqlx::query_as::<_, OauthToken>("SELECT * FROM oauth_tokens WHERE account_id = ?")
+            .bind(account_id)
             .fetch_optional(&self.pool)
             .await?;
-        Ok(token)
+        token.map(|t| self.decrypt_token(t)).transpose()
+    }
+
+    async fn get_all_tokens(&self) -> anyhow::Result<Vec<OauthToken>> {
+        let tokens = sqlx::query_as::<_, OauthToken>("SELECT * FROM oauth_tokens")
+            .fetch_all(&self.pool)
+            .await?;
+        tokens.into_iter().map(|t| self.decrypt_token(t)).collect()
+    }
+
+    async fn record_token_error(&self, account_id: i64, status: TokenStatus, error: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE oauth_tokens SET status = ?, last_error = ?, last_error_at = datetime('now') WHERE account_id = ?")
+            .bind(status)
+            .bind(error)
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_tokens_needing_attention(&self) -> anyhow::Result<Vec<TokenAttention>> {
+        let rows = sqlx::query_as::<_, TokenAttention>(
+            r#"
+            SELECT c.id AS credential_id, c.service_name, a.id AS account_id, a.email, a.name,
+                   t.status, t.last_error, t.last_error_at
+            FROM oauth_tokens t
+            JOIN oauth_accounts a ON a.id = t.account_id
+            JOIN service_credentials c ON c.id = a.credentials_id
+            WHERE t.status <> 'active' OR t.last_error IS NOT NULL
+            ORDER BY c.id, a.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows)
     }
 }
 
+#[async_trait]
+impl ProviderRepository for SqliteRepository {
+    async fn get_all_providers(&self) -> anyhow::Result<Vec<OAuthProviderRow>> {
+        let providers = sqlx::query_as::<_, OAuthProviderRow>("SELECT * FROM oauth_providers")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(providers)
+    }
+
+    async fn add_provider(&self, payload: AddOAuthProviderPayload) -> anyhow::Result<OAuthProviderRow> {
+        let provider_key = payload.provider_key.clone();
+        let provider = sqlx::query_as::<_, OAuthProviderRow>(
+            r#"
+            INSERT INTO oauth_providers (provider_key, display_name, auth_url, token_url, revoke_url, default_scopes, extra_params, client_secret_in_body, requires_refresh_token, device_authorization_url, userinfo_url)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            RETURNING *
+            "#,
+        )
+        .bind(payload.provider_key)
+        .bind(payload.display_name)
+        .bind(payload.auth_url)
+        .bind(payload.token_url)
+        .bind(payload.revoke_url)
+        .bind(payload.default_scopes.join(" "))
+        .bind(serde_json::to_string(&payload.extra_params)?)
+        .bind(payload.client_secret_in_body)
+        .bind(payload.requires_refresh_token)
+        .bind(payload.device_authorization_url)
+        .bind(payload.userinfo_url)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| unique_conflict(e, || AppError::conflict(format!("A provider with key '{}' already exists", provider_key))))?;
+        Ok(provider)
+    }
+
+    async fn get_provider_by_key(&self, provider_key: &str) -> anyhow::Result<Option<OAuthProviderRow>> {
+        let provider = sqlx::query_as::<_, OAuthProviderRow>("SELECT * FROM oauth_providers WHERE provider_key = ?")
+            .bind(provider_key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(provider)
+    }
+}
+
+#[async_trait]
+impl EncryptionKeyRepository for SqliteRepository {
+    async fn get_wrapped_key(&self) -> anyhow::Result<Option<WrappedKey>> {
+        let key = sqlx::query_as::<_, WrappedKey>("SELECT provider, params, wrapped_key FROM encryption_key WHERE id = 1")
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(key)
+    }
+
+    async fn insert_wrapped_key(&self, key: &WrappedKey) -> anyhow::Result<()> {
+        sqlx::query("INSERT INTO encryption_key (id, provider, params, wrapped_key) VALUES (1, ?, ?, ?)")
+            .bind(&key.provider)
+            .bind(&key.params)
+            .bind(&key.wrapped_key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn update_wrapped_key(&self, key: &WrappedKey) -> anyhow::Result<()> {
+        let result = sqlx::query("UPDATE encryption_key SET provider = ?, params = ?, wrapped_key = ?, updated_at = datetime('now') WHERE id = 1")
+            .bind(&key.provider)
+            .bind(&key.params)
+            .bind(&key.wrapped_key)
+            .execute(&self.pool)
+            .await?;
+        if result.rows_affected() == 0 {
+            anyhow::bail!("No encryption key to update");
+        }
+        Ok(())
+    }
+
+    // Runs in one transaction so a crash leaves either all or none of the rows encrypted
+    async fn encrypt_plaintext_secrets(&self, cipher: &FieldCipher) -> anyhow::Result<u64> {
+        let encrypted = format!("{}%", ENCRYPTED_PREFIX);
+        let mut tx = self.pool.begin().await?;
+        let mut changed = 0;
+
+        let creds: Vec<(i64, String)> = sqlx::query_as("SELECT id, client_secret FROM service_credentials WHERE client_secret NOT LIKE ?")
+            .bind(&encrypted)
+            .fetch_all(&mut *tx)
+            .await?;
+        for (id, client_secret) in creds {
+            sqlx::query("UPDATE service_credentials SET client_secret = ? WHERE id = ?")
+                .bind(cipher.encrypt(CLIENT_SECRET_COLUMN, &client_secret)?)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            changed += 1;
+        }
+
+        let tokens: Vec<(i64, String, String)> = sqlx::query_as(
+            "SELECT id, access_token, refresh_token FROM oauth_tokens WHERE access_token NOT LIKE ? OR refresh_token NOT LIKE ?",
+        )
+        .bind(&encrypted)
+        .bind(&encrypted)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (id, access_token, refresh_token) in tokens {
+            let seal = |column: &str, value: String| {
+                if value.starts_with(ENCRYPTED_PREFIX) { Ok(value) } else { cipher.encrypt(column, &value) }
+            };
+            sqlx::query("UPDATE oauth_tokens SET access_token = ?, refresh_token = ? WHERE id = ?")
+                .bind(seal(ACCESS_TOKEN_COLUMN, access_token)?)
+                .bind(seal(REFRESH_TOKEN_COLUMN, refresh_token)?)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            changed += 1;
+        }
+
+        tx.commit().await?;
+        if changed > 0 {
+            self.purge_freed_pages().await?;
+        }
+        Ok(changed)
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::setup::init_test_db;
+    use crate::db::setup::{init_test_db, test_vault};
+    use crate::error::ErrorCode;
 
     #[tokio::test]
     async fn test_add_and_get_credential() {
         let pool = init_test_db().await.unwrap();
-        let repo = SqliteRepository::new(pool);
+        let repo = SqliteRepository::new(pool, test_vault());
         let payload = AddCredentialPayload {
             service_name: "test".to_string(),
             client_id: "id".to_string(),
             client_secret: "secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
         };
         repo.add_credential(payload).await.unwrap();
         let creds = repo.get_all_credentials().await.unwrap();
@@ -115,19 +490,23 @@ mod tests {
     #[tokio::test]
     async fn test_upsert_token() {
         let pool = init_test_db().await.unwrap();
-        let repo = SqliteRepository::new(pool);
+        let repo = SqliteRepository::new(pool, test_vault());
 
-        // We need a credential first
+        // We need a credential and an account first
         let cred_payload = AddCredentialPayload {
             service_name: "test".to_string(),
             client_id: "id".to_string(),
             client_secret: "secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
         };
         let cred = repo.add_credential(cred_payload).await.unwrap();
+        let account = repo.add_account(cred.id, None).await.unwrap();
 
         // First upsert
         let token_payload1 = AddTokenPayload {
-            credentials_id: cred.id,
+            account_id: account.id,
             access_token: "test_access_1".to_string(),
             refresh_token: "test_refresh_1".to_string(),
             expires_at: "never".to_string(),
@@ -137,9 +516,9 @@ mod tests {
         let added_token1 = repo.upsert_token(token_payload1).await.unwrap();
         assert_eq!(added_token1.access_token, "test_access_1");
 
-        // Second upsert with same credentials_id should update the token
+        // Second upsert with same account_id should update the token
         let token_payload2 = AddTokenPayload {
-            credentials_id: cred.id,
+            account_id: account.id,
             access_token: "test_access_2".to_string(),
             refresh_token: "test_refresh_2".to_string(),
             expires_at: "never".to_string(),
@@ -150,9 +529,303 @@ mod tests {
         assert_eq!(added_token2.access_token, "test_access_2");
         assert_eq!(added_token2.scope, Some("write".to_string()));
 
-        // Should have only one token for this credential
-        let fetched_token = repo.get_token_by_credential_id(cred.id).await.unwrap().unwrap();
+        // Should have only one token for this account
+        let fetched_token = repo.get_token_by_account_id(account.id).await.unwrap().unwrap();
         assert_eq!(fetched_token.access_token, "test_access_2");
         assert_eq!(fetched_token.id, added_token1.id); // Same ID, updated content
+
+        assert_eq!(fetched_token.status, TokenStatus::Active);
+
+        // Refresh failures are recorded on the token and listed until the next upsert clears them
+        repo.record_token_error(account.id, TokenStatus::Active, "timeout").await.unwrap();
+        let attention = repo.get_tokens_needing_attention().await.unwrap();
+        assert_eq!(attention.len(), 1);
+        assert_eq!((attention[0].credential_id, attention[0].account_id), (cred.id, account.id));
+        assert_eq!(attention[0].status, TokenStatus::Active);
+        assert_eq!(attention[0].last_error.as_deref(), Some("timeout"));
+        assert!(attention[0].last_error_at.is_some());
+
+        repo.record_token_error(account.id, TokenStatus::NeedsReauth, "invalid_grant").await.unwrap();
+        let token = repo.get_token_by_account_id(account.id).await.unwrap().unwrap();
+        assert_eq!(token.status, TokenStatus::NeedsReauth);
+        assert_eq!(token.last_error.as_deref(), Some("invalid_grant"));
+
+        let reauthorized = repo.upsert_token(AddTokenPayload {
+            account_id: account.id,
+            access_token: "test_access_3".to_string(),
+            refresh_token: "test_refresh_3".to_string(),
+            expires_at: "never".to_string(),
+            scope: None,
+        }).await.unwrap();
+        assert_eq!(reauthorized.status, TokenStatus::Active);
+        assert!(reauthorized.last_error.is_none() && reauthorized.last_error_at.is_none());
+        assert!(repo.get_tokens_needing_attention().await.unwrap().is_empty());
+
+        // Deleting the account removes its token; a second delete reports nothing was removed
+        assert!(repo.delete_account(account.id).await.unwrap());
+        assert!(repo.get_token_by_account_id(account.id).await.unwrap().is_none());
+        assert!(!repo.delete_account(account.id).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_multiple_accounts_per_credential() {
+        let pool = init_test_db().await.unwrap();
+        let repo = SqliteRepository::new(pool, test_vault());
+        let cred = repo.add_credential(AddCredentialPayload {
+            service_name: "test".to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
+        }).await.unwrap();
+
+        let identity = |subject: &str| AccountIdentity {
+            subject: subject.to_string(),
+            email: Some(format!("{}@example.com", subject)),
+            name: None,
+            picture: None,
+        };
+        let main = repo.add_account(cred.id, Some(&identity("main"))).await.unwrap();
+        let sub = repo.add_account(cred.id, Some(&identity("sub"))).await.unwrap();
+        for (account, access_token) in [(&main, "main_access"), (&sub, "sub_access")] {
+            repo.upsert_token(AddTokenPayload {
+                account_id: account.id,
+                access_token: access_token.to_string(),
+                refresh_token: "r".to_string(),
+                expires_at: "never".to_string(),
+                scope: None,
+            }).await.unwrap();
+        }
+
+        let accounts = repo.get_accounts_by_credential_id(cred.id).await.unwrap();
+        assert_eq!(accounts.iter().map(|a| a.id).collect::<Vec<_>>(), vec![main.id, sub.id]);
+        assert_eq!(repo.get_all_tokens().await.unwrap().len(), 2);
+        assert_eq!(repo.get_token_by_account_id(sub.id).await.unwrap().unwrap().access_token, "sub_access");
+        assert_eq!(repo.get_account_by_subject(cred.id, "sub").await.unwrap().unwrap().id, sub.id);
+        assert!(repo.get_account_by_subject(cred.id, "other").await.unwrap().is_none());
+
+        // A subject is linked at most once per credential; unknown subjects are not deduplicated
+        assert!(repo.add_account(cred.id, Some(&identity("main"))).await.is_err());
+        repo.add_account(cred.id, None).await.unwrap();
+        repo.add_account(cred.id, None).await.unwrap();
+
+        repo.update_account_identity(sub.id, None).await.unwrap();
+        assert!(repo.get_account_by_id(sub.id).await.unwrap().unwrap().identity().is_none());
+        repo.update_account_identity(sub.id, Some(&identity("sub2"))).await.unwrap();
+        assert_eq!(repo.get_account_by_id(sub.id).await.unwrap().unwrap().identity(), Some(identity("sub2")));
+    }
+
+    #[tokio::test]
+    async fn test_secrets_are_encrypted_at_rest() {
+        let pool = init_test_db().await.unwrap();
+        let repo = SqliteRepository::new(pool.clone(), test_vault());
+        let cred = repo
+            .add_credential(AddCredentialPayload {
+                service_name: "test".to_string(),
+                client_id: "id".to_string(),
+                client_secret: "plain-secret".to_string(),
+                provider: "google".to_string(),
+                scopes: None,
+                auth_params: None,
+            })
+            .await
+            .unwrap();
+        let account = repo.add_account(cred.id, None).await.unwrap();
+        let token = repo
+            .upsert_token(AddTokenPayload {
+                account_id: account.id,
+                access_token: "plain-access".to_string(),
+                refresh_token: "plain-refresh".to_string(),
+                expires_at: "never".to_string(),
+                scope: None,
+            })
+            .await
+            .unwrap();
+        assert_eq!((cred.client_secret.as_str(), token.refresh_token.as_str()), ("plain-secret", "plain-refresh"));
+
+        let (secret, access, refresh): (String, String, String) =
+            sqlx::query_as("SELECT c.client_secret, t.access_token, t.refresh_token FROM service_credentials c, oauth_tokens t")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        for stored in [&secret, &access, &refresh] {
+            assert!(stored.starts_with(ENCRYPTED_PREFIX) && !stored.contains("plain"), "{}", stored);
+        }
+
+        // Another key cannot read them, and a locked repository refuses instead of returning ciphertext
+        assert!(SqliteRepository::new(pool.clone(), test_vault()).get_credential_by_id(cred.id).await.is_err());
+        let locked = SqliteRepository::new(pool, Arc::new(KeyVault::locked()));
+        assert!(locked.get_all_tokens().await.unwrap_err().is::<crate::db::encryption::DatabaseLocked>());
+        // Non-secret queries keep working while locked
+        assert_eq!(locked.get_accounts_by_credential_id(cred.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn encrypting_plaintext_secrets_leaves_no_copy_in_the_file() {
+        let dir = crate::test_support::TempDir::new("purge-plaintext");
+        let path = dir.path().join("app.sqlite");
+        let options = sqlx::sqlite::SqliteConnectOptions::new().filename(&path).create_if_missing(true);
+        let pool = crate::db::setup::connect_with(options).await.unwrap();
+        for n in 0..20 {
+            sqlx::query("INSERT INTO service_credentials (service_name, client_id, client_secret, provider) VALUES (?, 'id', 'plaintext-client-secret', 'google')")
+                .bind(format!("service-{}", n))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let vault = test_vault();
+        let repo = SqliteRepository::new(pool.clone(), vault.clone());
+        assert_eq!(repo.encrypt_plaintext_secrets(&vault.cipher().unwrap()).await.unwrap(), 20);
+
+        for file in ["app.sqlite", "app.sqlite-wal"] {
+            let bytes = std::fs::read(dir.path().join(file)).unwrap_or_default();
+            assert!(!bytes.windows(23).any(|w| w == b"plaintext-client-secret"), "{} still holds the plaintext", file);
+        }
+        pool.close().await;
+    }
+
+    #[tokio::test]
+    async fn test_migration_moves_tokens_to_accounts() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+
+        // Schema as it was before accounts: one token per credential with the identity on the token
+        let mut legacy = sqlx::migrate!("./migrations");
+        legacy.migrations = legacy.migrations.iter().filter(|m| m.version < 20250906000001).cloned().collect::<Vec<_>>().into();
+        legacy.run(&pool).await.unwrap();
+        sqlx::query("INSERT INTO service_credentials (id, service_name, client_id, client_secret) VALUES (1, 'a', 'id', 'secret'), (2, 'b', 'id', 'secret')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(
+            r#"
+            INSERT INTO oauth_tokens (id, credentials_id, access_token, refresh_token, expires_at, scope, account_subject, account_email)
+            VALUES (5, 1, 'at1', 'rt1', '2099-12-31 23:59:59', 's1', 'sub-1', 'one@example.com'),
+                   (7, 2, 'at2', 'rt2', '2099-12-31 23:59:59', NULL, NULL, NULL)
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::migrate!("./migrations").run(&pool).await.unwrap();
+        let vault = test_vault();
+        let repo = SqliteRepository::new(pool, vault.clone());
+        // Legacy plaintext secrets are encrypted when the key is unlocked (2 credentials + 2 tokens)
+        assert_eq!(repo.encrypt_plaintext_secrets(&vault.cipher().unwrap()).await.unwrap(), 4);
+
+        let accounts = repo.get_accounts_by_credential_id(1).await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert_eq!(accounts[0].id, 5);
+        assert_eq!(accounts[0].subject.as_deref(), Some("sub-1"));
+        assert_eq!(accounts[0].email.as_deref(), Some("one@example.com"));
+        let token = repo.get_token_by_account_id(5).await.unwrap().unwrap();
+        assert_eq!((token.id, token.access_token.as_str(), token.refresh_token.as_str()), (5, "at1", "rt1"));
+        assert_eq!(token.scope.as_deref(), Some("s1"));
+
+        let accounts = repo.get_accounts_by_credential_id(2).await.unwrap();
+        assert_eq!(accounts.len(), 1);
+        assert!(accounts[0].identity().is_none());
+        assert_eq!(repo.get_token_by_account_id(accounts[0].id).await.unwrap().unwrap().access_token, "at2");
+    }
+
+    #[tokio::test]
+    async fn test_add_and_get_provider() {
+        let pool = init_test_db().await.unwrap();
+        let repo = SqliteRepository::new(pool, test_vault());
+        let payload = AddOAuthProviderPayload {
+            provider_key: "custom".to_string(),
+            display_name: "Custom IdP".to_string(),
+            auth_url: "https://idp.example.com/authorize".to_string(),
+            token_url: "https://idp.example.com/token".to_string(),
+            revoke_url: None,
+            default_scopes: vec!["openid".to_string(), "profile".to_string()],
+            extra_params: [("audience".to_string(), "api".to_string())].into_iter().collect(),
+            client_secret_in_body: true,
+            requires_refresh_token: false,
+            device_authorization_url: Some("https://idp.example.com/device".to_string()),
+            userinfo_url: None,
+        };
+        repo.add_provider(payload).await.unwrap();
+
+        let fetched = repo.get_provider_by_key("custom").await.unwrap().unwrap();
+        assert_eq!(fetched.default_scopes, "openid profile");
+        assert_eq!(fetched.extra_params, r#"{"audience":"api"}"#);
+        assert!(fetched.client_secret_in_body);
+        assert!(!fetched.requires_refresh_token);
+        assert_eq!(fetched.device_authorization_url.as_deref(), Some("https://idp.example.com/device"));
+        assert!(repo.get_provider_by_key("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update_credential_scopes() {
+        let pool = init_test_db().await.unwrap();
+        let repo = SqliteRepository::new(pool, test_vault());
+        let payload = AddCredentialPayload {
+            service_name: "test".to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            provider: "google".to_string(),
+            scopes: Some(vec!["a".to_string(), "b".to_string()]),
+            auth_params: Some([("prompt".to_string(), "consent".to_string())].into_iter().collect()),
+        };
+        let cred = repo.add_credential(payload).await.unwrap();
+        assert_eq!(cred.scopes.as_deref(), Some("a b"));
+        assert_eq!(cred.auth_params.as_deref(), Some(r#"{"prompt":"consent"}"#));
+
+        let updated = repo.update_credential_scopes(cred.id, None).await.unwrap().unwrap();
+        assert_eq!(updated.scopes, None);
+        assert!(repo.update_credential_scopes(cred.id + 100, None).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_update_and_delete_credential() {
+        let pool = init_test_db().await.unwrap();
+        let repo = SqliteRepository::new(pool, test_vault());
+        let payload = |service_name: &str| AddCredentialPayload {
+            service_name: service_name.to_string(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
+        };
+        let cred = repo.add_credential(payload("youtube")).await.unwrap();
+        let other = repo.add_credential(payload("twitch")).await.unwrap();
+        let err = repo.add_credential(payload("youtube")).await.unwrap_err();
+        let conflict = err.downcast_ref::<AppError>().unwrap();
+        assert_eq!(conflict.code, ErrorCode::Conflict);
+        assert_eq!(conflict.details, Some(serde_json::json!({ "service_name": "youtube" })));
+
+        let update = UpdateCredentialPayload { service_name: None, client_id: Some("fixed-id".to_string()) };
+        let updated = repo.update_credential(cred.id, update).await.unwrap().unwrap();
+        assert_eq!((updated.service_name.as_str(), updated.client_id.as_str()), ("youtube", "fixed-id"));
+        let rename = UpdateCredentialPayload { service_name: Some("twitch".to_string()), client_id: None };
+        let err = repo.update_credential(cred.id, rename).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<AppError>().unwrap().message, "A credential named 'twitch' already exists");
+        assert!(repo.update_credential(cred.id + 100, UpdateCredentialPayload::default()).await.unwrap().is_none());
+
+        // Rotating the secret keeps the linked accounts and tokens
+        let account = repo.add_account(cred.id, None).await.unwrap();
+        repo.upsert_token(AddTokenPayload {
+            account_id: account.id,
+            access_token: "a".to_string(),
+            refresh_token: "r".to_string(),
+            expires_at: "never".to_string(),
+            scope: None,
+        })
+        .await
+        .unwrap();
+        let rotated = repo.update_client_secret(cred.id, "rotated").await.unwrap().unwrap();
+        assert_eq!(rotated.client_secret, "rotated");
+        assert_eq!(repo.get_credential_by_id(cred.id).await.unwrap().unwrap().client_secret, "rotated");
+        assert!(repo.get_token_by_account_id(account.id).await.unwrap().is_some());
+
+        // Deleting cascades to the accounts and their tokens only
+        assert!(repo.delete_credential(cred.id).await.unwrap());
+        assert!(!repo.delete_credential(cred.id).await.unwrap());
+        assert!(repo.get_account_by_id(account.id).await.unwrap().is_none());
+        assert!(repo.get_all_tokens().await.unwrap().is_empty());
+        assert_eq!(repo.get_all_credentials().await.unwrap().iter().map(|c| c.id).collect::<Vec<_>>(), vec![other.id]);
     }
 }
@@ -1,44 +1,210 @@
+use super::encryption::{KdfParams, KeyVault};
+use super::location::{DatabaseLocation, LEGACY_DB_PATH};
 use super::repositories::SqliteRepository;
+use crate::error::AppError;
+use crate::oauth_server::CallbackPorts;
 use crate::services::{
+    backup_service::{BackupSchedule, BackupScheduler, BackupService},
     credential_service::CredentialService,
+    encryption_service::EncryptionService,
+    events::EventSink,
+    oauth_flow::OAuthFlowRegistry,
+    oauth_provider::{EndpointOverrides, ProviderRegistry},
     oauth_service::OAuthService,
+    startup::Startup,
+    token_refresher::{RefreshSchedule, TokenRefreshScheduler},
 };
-use sqlx::SqlitePool;
+use sqlx::migrate::Migrator;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use sqlx::{Connection, Row, SqlitePool};
+use std::path::Path;
 use std::sync::Arc;
-use tauri::{AppHandle, Manager};
+use tauri::{AppHandle, Emitter, Manager};
 
 // A single state struct to hold all services
 pub struct AppState {
+    pub encryption_service: EncryptionService,
     pub credential_service: CredentialService,
     pub oauth_service: OAuthService,
+    pub provider_registry: ProviderRegistry,
+    pub oauth_flows: OAuthFlowRegistry,
+    pub callback_ports: CallbackPorts,
+    pub token_refresher: TokenRefreshScheduler,
+    pub backup_service: BackupService,
+    pub backup_scheduler: BackupScheduler,
+}
+
+// Managed from the start; commands reach `AppState` through it once `init` has finished
+pub type AppRuntime = Startup<AppState>;
+
+// Forwards service events to the frontend as Tauri events
+struct TauriEventSink {
+    app_handle: AppHandle,
+}
+
+impl EventSink for TauriEventSink {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) {
+        if let Err(e) = self.app_handle.emit(event, payload) {
+            eprintln!("Failed to emit '{}' event: {}", event, e);
+        }
+    }
+}
+
+// Migrations embedded in the binary; backups and legacy databases are checked against them before use
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// Open the SQLite database at `url` and apply pending migrations
+pub async fn connect(url: &str) -> anyhow::Result<SqlitePool> {
+    connect_with(url.parse()?).await
+}
+
+pub async fn connect_with(options: SqliteConnectOptions) -> anyhow::Result<SqlitePool> {
+    let pool = SqlitePool::connect_with(options).await?;
+    MIGRATOR.run(&pool).await?;
+    Ok(pool)
+}
+
+// Check that `path` is an intact database of this app with no migration this build does not know.
+// Used for backups before a restore and for a legacy file before adopting it. Returns the latest applied migration version.
+pub async fn validate_database(path: &Path) -> anyhow::Result<i64> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options)
+        .await
+        .map_err(|e| AppError::invalid_input(format!("The file cannot be opened as a database: {}", e)))?;
+    let result = check_database(&mut conn).await;
+    let _ = conn.close().await;
+    result
+}
+
+async fn check_database(conn: &mut SqliteConnection) -> anyhow::Result<i64> {
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|e| AppError::invalid_input(format!("The file is not a SQLite database: {}", e)))?;
+    if integrity != "ok" {
+        return Err(AppError::invalid_input(format!("The database failed the integrity check: {}", integrity)).into());
+    }
+
+    let rows = sqlx::query("SELECT version, success, checksum FROM _sqlx_migrations ORDER BY version")
+        .fetch_all(&mut *conn)
+        .await
+        .map_err(|_| AppError::invalid_input("The file has no migration history; it is not a k3-live-manager database"))?;
+    let mut latest = None;
+    for row in rows {
+        let version: i64 = row.try_get("version")?;
+        let success: bool = row.try_get("success")?;
+        let checksum: Vec<u8> = row.try_get("checksum")?;
+        if !success {
+            return Err(AppError::invalid_input(format!("The database has an incomplete migration {}", version)).into());
+        }
+        match MIGRATOR.iter().find(|m| m.version == version) {
+            None => {
+                return Err(AppError::invalid_input(format!(
+                    "The database was made by a newer version of the app (unknown migration {})",
+                    version
+                ))
+                .into())
+            }
+            Some(migration) if *migration.checksum != *checksum => {
+                return Err(AppError::invalid_input(format!("Migration {} of the database does not match this version of the app", version)).into())
+            }
+            Some(_) => latest = Some(version),
+        }
+    }
+    latest.ok_or_else(|| AppError::invalid_input("The file has no migration history; it is not a k3-live-manager database").into())
+}
+
+// Resolve the database file (app data dir unless overridden), adopting a legacy `../app.sqlite` on first run
+pub async fn resolve_database(app_handle: &AppHandle) -> anyhow::Result<DatabaseLocation> {
+    let app_data_dir = app_handle.path().app_data_dir()?;
+    let location = DatabaseLocation::from_env(&app_data_dir)?;
+    if location
+        .migrate_legacy(Path::new(LEGACY_DB_PATH))
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to move the legacy database {}: {:#}", LEGACY_DB_PATH, e))?
+    {
+        println!("Moved legacy database {} to {}", LEGACY_DB_PATH, location.path.display());
+    }
+    if location.apply_staged_restore()? {
+        println!("Restored the database from a backup; the previous one is {}", location.pre_restore_path().display());
+    }
+    location.ensure_parent_dir()?;
+    Ok(location)
+}
+
+// Manage the runtime right away and initialize in the background.
+// The outcome is published through `get_app_status` and the `app-status` event instead of panicking.
+pub fn start(app_handle: &AppHandle) {
+    let events: Arc<dyn EventSink> = Arc::new(TauriEventSink { app_handle: app_handle.clone() });
+    app_handle.manage(AppRuntime::new(events));
+
+    let handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        let runtime = handle.state::<AppRuntime>();
+        match init(&handle).await {
+            Ok(state) => runtime.ready(state),
+            Err(e) => {
+                eprintln!("Failed to initialize the app: {:#}", e);
+                runtime.fail(AppError::from(e));
+            }
+        }
+    });
 }
 
 // Initializes the database and sets up all services in the app state.
-pub async fn init(app_handle: &AppHandle) -> Result<(), Box<dyn std::error::Error>> {
-    let pool = SqlitePool::connect("sqlite:../app.sqlite").await?;
-    sqlx::migrate!("./migrations").run(&pool).await?;
+pub async fn init(app_handle: &AppHandle) -> anyhow::Result<AppState> {
+    let location = resolve_database(app_handle).await?;
+    println!("Database: {} ({:?})", location.path.display(), location.source);
+    let pool = connect_with(location.connect_options()).await?;
 
-    // Create a single repository instance, wrapped in an Arc for shared ownership
-    let repo = Arc::new(SqliteRepository::new(pool));
+    // Create a single repository instance, wrapped in an Arc for shared ownership.
+    // Secrets stay unreadable until the vault is unlocked with the passphrase.
+    let vault = Arc::new(KeyVault::locked());
+    let repo = Arc::new(SqliteRepository::new(pool.clone(), vault.clone()));
+    let encryption_service = EncryptionService::new(repo.clone(), vault, KdfParams::default());
+    match encryption_service.unlock_from_env().await {
+        Ok(state) => println!("Database encryption: {:?}", state),
+        Err(e) => eprintln!("Failed to unlock the database from the environment: {:#}", e),
+    }
 
     // Create services, passing a clone of the repository Arc to each
-    let credential_service = CredentialService::new(repo.clone());
-    let oauth_service = OAuthService::new(repo.clone(), repo.clone());
+    let provider_registry = ProviderRegistry::with_overrides(repo.clone(), EndpointOverrides::from_env());
+    let credential_service = CredentialService::new(repo.clone(), provider_registry.clone());
+    let oauth_service = OAuthService::new(repo.clone(), repo.clone(), repo.clone(), provider_registry.clone());
+    let events: Arc<dyn EventSink> = Arc::new(TauriEventSink { app_handle: app_handle.clone() });
+
+    // Keep stored tokens fresh in the background; stopped from the app's exit handler
+    let token_refresher =
+        TokenRefreshScheduler::start(oauth_service.clone(), repo.clone(), events.clone(), RefreshSchedule::from_env());
 
-    // Create the final AppState and manage it
-    let app_state = AppState {
+    // Rotating backups next to the database; stopped from the app's exit handler
+    let backup_schedule = BackupSchedule::from_env();
+    let backup_service = BackupService::new(pool, location, backup_schedule.retention);
+    let backup_scheduler = BackupScheduler::start(backup_service.clone(), backup_schedule);
+
+    Ok(AppState {
+        encryption_service,
         credential_service,
         oauth_service,
-    };
-    app_handle.manage(app_state);
-
-    Ok(())
+        provider_registry,
+        oauth_flows: OAuthFlowRegistry::new(events),
+        callback_ports: CallbackPorts::from_env(),
+        token_refresher,
+        backup_service,
+        backup_scheduler,
+    })
 }
 
 // This function is only compiled for tests.
 #[cfg(test)]
 pub async fn init_test_db() -> anyhow::Result<SqlitePool> {
-    let pool = SqlitePool::connect("sqlite::memory:").await?;
-    sqlx::migrate!("./migrations").run(&pool).await?;
-    Ok(pool)
+    connect("sqlite::memory:").await
+}
+
+// Unlocked vault with a throwaway data key, for repository tests
+#[cfg(test)]
+pub fn test_vault() -> Arc<KeyVault> {
+    use super::encryption::DataKey;
+
+    Arc::new(KeyVault::unlocked(DataKey::generate()))
 }
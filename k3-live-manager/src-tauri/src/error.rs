@@ -0,0 +1,235 @@
+use crate::db::encryption::DatabaseLocked;
+use crate::services::oauth_service::{DeviceFlowError, MissingRefreshToken, ReauthorizationRequired};
+use serde::{Deserialize, Serialize};
+
+// Stable identifiers the UI can branch on. Never rename a variant: the snake_case names are part of the command API.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    // The credential, account, token or flow does not exist
+    NotFound,
+    // The request itself is wrong (empty field, malformed file, unknown provider, ...)
+    InvalidInput,
+    // Clashes with existing data or the current state (duplicate name, passphrase already set, ...)
+    Conflict,
+    // Secrets cannot be read until the database is unlocked with the passphrase
+    DatabaseLocked,
+    IncorrectPassphrase,
+    // The account has to go through the authorization flow again
+    NeedsReauth,
+    // The OAuth provider answered with an error
+    ProviderError,
+    // The provider could not be reached
+    Network,
+    Database,
+    Internal,
+    // Startup has not finished yet; retry once the `app-status` event reports `ready`
+    NotReady,
+    // Startup failed (database could not be opened or migrated); needs a restart after fixing the cause
+    StartupFailed,
+}
+
+// Error returned by the services and serialized by every command as
+// `{ "code", "message", "retryable", "details"? }`.
+// Services keep using anyhow and raise an AppError where they know what went wrong;
+// `From<anyhow::Error>` classifies everything else.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AppError {
+    pub code: ErrorCode,
+    // Safe to show to the user: never contains secrets or tokens
+    pub message: String,
+    // Trying the same request again later may succeed
+    pub retryable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+}
+
+impl AppError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self { code, message: message.into(), retryable: false, details: None }
+    }
+
+    // `what` is the entity, e.g. "Credential" -> "Credential not found"
+    pub fn not_found(what: &str) -> Self {
+        Self::new(ErrorCode::NotFound, format!("{} not found", what))
+    }
+
+    pub fn invalid_input(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidInput, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Conflict, message)
+    }
+
+    // Error response from a provider endpoint. `error`/`description` are the RFC 6749 `error` and
+    // `error_description` when the body had them; they go to `details`, the message stays fixed.
+    pub fn provider(error: Option<&str>, description: Option<&str>) -> Self {
+        let mut details = serde_json::Map::new();
+        if let Some(error) = error {
+            details.insert("error".into(), error.into());
+        }
+        if let Some(description) = description {
+            details.insert("error_description".into(), description.into());
+        }
+        let app_error = Self::new(ErrorCode::ProviderError, "The OAuth provider returned an error")
+            .retryable(matches!(error, Some("server_error" | "temporarily_unavailable")));
+        match details.is_empty() {
+            true => app_error,
+            false => app_error.with_details(details.into()),
+        }
+    }
+
+    // Failure nobody raised an AppError for: the UI gets a fixed message, the full chain goes to the log
+    fn unexpected(code: ErrorCode, message: &str, e: &anyhow::Error) -> Self {
+        eprintln!("{}: {:#}", message, e);
+        Self::new(code, message)
+    }
+
+    pub fn retryable(mut self, retryable: bool) -> Self {
+        self.retryable = retryable;
+        self
+    }
+
+    pub fn with_details(mut self, details: serde_json::Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<anyhow::Error> for AppError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::from(&e)
+    }
+}
+
+// The first cause in the chain that we know decides the code, so context added on the way up does not hide it
+impl From<&anyhow::Error> for AppError {
+    fn from(e: &anyhow::Error) -> Self {
+        for cause in e.chain() {
+            if let Some(app_error) = cause.downcast_ref::<AppError>() {
+                return app_error.clone();
+            }
+            if cause.is::<DatabaseLocked>() {
+                return Self::new(ErrorCode::DatabaseLocked, cause.to_string());
+            }
+            // `reason` carries the provider's answer, so it goes to `details` like other provider text
+            if let Some(reauth) = cause.downcast_ref::<ReauthorizationRequired>() {
+                return Self::new(ErrorCode::NeedsReauth, "This account needs to be re-authorized")
+                    .with_details(serde_json::json!({ "account_id": reauth.account_id, "reason": reauth.reason }));
+            }
+            if let Some(missing) = cause.downcast_ref::<MissingRefreshToken>() {
+                return Self::new(ErrorCode::NeedsReauth, "The provider returned no refresh token; authorize again with offline access")
+                    .with_details(serde_json::json!({ "credential_id": missing.credential_id }));
+            }
+            if let Some(device) = cause.downcast_ref::<DeviceFlowError>() {
+                return Self::provider(Some(&device.error), device.description.as_deref());
+            }
+            if cause.is::<reqwest::Error>() || cause.is::<oauth2::reqwest::AsyncHttpClientError>() {
+                return Self::unexpected(ErrorCode::Network, "Could not reach the OAuth provider", e).retryable(true);
+            }
+            if let Some(db) = cause.downcast_ref::<sqlx::Error>() {
+                return match db {
+                    sqlx::Error::Database(db) if db.is_unique_violation() => {
+                        Self::unexpected(ErrorCode::Conflict, "Conflicts with existing data", e)
+                    }
+                    sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) => Self::unexpected(ErrorCode::Database, "Database error", e).retryable(true),
+                    _ => Self::unexpected(ErrorCode::Database, "Database error", e),
+                };
+            }
+        }
+        Self::unexpected(ErrorCode::Internal, "Unexpected error", e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Context;
+
+    fn classify(e: anyhow::Error) -> AppError {
+        AppError::from(e)
+    }
+
+    #[test]
+    fn serializes_with_stable_codes() {
+        let error = AppError::not_found("Credential").with_details(serde_json::json!({ "credential_id": 7 }));
+        assert_eq!(
+            serde_json::to_value(&error).unwrap(),
+            serde_json::json!({
+                "code": "not_found",
+                "message": "Credential not found",
+                "retryable": false,
+                "details": { "credential_id": 7 },
+            })
+        );
+        let json = serde_json::to_value(AppError::invalid_input("bad")).unwrap();
+        assert_eq!(json["code"], "invalid_input");
+        assert!(json.get("details").is_none());
+    }
+
+    #[test]
+    fn app_errors_survive_added_context() {
+        let error = classify(anyhow::Error::new(AppError::conflict("taken")).context("Failed to save"));
+        assert_eq!((error.code, error.message.as_str()), (ErrorCode::Conflict, "taken"));
+    }
+
+    #[test]
+    fn known_failures_map_to_their_codes() {
+        assert_eq!(classify(DatabaseLocked.into()).code, ErrorCode::DatabaseLocked);
+
+        let reason = "invalid_grant: Token has been expired or revoked.";
+        let reauth = classify(anyhow::Error::new(ReauthorizationRequired { account_id: 3, reason: reason.into() }).context("refresh"));
+        assert_eq!((reauth.code, reauth.message.as_str()), (ErrorCode::NeedsReauth, "This account needs to be re-authorized"));
+        assert_eq!(reauth.details, Some(serde_json::json!({ "account_id": 3, "reason": reason })));
+        assert_eq!(classify(MissingRefreshToken { credential_id: 1 }.into()).code, ErrorCode::NeedsReauth);
+
+        let denied = classify(DeviceFlowError { error: "access_denied".into(), description: None }.into());
+        assert_eq!((denied.code, denied.retryable), (ErrorCode::ProviderError, false));
+        assert_eq!(denied.details, Some(serde_json::json!({ "error": "access_denied" })));
+        assert!(AppError::provider(Some("temporarily_unavailable"), None).retryable);
+
+        let internal = classify(anyhow::anyhow!("Invalid auth_params stored for credential"));
+        assert_eq!((internal.code, internal.message.as_str()), (ErrorCode::Internal, "Unexpected error"));
+    }
+
+    #[test]
+    fn provider_text_goes_to_details_not_the_message() {
+        let error = classify(anyhow::Error::new(AppError::provider(Some("invalid_client"), Some("Unknown client <x>"))).context("Token endpoint returned 401"));
+        assert_eq!(error.message, "The OAuth provider returned an error");
+        assert_eq!(error.details, Some(serde_json::json!({ "error": "invalid_client", "error_description": "Unknown client <x>" })));
+    }
+
+    #[tokio::test]
+    async fn network_failures_are_retryable() {
+        // Nothing listens on port 9 of localhost
+        let result = reqwest::Client::new().get("http://127.0.0.1:9/").send().await;
+        let error = classify(anyhow::Error::new(result.unwrap_err()).context("Failed to reach token endpoint"));
+        assert_eq!((error.code, error.retryable), (ErrorCode::Network, true));
+        assert_eq!(error.message, "Could not reach the OAuth provider");
+    }
+
+    #[tokio::test]
+    async fn database_failures_map_to_database_or_conflict() {
+        let pool = crate::db::setup::init_test_db().await.unwrap();
+        let insert = "INSERT INTO oauth_providers (provider_key, display_name, auth_url, token_url) VALUES ('p', 'P', 'a', 't')";
+        sqlx::query(insert).execute(&pool).await.unwrap();
+        let duplicate = sqlx::query(insert).execute(&pool).await.context("Failed to save provider").unwrap_err();
+        let conflict = classify(duplicate);
+        assert_eq!(conflict.code, ErrorCode::Conflict);
+        // SQL text stays in the backend log
+        assert!(!conflict.message.contains("UNIQUE"), "{}", conflict.message);
+
+        let missing_table = sqlx::query("SELECT * FROM missing").execute(&pool).await.unwrap_err();
+        let error = classify(missing_table.into());
+        assert_eq!((error.code, error.retryable), (ErrorCode::Database, false));
+    }
+}
@@ -1,6 +1,12 @@
-mod db;
-mod services;
-mod oauth_server;
+use tauri::Manager;
+
+// Public so the integration tests under `tests/` can drive the services directly
+pub mod db;
+pub mod error;
+pub mod services;
+pub mod oauth_server;
+#[cfg(test)]
+mod test_support;
 
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 #[tauri::command]
@@ -14,20 +20,51 @@ pub fn run() {
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_sql::Builder::default().build())
         .setup(|app| {
-            let handle = app.handle().clone();
-            tauri::async_runtime::spawn(async move {
-                db::setup::init(&handle)
-                    .await
-                    .expect("failed to initialize database");
-            });
+            db::setup::start(app.handle());
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             greet,
+            db::commands::get_app_status,
+            db::commands::get_encryption_state,
+            db::commands::set_database_passphrase,
+            db::commands::unlock_database,
+            db::commands::change_database_passphrase,
             db::commands::get_service_credentials,
             db::commands::add_service_credential,
-            db::commands::start_oauth_flow
+            db::commands::import_client_secret_json,
+            db::commands::reveal_client_secret,
+            db::commands::update_service_credential,
+            db::commands::rotate_client_secret,
+            db::commands::delete_service_credential,
+            db::commands::set_credential_scopes,
+            db::commands::set_credential_auth_params,
+            db::commands::get_oauth_providers,
+            db::commands::add_oauth_provider,
+            db::commands::start_oauth_flow,
+            db::commands::start_device_flow,
+            db::commands::cancel_oauth_flow,
+            db::commands::get_oauth_flow_status,
+            db::commands::check_token_scopes,
+            db::commands::get_linked_accounts,
+            db::commands::get_credentials_needing_attention,
+            db::commands::revoke_account_token,
+            db::commands::create_backup,
+            db::commands::list_backups,
+            db::commands::restore_backup,
+            db::commands::restart_app
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while building tauri application")
+        .run(|app_handle, event| {
+            // Stop background tasks before the process exits
+            if let tauri::RunEvent::Exit = event {
+                if let Some(Ok(state)) = app_handle.try_state::<db::setup::AppRuntime>().map(|r| r.inner().state()) {
+                    tauri::async_runtime::block_on(async {
+                        state.token_refresher.shutdown().await;
+                        state.backup_scheduler.shutdown().await;
+                    });
+                }
+            }
+        });
 }
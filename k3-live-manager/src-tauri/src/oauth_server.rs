@@ -5,138 +5,481 @@ use hyper::body::Incoming as HyperIncomingBody;
 use hyper::body::Bytes as HyperBytes;
 use std::convert::Infallible;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
 use tokio::sync::oneshot;
-use url::Url;
+use tokio_util::sync::CancellationToken;
 use http_body_util::{Full};
 use hyper_util::rt::TokioIo;
 
-pub async fn start_oauth_server(
-    tx: oneshot::Sender<(String, String)>,
-    port: u16,
-) -> anyhow::Result<()> {
-    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+// What the provider redirected back with (RFC 6749 4.1.2 / 4.1.2.1)
+#[derive(Debug, Clone, PartialEq)]
+pub enum OAuthCallback {
+    Code {
+        code: String,
+        state: String,
+    },
+    Error {
+        // e.g. `access_denied`, `invalid_scope`
+        error: String,
+        description: Option<String>,
+        state: Option<String>,
+    },
+}
+
+impl OAuthCallback {
+    // Parse the callback query; None when it is neither a code nor an error response
+    fn from_query(query: &str) -> Option<Self> {
+        let params: std::collections::HashMap<String, String> =
+            url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+        if let Some(error) = params.get("error") {
+            return Some(OAuthCallback::Error {
+                error: error.clone(),
+                description: params.get("error_description").cloned(),
+                state: params.get("state").cloned(),
+            });
+        }
+        match (params.get("code"), params.get("state")) {
+            (Some(code), Some(state)) => Some(OAuthCallback::Code { code: code.clone(), state: state.clone() }),
+            _ => None,
+        }
+    }
+
+    pub fn state(&self) -> Option<&str> {
+        match self {
+            OAuthCallback::Code { state, .. } => Some(state),
+            OAuthCallback::Error { state, .. } => state.as_deref(),
+        }
+    }
+}
+
+// Ports tried for the callback listener, in order. Loopback redirects accept any port for desktop clients.
+pub const DEFAULT_CALLBACK_PORTS: std::ops::RangeInclusive<u16> = 1421..=1430;
+
+// Overrides the callback ports, e.g. `1421-1430,8080`
+pub const CALLBACK_PORTS_ENV: &str = "K3_OAUTH_CALLBACK_PORTS";
+
+// Allow-list of callback ports, tried in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallbackPorts(Vec<u16>);
+
+impl Default for CallbackPorts {
+    fn default() -> Self {
+        Self(DEFAULT_CALLBACK_PORTS.collect())
+    }
+}
+
+impl CallbackPorts {
+    // Parse a comma separated list of ports and inclusive ranges (`1421-1430,8080`)
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut ports = Vec::new();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (start, end) = match part.split_once('-') {
+                Some((start, end)) => (start.trim().parse::<u16>()?, end.trim().parse::<u16>()?),
+                None => {
+                    let port = part.parse::<u16>()?;
+                    (port, port)
+                }
+            };
+            if start == 0 || start > end {
+                anyhow::bail!("Invalid callback port range '{}'", part);
+            }
+            for port in start..=end {
+                if !ports.contains(&port) {
+                    ports.push(port);
+                }
+            }
+        }
+        if ports.is_empty() {
+            anyhow::bail!("No callback ports configured");
+        }
+        Ok(Self(ports))
+    }
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
+    pub fn ports(&self) -> &[u16] {
+        &self.0
+    }
+
+    // Ports from `K3_OAUTH_CALLBACK_PORTS`, or the default range when unset or invalid
+    pub fn from_env() -> Self {
+        match std::env::var(CALLBACK_PORTS_ENV) {
+            Ok(spec) => Self::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {}", CALLBACK_PORTS_ENV, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+pub fn callback_redirect_url(port: u16) -> String {
+    format!("http://localhost:{}/oauth/callback", port)
+}
 
+// Bind the callback listener up front so a busy port is reported to the caller
+pub async fn bind_oauth_listener(port: u16) -> anyhow::Result<TcpListener> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = TcpListener::bind(addr).await?;
     println!("OAuth server listening on {}", addr);
+    Ok(listener)
+}
 
-    // Single connection only
-    let (stream, _remote_addr) = listener.accept().await?;
-    let io = TokioIo::new(stream);
+// Bind the first free port from the allow-list and return the listener with its redirect URL
+pub async fn bind_callback_listener(ports: &CallbackPorts) -> anyhow::Result<(TcpListener, String)> {
+    for &port in &ports.0 {
+        match bind_oauth_listener(port).await {
+            Ok(listener) => return Ok((listener, callback_redirect_url(port))),
+            Err(e) => eprintln!("OAuth callback port {} unavailable: {}", port, e),
+        }
+    }
+    anyhow::bail!("No free OAuth callback port in {:?}", ports.0)
+}
 
-    use std::sync::{Arc, Mutex};
+// Serve until a valid `/oauth/callback` carrying `expected_state` arrives or `cancel` fires (flow cancelled / timed out / finished).
+// Unrelated requests (favicon, pre-connects, probes) get a 404 and callbacks with another state a 400, without consuming `tx`;
+// connections are served concurrently with keep-alive. The listener is dropped on return, which frees the port.
+pub async fn serve_oauth_callback(
+    listener: TcpListener,
+    expected_state: String,
+    tx: oneshot::Sender<OAuthCallback>,
+    cancel: CancellationToken,
+) -> anyhow::Result<()> {
+    let expected_state: Arc<str> = expected_state.into();
     let tx = Arc::new(Mutex::new(Some(tx)));
-    let service = service_fn({
-        let tx = tx.clone();
-        move |req| {
-            let tx = tx.clone();
-            async move {
-                let tx_opt = tx.lock().unwrap().take();
-                handle_request(req, tx_opt).await
+    // Fired once the callback has been handed over; stops accepting new connections
+    let delivered = CancellationToken::new();
+
+    loop {
+        let (stream, _remote_addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("OAuth server accept error: {}", e);
+                    continue;
+                }
+            },
+            _ = delivered.cancelled() => break,
+            _ = cancel.cancelled() => {
+                println!("OAuth server stopped before callback");
+                break;
             }
-        }
-    });
+        };
 
-    http1::Builder::new()
-        .serve_connection(io, service)
-        .await?;
+        let service = service_fn({
+            let expected_state = expected_state.clone();
+            let tx = tx.clone();
+            let delivered = delivered.clone();
+            move |req| {
+                let expected_state = expected_state.clone();
+                let tx = tx.clone();
+                let delivered = delivered.clone();
+                async move { handle_request(req, &expected_state, &tx, &delivered).await }
+            }
+        });
+        // Open connections live until the flow ends, so the callback response is never cut short
+        let conn_cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                served = http1::Builder::new().serve_connection(TokioIo::new(stream), service) => {
+                    if let Err(e) = served {
+                        eprintln!("OAuth server connection error: {}", e);
+                    }
+                }
+                _ = conn_cancel.cancelled() => {}
+            }
+        });
+    }
 
     Ok(())
 }
 
+fn text_response(status: StatusCode, body: &'static str) -> Response<Full<HyperBytes>> {
+    Response::builder()
+        .status(status)
+        .body(Full::new(HyperBytes::from(body)))
+        .unwrap()
+}
+
 async fn handle_request(
     req: Request<HyperIncomingBody>,
-    tx: Option<oneshot::Sender<(String, String)>>,
+    expected_state: &str,
+    tx: &Mutex<Option<oneshot::Sender<OAuthCallback>>>,
+    delivered: &CancellationToken,
 ) -> Result<Response<Full<HyperBytes>>, Infallible> {
     let path = req.uri().path();
-    let query = req.uri().query();
+    let callback = req.uri().query().and_then(OAuthCallback::from_query);
 
     if path == "/oauth/callback" {
-        if let Some(query_str) = query {
-            let url = Url::parse(&format!("http://localhost/?{}", query_str)).unwrap();
-            let code = url.query_pairs().find(|(key, _)| key == "code").map(|(_, value)| value.to_string());
-            let state = url.query_pairs().find(|(key, _)| key == "state").map(|(_, value)| value.to_string());
-
-            if let (Some(code), Some(state)) = (code, state) {
-                // Received OAuth callback (redacted)
-                if let Some(tx) = tx {
-                    let _ = tx.send((code, state)); // Send the code and state back to the main app
-                }
-                let response_html = r#"
-                    <!DOCTYPE html>
-                    <html lang="ja">
-                    <head>
-                        <meta charset="UTF-8">
-                        <meta name="viewport" content="width=device-width, initial-scale=1.0">
-                        <title>認証完了</title>
-                        <style>
-                            body { 
-                                font-family: "Segoe UI", Tahoma, Geneva, Verdana, sans-serif; 
-                                text-align: center; 
-                                margin-top: 50px; 
-                                background-color: #f5f5f5;
-                                padding: 20px;
-                            }
-                            .container {
-                                background-color: white;
-                                border-radius: 8px;
-                                padding: 40px;
-                                box-shadow: 0 2px 10px rgba(0,0,0,0.1);
-                                max-width: 500px;
-                                margin: 0 auto;
-                            }
-                            h1 { 
-                                color: #4CAF50; 
-                                margin-bottom: 20px;
-                            }
-                            p { 
-                                color: #666; 
-                                margin-bottom: 20px;
-                                line-height: 1.6;
-                            }
-                            .countdown {
-                                font-size: 18px;
-                                color: #2196F3;
-                                font-weight: bold;
-                            }
-                        </style>
-                        <script>
-                            let countdown = 5;
-                            function updateCountdown() {
-                                const el = document.getElementById('countdown');
-                                if (el) el.textContent = countdown;
-                                if (countdown <= 0) {
-                                    window.close();
-                                } else {
-                                    countdown--;
-                                    setTimeout(updateCountdown, 1000);
-                                }
-                            }
-                            window.onload = updateCountdown;
-                        </script>
-                    </head>
-                    <body>
-                        <div class="container">
-                            <h1>✅ 認証が完了しました</h1>
-                            <p>認証が正常に完了しました。<br>アプリケーションに戻ってご利用ください。</p>
-                            <p class="countdown">このタブは <span id="countdown">5</span> 秒後に自動で閉じます</p>
-                        </div>
-                    </body>
-                    </html>
-                "#;
-
-                return Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .header("Content-Type", "text/html")
-                    .body(Full::new(HyperBytes::from(response_html)))
-                    .unwrap());
+        if let Some(callback) = callback {
+            // A stale tab or another local page must not use up the callback meant for this flow
+            if callback.state() != Some(expected_state) {
+                eprintln!("Ignoring OAuth callback with an unexpected state");
+                return Ok(text_response(StatusCode::BAD_REQUEST, "OAuth state mismatch"));
             }
+            // Received OAuth callback (redacted). Only the first one is handed to the app.
+            let Some(tx) = tx.lock().unwrap().take() else {
+                return Ok(text_response(StatusCode::CONFLICT, "OAuth callback already received"));
+            };
+            let html = match &callback {
+                OAuthCallback::Error { error, description, .. } => failure_page_html(error, description.as_deref()),
+                OAuthCallback::Code { .. } => success_page_html(),
+            };
+            let _ = tx.send(callback); // Send the callback back to the main app
+            delivered.cancel();
+
+            return Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Full::new(HyperBytes::from(html)))
+                .unwrap());
         }
     }
 
-    // For any other path or missing parameters, return a 404
-    Ok(Response::builder()
-        .status(StatusCode::NOT_FOUND)
-        .body(Full::new(HyperBytes::from("Not Found")))
-        .unwrap())
+    // For any other path or missing parameters, return a 404 and keep waiting for the callback
+    Ok(text_response(StatusCode::NOT_FOUND, "Not Found"))
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+// Page shown in the browser tab after the redirect; it closes itself after 5 seconds.
+// `body_html` is inserted as is, so provider text must be escaped by the caller.
+fn callback_page_html(title: &str, icon: &str, heading_color: &str, body_html: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="ja">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{title}</title>
+    <style>
+        body {{ font-family: "Segoe UI", Tahoma, Geneva, Verdana, sans-serif; text-align: center; margin-top: 50px; background-color: #f5f5f5; padding: 20px; }}
+        .container {{ background-color: white; border-radius: 8px; padding: 40px; box-shadow: 0 2px 10px rgba(0,0,0,0.1); max-width: 500px; margin: 0 auto; }}
+        h1 {{ color: {heading_color}; margin-bottom: 20px; }}
+        p {{ color: #666; margin-bottom: 20px; line-height: 1.6; }}
+        code {{ color: #999; font-size: 13px; }}
+        .countdown {{ font-size: 18px; color: #2196F3; font-weight: bold; }}
+    </style>
+    <script>
+        let countdown = 5;
+        function updateCountdown() {{
+            const el = document.getElementById('countdown');
+            if (el) el.textContent = countdown;
+            if (countdown <= 0) {{
+                window.close();
+            }} else {{
+                countdown--;
+                setTimeout(updateCountdown, 1000);
+            }}
+        }}
+        window.onload = updateCountdown;
+    </script>
+</head>
+<body>
+    <div class="container">
+        <h1>{icon} {title}</h1>
+        {body_html}
+        <p class="countdown">このタブは <span id="countdown">5</span> 秒後に自動で閉じます</p>
+    </div>
+</body>
+</html>
+"#
+    )
+}
+
+fn success_page_html() -> String {
+    callback_page_html(
+        "認証が完了しました",
+        "✅",
+        "#4CAF50",
+        "<p>認証が正常に完了しました。<br>アプリケーションに戻ってご利用ください。</p>",
+    )
+}
+
+// Browser page for an error redirect; the provider's text is escaped
+fn failure_page_html(error: &str, description: Option<&str>) -> String {
+    let (title, message) = if error == "access_denied" {
+        ("認証がキャンセルされました", "認証は許可されませんでした。<br>必要な場合はアプリケーションから再度お試しください。")
+    } else {
+        ("認証に失敗しました", "プロバイダからエラーが返されました。<br>アプリケーションに戻って設定を確認してください。")
+    };
+    let detail = match description {
+        Some(description) => format!("{}: {}", html_escape(error), html_escape(description)),
+        None => html_escape(error),
+    };
+    callback_page_html(title, "❌", "#F44336", &format!("<p>{}</p>\n        <p><code>{}</code></p>", message, detail))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn cancel_shuts_down_listener_and_frees_port() {
+        let listener = bind_oauth_listener(0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = oneshot::channel();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve_oauth_callback(listener, "s".to_string(), tx, cancel.clone()));
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server should stop on cancel")
+            .unwrap()
+            .unwrap();
+
+        // Sender dropped without a callback, and the port can be bound again
+        assert!(rx.await.is_err());
+        bind_oauth_listener(port).await.unwrap();
+    }
+
+    // Open a keep-alive HTTP/1 client connection to the server
+    async fn connect(port: u16) -> hyper::client::conn::http1::SendRequest<http_body_util::Empty<HyperBytes>> {
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let (sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    async fn get(
+        sender: &mut hyper::client::conn::http1::SendRequest<http_body_util::Empty<HyperBytes>>,
+        path: &str,
+    ) -> StatusCode {
+        sender.ready().await.unwrap();
+        let req = Request::get(path)
+            .header("Host", "localhost")
+            .body(http_body_util::Empty::new())
+            .unwrap();
+        sender.send_request(req).await.unwrap().status()
+    }
+
+    async fn spawn_server(state: &str) -> (u16, oneshot::Receiver<OAuthCallback>, CancellationToken, tokio::task::JoinHandle<anyhow::Result<()>>) {
+        let listener = bind_oauth_listener(0).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = oneshot::channel();
+        let cancel = CancellationToken::new();
+        let server = tokio::spawn(serve_oauth_callback(listener, state.to_string(), tx, cancel.clone()));
+        (port, rx, cancel, server)
+    }
+
+    fn code_callback(code: &str, state: &str) -> OAuthCallback {
+        OAuthCallback::Code { code: code.to_string(), state: state.to_string() }
+    }
+
+    #[tokio::test]
+    async fn error_redirect_is_forwarded_to_the_flow() {
+        let (port, rx, cancel, _server) = spawn_server("s1").await;
+
+        let mut sender = connect(port).await;
+        let status = get(
+            &mut sender,
+            "/oauth/callback?error=access_denied&error_description=User%20%3Cb%3Edenied%3C%2Fb%3E&state=s1",
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            rx.await.unwrap(),
+            OAuthCallback::Error {
+                error: "access_denied".to_string(),
+                description: Some("User <b>denied</b>".to_string()),
+                state: Some("s1".to_string()),
+            }
+        );
+        cancel.cancel();
+    }
+
+    #[test]
+    fn failure_page_escapes_provider_text() {
+        let html = failure_page_html("invalid_scope", Some("<script>x</script>"));
+        assert!(html.contains("認証に失敗しました"));
+        assert!(html.contains("invalid_scope: &lt;script&gt;x&lt;/script&gt;"));
+        assert!(!html.contains("<script>x"));
+        assert!(failure_page_html("access_denied", None).contains("キャンセル"));
+    }
+
+    #[test]
+    fn both_pages_close_themselves() {
+        for html in [success_page_html(), failure_page_html("server_error", None)] {
+            assert!(html.contains("window.close()") && html.contains("<span id=\"countdown\">5</span>"), "{}", html);
+        }
+    }
+
+    #[tokio::test]
+    async fn unrelated_requests_do_not_consume_the_callback() {
+        let (port, rx, cancel, server) = spawn_server("s1").await;
+
+        // favicon and a bare callback on the same keep-alive connection, then the real callback
+        let mut sender = connect(port).await;
+        assert_eq!(get(&mut sender, "/favicon.ico").await, StatusCode::NOT_FOUND);
+        assert_eq!(get(&mut sender, "/oauth/callback").await, StatusCode::NOT_FOUND);
+        assert_eq!(get(&mut sender, "/oauth/callback?code=c1&state=s1").await, StatusCode::OK);
+        assert_eq!(rx.await.unwrap(), code_callback("c1", "s1"));
+
+        // A replayed callback is not delivered twice
+        assert_eq!(get(&mut sender, "/oauth/callback?code=c2&state=s1").await, StatusCode::CONFLICT);
+
+        // The accept loop stops after delivery; open connections close when the flow ends
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+        bind_oauth_listener(port).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn wrong_state_callback_does_not_consume_the_callback() {
+        let (port, rx, cancel, _server) = spawn_server("s1").await;
+
+        // A stale tab from an earlier flow, then an error redirect without any state
+        let mut sender = connect(port).await;
+        assert_eq!(get(&mut sender, "/oauth/callback?code=old&state=s0").await, StatusCode::BAD_REQUEST);
+        assert_eq!(get(&mut sender, "/oauth/callback?error=access_denied").await, StatusCode::BAD_REQUEST);
+        assert_eq!(get(&mut sender, "/oauth/callback?code=c1&state=s1").await, StatusCode::OK);
+        assert_eq!(rx.await.unwrap(), code_callback("c1", "s1"));
+        cancel.cancel();
+    }
+
+    #[tokio::test]
+    async fn serves_concurrent_connections() {
+        let (port, rx, cancel, _server) = spawn_server("s").await;
+
+        // An idle pre-connect must not block other connections
+        let _preconnect = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut probe = connect(port).await;
+        let mut browser = connect(port).await;
+        assert_eq!(get(&mut probe, "/favicon.ico").await, StatusCode::NOT_FOUND);
+        assert_eq!(get(&mut browser, "/oauth/callback?code=c&state=s").await, StatusCode::OK);
+        assert_eq!(rx.await.unwrap(), code_callback("c", "s"));
+        cancel.cancel();
+    }
+
+    #[test]
+    fn parses_port_lists_and_ranges() {
+        let ports = CallbackPorts::parse("1421-1423, 8080,1422").unwrap();
+        assert_eq!(ports, CallbackPorts(vec![1421, 1422, 1423, 8080]));
+        assert!(CallbackPorts::parse("").is_err());
+        assert!(CallbackPorts::parse("1430-1421").is_err());
+        assert!(CallbackPorts::parse("0").is_err());
+        assert!(CallbackPorts::parse("http").is_err());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_next_free_port() {
+        let busy = bind_oauth_listener(0).await.unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let free_port = bind_oauth_listener(0).await.unwrap().local_addr().unwrap().port();
+
+        let ports = CallbackPorts(vec![busy_port, free_port]);
+        let (listener, redirect_url) = bind_callback_listener(&ports).await.unwrap();
+        assert_eq!(listener.local_addr().unwrap().port(), free_port);
+        assert_eq!(redirect_url, callback_redirect_url(free_port));
+
+        drop(listener);
+        assert!(bind_callback_listener(&CallbackPorts(vec![busy_port])).await.is_err());
+    }
 }
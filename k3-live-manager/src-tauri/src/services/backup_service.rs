@@ -0,0 +1,490 @@
+use crate::db::location::{with_suffix, DatabaseLocation};
+use crate::db::setup::validate_database;
+use crate::error::AppError;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+pub const DEFAULT_BACKUP_INTERVAL_HOURS: u64 = 24;
+pub const DEFAULT_BACKUP_RETENTION: usize = 7;
+
+// Hours between scheduled backups, e.g. `12`; `0` turns them off
+pub const BACKUP_INTERVAL_ENV: &str = "K3_BACKUP_INTERVAL_HOURS";
+
+// Number of scheduled backups to keep, e.g. `14`
+pub const BACKUP_RETENTION_ENV: &str = "K3_BACKUP_RETENTION";
+
+// Wait before trying again after a scheduled backup failed
+const RETRY_AFTER_FAILURE: Duration = Duration::from_secs(10 * 60);
+
+// How long app exit waits for a scheduled backup that is already being written
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+const BACKUP_EXTENSION: &str = ".sqlite";
+// Suffix of a backup still being written; `parse_backup_name` does not match it, so it is never listed
+const PARTIAL_SUFFIX: &str = ".partial";
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackupKind {
+    // Made on request; never removed automatically
+    Manual,
+    // Made by `BackupScheduler`; only the newest `retention` are kept
+    Scheduled,
+}
+
+impl BackupKind {
+    fn prefix(self) -> &'static str {
+        match self {
+            Self::Manual => "manual-",
+            Self::Scheduled => "scheduled-",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct BackupInfo {
+    pub file_name: String,
+    pub kind: BackupKind,
+    // UTC "YYYY-MM-DD HH:MM:SS", taken from the file name
+    pub created_at: String,
+    pub size_bytes: u64,
+}
+
+// A validated backup that replaces the database on the next start
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RestoreStaged {
+    pub file_name: String,
+    // Latest migration applied in the backup; newer migrations run when it is opened
+    pub schema_version: i64,
+    pub restart_required: bool,
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    with_suffix(path, PARTIAL_SUFFIX)
+}
+
+fn remove_partial_backups(backup_dir: &Path) {
+    let Ok(entries) = std::fs::read_dir(backup_dir) else {
+        return;
+    };
+    for path in entries.filter_map(|e| e.ok()).map(|e| e.path()) {
+        if path.to_str().is_some_and(|p| p.ends_with(PARTIAL_SUFFIX)) {
+            if let Err(e) = std::fs::remove_file(&path) {
+                eprintln!("Failed to remove incomplete backup {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// `<kind>-<YYYYmmdd-HHMMSS>[.<n>].sqlite` -> kind, time and the tie-breaker for backups made within one second
+fn parse_backup_name(file_name: &str) -> Option<(BackupKind, NaiveDateTime, u32)> {
+    let stem = file_name.strip_suffix(BACKUP_EXTENSION)?;
+    let (kind, rest) = [BackupKind::Manual, BackupKind::Scheduled]
+        .into_iter()
+        .find_map(|kind| stem.strip_prefix(kind.prefix()).map(|rest| (kind, rest)))?;
+    let (timestamp, seq) = match rest.split_once('.') {
+        Some((timestamp, seq)) => (timestamp, seq.parse().ok()?),
+        None => (rest, 0),
+    };
+    let created_at = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+    Some((kind, created_at, seq))
+}
+
+// Timing and retention of scheduled backups
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupSchedule {
+    // None turns scheduled backups off
+    pub interval: Option<Duration>,
+    pub retention: usize,
+}
+
+impl Default for BackupSchedule {
+    fn default() -> Self {
+        Self {
+            interval: Some(Duration::from_secs(DEFAULT_BACKUP_INTERVAL_HOURS * 60 * 60)),
+            retention: DEFAULT_BACKUP_RETENTION,
+        }
+    }
+}
+
+impl BackupSchedule {
+    // Default schedule with `K3_BACKUP_INTERVAL_HOURS`/`K3_BACKUP_RETENTION` applied when set and valid
+    pub fn from_env() -> Self {
+        let mut schedule = Self::default();
+        if let Ok(value) = std::env::var(BACKUP_INTERVAL_ENV) {
+            match value.trim().parse::<u64>() {
+                Ok(0) => schedule.interval = None,
+                Ok(hours) => schedule.interval = Some(Duration::from_secs(hours * 60 * 60)),
+                Err(e) => eprintln!("Ignoring {}: {}", BACKUP_INTERVAL_ENV, e),
+            }
+        }
+        if let Ok(value) = std::env::var(BACKUP_RETENTION_ENV) {
+            match value.trim().parse::<usize>() {
+                Ok(retention) if retention > 0 => schedule.retention = retention,
+                Ok(_) => eprintln!("Ignoring {}: keep at least one backup", BACKUP_RETENTION_ENV),
+                Err(e) => eprintln!("Ignoring {}: {}", BACKUP_RETENTION_ENV, e),
+            }
+        }
+        schedule
+    }
+}
+
+// Online backups with `VACUUM INTO` and restores staged for the next start
+#[derive(Clone)]
+pub struct BackupService {
+    pool: SqlitePool,
+    database: DatabaseLocation,
+    backup_dir: PathBuf,
+    retention: usize,
+}
+
+impl BackupService {
+    // Also removes backups left half-written by an earlier run; none of this service's own can be in progress yet
+    pub fn new(pool: SqlitePool, database: DatabaseLocation, retention: usize) -> Self {
+        let backup_dir = database.backup_dir();
+        remove_partial_backups(&backup_dir);
+        Self { pool, database, backup_dir, retention }
+    }
+
+    pub async fn create_backup(&self, kind: BackupKind) -> anyhow::Result<BackupInfo> {
+        self.create_backup_at(kind, Utc::now()).await
+    }
+
+    async fn create_backup_at(&self, kind: BackupKind, now: DateTime<Utc>) -> anyhow::Result<BackupInfo> {
+        std::fs::create_dir_all(&self.backup_dir)
+            .map_err(|e| anyhow::anyhow!("Failed to create backup directory {}: {}", self.backup_dir.display(), e))?;
+        let path = self.free_backup_path(kind, now);
+        let partial = partial_path(&path);
+        let target = partial.to_str().ok_or_else(|| anyhow::anyhow!("Backup path is not valid UTF-8: {}", partial.display()))?;
+
+        // VACUUM INTO reads one consistent snapshot while the app keeps writing.
+        // It writes under a temporary name so an interrupted backup is never listed, restored or counted for retention.
+        if let Err(e) = sqlx::query("VACUUM INTO ?").bind(target).execute(&self.pool).await {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e.into());
+        }
+        std::fs::rename(&partial, &path).map_err(|e| anyhow::anyhow!("Failed to move backup into place at {}: {}", path.display(), e))?;
+
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string();
+        let info = self.backup_info(&file_name).ok_or_else(|| anyhow::anyhow!("Backup {} was not written", file_name))?;
+        if kind == BackupKind::Scheduled {
+            self.prune()?;
+        }
+        Ok(info)
+    }
+
+    // Newest first
+    pub fn list_backups(&self) -> anyhow::Result<Vec<BackupInfo>> {
+        let entries = match std::fs::read_dir(&self.backup_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut backups = Vec::new();
+        for entry in entries {
+            let name = entry?.file_name();
+            if let Some(info) = name.to_str().and_then(|name| self.backup_info(name)) {
+                backups.push(info);
+            }
+        }
+        backups.sort_by_key(|b| std::cmp::Reverse(parse_backup_name(&b.file_name).map(|(_, at, seq)| (at, seq))));
+        Ok(backups)
+    }
+
+    // Validate `file_name` from the backup directory and stage it to replace the database on the next start.
+    // Changes made after this call are lost once the app restarts.
+    pub async fn stage_restore(&self, file_name: &str) -> anyhow::Result<RestoreStaged> {
+        // Only names listed in the backup directory, so the UI cannot point at arbitrary files
+        if self.backup_info(file_name).is_none() {
+            return Err(AppError::not_found("Backup").into());
+        }
+        let source = self.backup_dir.join(file_name);
+        let schema_version = validate_database(&source).await?;
+
+        let staged = self.database.staged_restore_path();
+        let partial = staged.with_extension("restore-partial");
+        std::fs::copy(&source, &partial)
+            .map_err(|e| anyhow::anyhow!("Failed to copy backup {}: {}", file_name, e))?;
+        std::fs::rename(&partial, &staged)
+            .map_err(|e| anyhow::anyhow!("Failed to stage backup {}: {}", file_name, e))?;
+        Ok(RestoreStaged { file_name: file_name.to_string(), schema_version, restart_required: true })
+    }
+
+    // Delay until the next scheduled backup is due, given the newest one on disk
+    fn next_scheduled_in(&self, interval: Duration) -> Duration {
+        let latest = self
+            .list_backups()
+            .unwrap_or_default()
+            .into_iter()
+            .find(|b| b.kind == BackupKind::Scheduled)
+            .and_then(|b| parse_backup_name(&b.file_name))
+            .map(|(_, created_at, _)| created_at.and_utc());
+        match latest {
+            Some(latest) => (latest + chrono::Duration::from_std(interval).unwrap_or_default() - Utc::now())
+                .to_std()
+                .unwrap_or(Duration::ZERO),
+            None => Duration::ZERO,
+        }
+    }
+
+    // Backups and the database a restore replaced. Copies made before the passphrase was set still hold
+    // the secrets in plaintext; encrypting the live database does not touch them.
+    pub fn database_copies(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut copies: Vec<PathBuf> = self.list_backups()?.into_iter().map(|b| self.backup_dir.join(b.file_name)).collect();
+        let pre_restore = self.database.pre_restore_path();
+        if pre_restore.is_file() {
+            copies.push(pre_restore);
+        }
+        Ok(copies)
+    }
+
+    // Remove scheduled backups beyond the retention count, oldest first
+    fn prune(&self) -> anyhow::Result<()> {
+        let scheduled = self.list_backups()?.into_iter().filter(|b| b.kind == BackupKind::Scheduled);
+        for backup in scheduled.skip(self.retention) {
+            let path = self.backup_dir.join(&backup.file_name);
+            std::fs::remove_file(&path).map_err(|e| anyhow::anyhow!("Failed to remove old backup {}: {}", path.display(), e))?;
+        }
+        Ok(())
+    }
+
+    fn backup_info(&self, file_name: &str) -> Option<BackupInfo> {
+        let (kind, created_at, _) = parse_backup_name(file_name)?;
+        let metadata = std::fs::metadata(self.backup_dir.join(file_name)).ok().filter(|m| m.is_file())?;
+        Some(BackupInfo {
+            file_name: file_name.to_string(),
+            kind,
+            created_at: created_at.format("%Y-%m-%d %H:%M:%S").to_string(),
+            size_bytes: metadata.len(),
+        })
+    }
+
+    fn free_backup_path(&self, kind: BackupKind, now: DateTime<Utc>) -> PathBuf {
+        let stem = format!("{}{}", kind.prefix(), now.format(TIMESTAMP_FORMAT));
+        let mut path = self.backup_dir.join(format!("{}{}", stem, BACKUP_EXTENSION));
+        let mut seq = 1;
+        while path.exists() || partial_path(&path).exists() {
+            path = self.backup_dir.join(format!("{}.{}{}", stem, seq, BACKUP_EXTENSION));
+            seq += 1;
+        }
+        path
+    }
+}
+
+// Background task that makes a scheduled backup every `interval` and prunes old ones
+pub struct BackupScheduler {
+    cancel: CancellationToken,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl BackupScheduler {
+    pub fn start(service: BackupService, schedule: BackupSchedule) -> Self {
+        let cancel = CancellationToken::new();
+        let task = schedule.interval.map(|interval| tokio::spawn(run_scheduled_backups(service, interval, cancel.clone())));
+        Self { cancel, task: Mutex::new(task) }
+    }
+
+    // Stop the loop; a backup already being written is allowed to finish
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await.is_err() {
+                eprintln!("Backup scheduler did not stop within {:?}", SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
+
+async fn run_scheduled_backups(service: BackupService, interval: Duration, cancel: CancellationToken) {
+    // Catch up right away when the last scheduled backup is older than the interval
+    let mut wait = service.next_scheduled_in(interval);
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = cancel.cancelled() => break,
+        }
+        wait = match service.create_backup(BackupKind::Scheduled).await {
+            Ok(info) => {
+                println!("Scheduled backup written: {}", info.file_name);
+                interval
+            }
+            Err(e) => {
+                eprintln!("Scheduled backup failed: {:#}", e);
+                interval.min(RETRY_AFTER_FAILURE)
+            }
+        };
+    }
+    println!("Backup scheduler stopped.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::location::DatabaseSource;
+    use crate::db::setup::{connect_with, MIGRATOR};
+    use sqlx::sqlite::SqliteConnectOptions;
+    use crate::error::ErrorCode;
+    use crate::test_support::TempDir;
+    use chrono::TimeZone;
+
+    async fn setup(dir: &TempDir, retention: usize) -> BackupService {
+        let database = DatabaseLocation { path: dir.path().join("app.sqlite"), source: DatabaseSource::AppData };
+        let pool = connect_with(database.connect_options()).await.unwrap();
+        sqlx::query("INSERT INTO oauth_providers (provider_key, display_name, auth_url, token_url) VALUES ('p', 'P', 'a', 't')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        BackupService::new(pool, database, retention)
+    }
+
+    fn at(secs: i64) -> DateTime<Utc> {
+        Utc.timestamp_opt(1_750_000_000 + secs, 0).unwrap()
+    }
+
+    fn names(backups: &[BackupInfo]) -> Vec<&str> {
+        backups.iter().map(|b| b.file_name.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn backup_is_a_consistent_copy_of_the_live_database() {
+        let dir = TempDir::new("backup-copy");
+        let service = setup(&dir, 3).await;
+
+        let info = service.create_backup(BackupKind::Manual).await.unwrap();
+        assert_eq!(info.kind, BackupKind::Manual);
+        assert!(info.size_bytes > 0);
+        assert_eq!(service.list_backups().unwrap(), vec![info.clone()]);
+
+        let copy = connect_with(SqliteConnectOptions::new().filename(service.backup_dir.join(&info.file_name))).await.unwrap();
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM oauth_providers").fetch_one(&copy).await.unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_backups_are_never_listed_and_are_cleaned_up() {
+        let dir = TempDir::new("backup-partial");
+        let service = setup(&dir, 1).await;
+        let good = service.create_backup_at(BackupKind::Scheduled, at(0)).await.unwrap();
+        assert!(!partial_path(&service.backup_dir.join(&good.file_name)).exists());
+
+        // A crash in the middle of a later backup leaves only the temporary file
+        let interrupted = partial_path(&service.free_backup_path(BackupKind::Scheduled, at(1)));
+        std::fs::write(&interrupted, b"truncated").unwrap();
+        assert_eq!(service.list_backups().unwrap(), vec![good.clone()]);
+
+        let restarted = BackupService::new(service.pool.clone(), service.database.clone(), 1);
+        assert!(!interrupted.exists());
+        assert_eq!(restarted.list_backups().unwrap(), vec![good]);
+    }
+
+    #[tokio::test]
+    async fn scheduled_backups_are_pruned_to_the_retention_count() {
+        let dir = TempDir::new("backup-retention");
+        let service = setup(&dir, 2).await;
+
+        let manual = service.create_backup_at(BackupKind::Manual, at(0)).await.unwrap();
+        for secs in 1..=3 {
+            service.create_backup_at(BackupKind::Scheduled, at(secs)).await.unwrap();
+        }
+        // Two backups within the same second get distinct names and keep their order
+        let same_second = service.create_backup_at(BackupKind::Scheduled, at(3)).await.unwrap();
+        assert!(same_second.file_name.ends_with(".1.sqlite"), "{}", same_second.file_name);
+
+        let backups = service.list_backups().unwrap();
+        let expected_latest = format!("scheduled-{}.sqlite", at(3).format(TIMESTAMP_FORMAT));
+        assert_eq!(names(&backups), vec![same_second.file_name.as_str(), expected_latest.as_str(), manual.file_name.as_str()]);
+    }
+
+    #[tokio::test]
+    async fn database_copies_cover_backups_and_the_replaced_database() {
+        let dir = TempDir::new("backup-copies");
+        let service = setup(&dir, 2).await;
+        assert!(service.database_copies().unwrap().is_empty());
+
+        let info = service.create_backup(BackupKind::Manual).await.unwrap();
+        std::fs::write(service.database.pre_restore_path(), b"previous").unwrap();
+        assert_eq!(
+            service.database_copies().unwrap(),
+            vec![service.backup_dir.join(&info.file_name), service.database.pre_restore_path()]
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_stages_a_validated_backup() {
+        let dir = TempDir::new("backup-restore");
+        let service = setup(&dir, 2).await;
+        let info = service.create_backup(BackupKind::Manual).await.unwrap();
+
+        let staged = service.stage_restore(&info.file_name).await.unwrap();
+        assert_eq!(staged.schema_version, MIGRATOR.iter().map(|m| m.version).max().unwrap());
+        assert!(staged.restart_required);
+        assert!(service.database.staged_restore_path().is_file());
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_unknown_names_and_foreign_databases() {
+        let dir = TempDir::new("backup-reject");
+        let service = setup(&dir, 2).await;
+        let code = |result: anyhow::Result<RestoreStaged>| AppError::from(result.unwrap_err()).code;
+
+        assert_eq!(code(service.stage_restore("../app.sqlite").await), ErrorCode::NotFound);
+        assert_eq!(code(service.stage_restore("manual-20250101-000000.sqlite").await), ErrorCode::NotFound);
+
+        // A SQLite file without the migration table
+        let foreign = service.backup_dir.join("manual-20250101-000000.sqlite");
+        std::fs::create_dir_all(&service.backup_dir).unwrap();
+        let pool = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&foreign).create_if_missing(true)).await.unwrap();
+        sqlx::query("CREATE TABLE other (id INTEGER)").execute(&pool).await.unwrap();
+        pool.close().await;
+        assert_eq!(code(service.stage_restore("manual-20250101-000000.sqlite").await), ErrorCode::InvalidInput);
+
+        // Not a database at all
+        std::fs::write(service.backup_dir.join("manual-20250101-000001.sqlite"), b"not sqlite").unwrap();
+        assert_eq!(code(service.stage_restore("manual-20250101-000001.sqlite").await), ErrorCode::InvalidInput);
+        assert!(!service.database.staged_restore_path().exists());
+    }
+
+    #[tokio::test]
+    async fn restore_rejects_backups_from_a_newer_app() {
+        let dir = TempDir::new("backup-newer");
+        let service = setup(&dir, 2).await;
+        let info = service.create_backup(BackupKind::Manual).await.unwrap();
+
+        let backup = SqlitePool::connect_with(SqliteConnectOptions::new().filename(service.backup_dir.join(&info.file_name))).await.unwrap();
+        sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (99990101000001, 'future', 1, x'00', 0)")
+            .execute(&backup)
+            .await
+            .unwrap();
+        backup.close().await;
+
+        let error = AppError::from(service.stage_restore(&info.file_name).await.unwrap_err());
+        assert_eq!(error.code, ErrorCode::InvalidInput);
+        assert!(error.message.contains("newer version"), "{}", error.message);
+    }
+
+    #[tokio::test]
+    async fn scheduler_catches_up_and_keeps_the_retention_count() {
+        let dir = TempDir::new("backup-scheduler");
+        let service = setup(&dir, 2).await;
+        let schedule = BackupSchedule { interval: Some(Duration::from_millis(50)), retention: 2 };
+
+        let scheduler = BackupScheduler::start(service.clone(), schedule);
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        scheduler.shutdown().await;
+
+        let backups = service.list_backups().unwrap();
+        assert_eq!(backups.len(), 2);
+        assert!(backups.iter().all(|b| b.kind == BackupKind::Scheduled));
+
+        let disabled = BackupScheduler::start(service.clone(), BackupSchedule { interval: None, retention: 2 });
+        disabled.shutdown().await;
+    }
+}
@@ -0,0 +1,258 @@
+use crate::db::models::ServiceCredentialView;
+use crate::oauth_server::{callback_redirect_url, CallbackPorts, CALLBACK_PORTS_ENV};
+use crate::services::oauth_provider::{OAuthProvider, GOOGLE};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+// Hosts Google has used for auth_uri/token_uri in downloaded client files
+const GOOGLE_OAUTH_HOSTS: &[&str] = &["accounts.google.com", "oauth2.googleapis.com", "www.googleapis.com"];
+
+// OAuth client types found at the top level of a Google `client_secret_*.json`
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ClientType {
+    // "Desktop app": any loopback port is accepted as redirect URI
+    Installed,
+    // "Web application": only the exact registered redirect URIs are accepted
+    Web,
+}
+
+// Result of importing a client file: the new credential plus anything the user should fix in the console
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientSecretImport {
+    pub credential: ServiceCredentialView,
+    pub client_type: ClientType,
+    // Callback ports whose redirect URI the client accepts
+    pub redirect_ports: Vec<u16>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientFields {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    project_id: Option<String>,
+    auth_uri: Option<String>,
+    token_uri: Option<String>,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+}
+
+// Parsed and checked contents of a Google OAuth client file
+#[derive(Debug, Clone)]
+pub struct ClientSecretFile {
+    pub client_type: ClientType,
+    pub client_id: String,
+    pub client_secret: String,
+    pub project_id: Option<String>,
+    pub auth_uri: Url,
+    pub token_uri: Url,
+    pub redirect_uris: Vec<String>,
+}
+
+impl ClientSecretFile {
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let value: serde_json::Value = serde_json::from_str(contents).context("Not a valid client_secret JSON file")?;
+        let object = value.as_object().context("Not a valid client_secret JSON file: expected a JSON object")?;
+        if object.get("type").and_then(|t| t.as_str()) == Some("service_account") {
+            anyhow::bail!("This is a service account key, not an OAuth client. Download the JSON of a 'Desktop app' or 'Web application' OAuth client instead");
+        }
+
+        let (client_type, fields) = match (object.get("installed"), object.get("web")) {
+            (Some(_), Some(_)) => anyhow::bail!("The file defines both an 'installed' and a 'web' client; import one client per file"),
+            (Some(fields), None) => (ClientType::Installed, fields),
+            (None, Some(fields)) => (ClientType::Web, fields),
+            (None, None) => match object.keys().next() {
+                Some(key) if object.len() == 1 => anyhow::bail!(
+                    "Unsupported OAuth client type '{}': only 'installed' (Desktop app) and 'web' (Web application) clients can be imported",
+                    key
+                ),
+                _ => anyhow::bail!("Not a Google OAuth client file: expected an 'installed' or 'web' section"),
+            },
+        };
+        let fields: ClientFields = serde_json::from_value(fields.clone()).context("Malformed OAuth client section")?;
+
+        let required = |value: Option<String>, name: &str| -> anyhow::Result<String> {
+            value
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+                .with_context(|| format!("{} is missing from the client file", name))
+        };
+        let client_id = required(fields.client_id, "client_id")?;
+        // Android/iOS/TV clients are downloaded without a secret and cannot use the loopback flow
+        let client_secret = required(fields.client_secret, "client_secret")?;
+        let auth_uri = Url::parse(&required(fields.auth_uri, "auth_uri")?).context("Invalid auth_uri")?;
+        let token_uri = Url::parse(&required(fields.token_uri, "token_uri")?).context("Invalid token_uri")?;
+
+        Ok(Self {
+            client_type,
+            client_id,
+            client_secret,
+            project_id: fields.project_id.filter(|p| !p.trim().is_empty()),
+            auth_uri,
+            token_uri,
+            redirect_uris: fields.redirect_uris,
+        })
+    }
+
+    // The file's endpoints belong to `provider`: Google's own hosts, or the same origins as the
+    // provider's (possibly overridden) endpoints
+    pub fn matches_provider(&self, provider: &OAuthProvider) -> bool {
+        let same_origin = |uri: &Url, endpoint: &str| Url::parse(endpoint).is_ok_and(|e| e.origin() == uri.origin());
+        if same_origin(&self.auth_uri, &provider.auth_url) && same_origin(&self.token_uri, &provider.token_url) {
+            return true;
+        }
+        let is_google = |uri: &Url| uri.scheme() == "https" && uri.host_str().is_some_and(|h| GOOGLE_OAUTH_HOSTS.contains(&h));
+        provider.key == GOOGLE && is_google(&self.auth_uri) && is_google(&self.token_uri)
+    }
+
+    // Callback ports the client accepts as redirect URI, plus warnings about the ones it does not.
+    // Fails when no callback port can be used.
+    pub fn redirect_ports(&self, ports: &CallbackPorts) -> anyhow::Result<(Vec<u16>, Vec<String>)> {
+        let registered: Vec<Url> = self.redirect_uris.iter().filter_map(|u| Url::parse(u).ok()).collect();
+        match self.client_type {
+            ClientType::Installed => {
+                let loopback = registered.iter().any(|u| u.scheme() == "http" && is_loopback(u));
+                if !self.redirect_uris.is_empty() && !loopback {
+                    anyhow::bail!(
+                        "The client does not allow loopback redirects (redirect_uris: {}); add http://localhost to its redirect URIs",
+                        self.redirect_uris.join(", ")
+                    );
+                }
+                Ok((ports.ports().to_vec(), Vec::new()))
+            }
+            ClientType::Web => {
+                let accepted: Vec<u16> = ports
+                    .ports()
+                    .iter()
+                    .copied()
+                    .filter(|&port| Url::parse(&callback_redirect_url(port)).is_ok_and(|ours| registered.contains(&ours)))
+                    .collect();
+                let Some(&first) = ports.ports().first() else {
+                    anyhow::bail!("No callback ports configured");
+                };
+                if accepted.is_empty() {
+                    let mut message = format!(
+                        "None of the web client's redirect URIs matches the loopback callback; register {} in Google Cloud Console",
+                        callback_redirect_url(first)
+                    );
+                    if registered.iter().any(|u| is_loopback(u) && u.host_str() != Some("localhost")) {
+                        message.push_str(" (the callback uses 'localhost', not an IP address)");
+                    }
+                    anyhow::bail!(message);
+                }
+                let mut warnings = Vec::new();
+                if accepted.len() < ports.ports().len() {
+                    let list = |ports: &[u16]| ports.iter().map(u16::to_string).collect::<Vec<_>>().join(", ");
+                    let missing: Vec<u16> = ports.ports().iter().copied().filter(|p| !accepted.contains(p)).collect();
+                    warnings.push(format!(
+                        "Only callback port(s) {} are registered as redirect URIs, so sign-in fails while they are in use. Register the URIs for port(s) {} or narrow {}",
+                        list(&accepted),
+                        list(&missing),
+                        CALLBACK_PORTS_ENV
+                    ));
+                }
+                Ok((accepted, warnings))
+            }
+        }
+    }
+}
+
+fn is_loopback(url: &Url) -> bool {
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INSTALLED: &str = r#"{"installed":{"client_id":"123.apps.googleusercontent.com","project_id":"k3-live","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","auth_provider_x509_cert_url":"https://www.googleapis.com/oauth2/v1/certs","client_secret":"GOCSPX-secret","redirect_uris":["http://localhost"]}}"#;
+
+    fn web(redirect_uris: &[&str]) -> String {
+        serde_json::json!({"web": {
+            "client_id": "456.apps.googleusercontent.com",
+            "client_secret": "GOCSPX-web",
+            "auth_uri": "https://accounts.google.com/o/oauth2/auth",
+            "token_uri": "https://oauth2.googleapis.com/token",
+            "redirect_uris": redirect_uris,
+            "javascript_origins": ["http://localhost:1420"]
+        }})
+        .to_string()
+    }
+
+    #[test]
+    fn installed_client_accepts_every_callback_port() {
+        let file = ClientSecretFile::parse(INSTALLED).unwrap();
+        assert_eq!(file.client_type, ClientType::Installed);
+        assert_eq!(file.client_id, "123.apps.googleusercontent.com");
+        assert_eq!(file.client_secret, "GOCSPX-secret");
+        assert_eq!(file.project_id.as_deref(), Some("k3-live"));
+        assert!(file.matches_provider(&OAuthProvider::google()));
+        assert!(!file.matches_provider(&OAuthProvider::twitch()));
+
+        let ports = CallbackPorts::parse("1421-1423").unwrap();
+        assert_eq!(file.redirect_ports(&ports).unwrap(), (vec![1421, 1422, 1423], vec![]));
+    }
+
+    #[test]
+    fn installed_client_without_loopback_redirect_is_rejected() {
+        let contents = INSTALLED.replace(r#"["http://localhost"]"#, r#"["urn:ietf:wg:oauth:2.0:oob"]"#);
+        let file = ClientSecretFile::parse(&contents).unwrap();
+        let err = file.redirect_ports(&CallbackPorts::default()).unwrap_err();
+        assert!(err.to_string().contains("loopback"), "{}", err);
+    }
+
+    #[test]
+    fn web_client_needs_registered_callback_urls() {
+        let ports = CallbackPorts::parse("1421-1423").unwrap();
+
+        let file = ClientSecretFile::parse(&web(&["http://localhost:1421/oauth/callback", "http://localhost:1423/oauth/callback"])).unwrap();
+        assert_eq!(file.client_type, ClientType::Web);
+        let (accepted, warnings) = file.redirect_ports(&ports).unwrap();
+        assert_eq!(accepted, vec![1421, 1423]);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("port(s) 1422"), "{}", warnings[0]);
+
+        let all = ["http://localhost:1421/oauth/callback", "http://localhost:1422/oauth/callback", "http://localhost:1423/oauth/callback"];
+        assert_eq!(ClientSecretFile::parse(&web(&all)).unwrap().redirect_ports(&ports).unwrap().1, Vec::<String>::new());
+
+        let err = ClientSecretFile::parse(&web(&["http://127.0.0.1:1421/oauth/callback"])).unwrap().redirect_ports(&ports).unwrap_err();
+        assert!(err.to_string().contains("http://localhost:1421/oauth/callback"), "{}", err);
+        assert!(err.to_string().contains("not an IP address"), "{}", err);
+    }
+
+    #[test]
+    fn unsupported_files_report_what_is_wrong() {
+        let message = |contents: &str| ClientSecretFile::parse(contents).unwrap_err().to_string();
+
+        assert!(message("not json").contains("Not a valid client_secret JSON file"));
+        assert!(message(r#"{"type":"service_account","client_email":"x@y"}"#).contains("service account"));
+        assert!(message(r#"{"android":{"client_id":"a"}}"#).contains("Unsupported OAuth client type 'android'"));
+        assert!(message(r#"{"client_id":"a","client_secret":"b"}"#).contains("expected an 'installed' or 'web' section"));
+        assert!(message(r#"{"installed":{},"web":{}}"#).contains("both"));
+        let no_secret = INSTALLED.replace(r#""client_secret":"GOCSPX-secret","#, "");
+        assert!(message(&no_secret).contains("client_secret is missing"));
+    }
+
+    #[test]
+    fn endpoints_must_belong_to_the_provider() {
+        let google = OAuthProvider::google();
+        let file = ClientSecretFile::parse(&INSTALLED.replace("https://oauth2.googleapis.com/token", "https://idp.example.com/token")).unwrap();
+        assert!(!file.matches_provider(&google));
+
+        // An overridden provider accepts files pointing at the override's origin
+        let mock = Url::parse("http://127.0.0.1:9000").unwrap();
+        let contents = INSTALLED
+            .replace("https://accounts.google.com/o/oauth2/auth", "http://127.0.0.1:9000/o/oauth2/auth")
+            .replace("https://oauth2.googleapis.com/token", "http://127.0.0.1:9000/token");
+        let file = ClientSecretFile::parse(&contents).unwrap();
+        assert!(!file.matches_provider(&google));
+        assert!(file.matches_provider(&google.with_base_url(&mock)));
+    }
+}
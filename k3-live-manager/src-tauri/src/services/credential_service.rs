@@ -1,17 +1,23 @@
-use crate::db::models::{AddCredentialPayload, ServiceCredential};
+use crate::db::models::{AddCredentialPayload, ImportClientSecretPayload, ServiceCredential, UpdateCredentialPayload};
 use crate::db::repositories::CredentialRepository;
+use crate::error::AppError;
+use crate::oauth_server::CallbackPorts;
+use crate::services::client_secret::{ClientSecretFile, ClientSecretImport};
+use crate::services::oauth_provider::{validate_auth_params, ProviderRegistry, GOOGLE};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 
 // CredentialRepositoryトレイトに依存する新しい構造体
 #[allow(dead_code)]
 pub struct CredentialService {
     repo: Arc<dyn CredentialRepository + Send + Sync>,
+    providers: ProviderRegistry,
 }
 
 #[allow(dead_code)]
 impl CredentialService {
-    pub fn new(repo: Arc<dyn CredentialRepository + Send + Sync>) -> Self {
-        Self { repo }
+    pub fn new(repo: Arc<dyn CredentialRepository + Send + Sync>, providers: ProviderRegistry) -> Self {
+        Self { repo, providers }
     }
 
     //--- Pass-through methods (business validations could be added here) ---
@@ -19,11 +25,114 @@ impl CredentialService {
         self.repo.get_all_credentials().await
     }
 
-    pub async fn add_credential(&self, payload: AddCredentialPayload) -> anyhow::Result<ServiceCredential> {
-        // In a real app, you might have validation or other business logic here
+    pub async fn add_credential(&self, mut payload: AddCredentialPayload) -> anyhow::Result<ServiceCredential> {
+        // The provider must be a built-in or a registered custom provider
+        self.providers.resolve(&payload.provider).await?;
+        payload.scopes = payload.scopes.map(normalize_scopes).filter(|s| !s.is_empty());
+        if let Some(auth_params) = &payload.auth_params {
+            validate_auth_params(auth_params)?;
+        }
+        payload.auth_params = payload.auth_params.filter(|p| !p.is_empty());
         self.repo.add_credential(payload).await
     }
 
+    // Create a Google credential from a downloaded `client_secret_*.json` ("installed" or "web" client).
+    // The file's endpoints must be Google's and its redirect URIs must allow our loopback callback.
+    pub async fn import_client_secret(&self, payload: ImportClientSecretPayload, callback_ports: &CallbackPorts) -> anyhow::Result<ClientSecretImport> {
+        let file = ClientSecretFile::parse(&payload.contents).map_err(invalid_file)?;
+        let provider = self.providers.resolve(GOOGLE).await?;
+        if !file.matches_provider(&provider) {
+            return Err(AppError::invalid_input(format!(
+                "auth_uri/token_uri ({}, {}) are not Google endpoints; add the credential manually with a custom provider",
+                file.auth_uri, file.token_uri
+            ))
+            .into());
+        }
+        let (redirect_ports, warnings) = file.redirect_ports(callback_ports).map_err(invalid_file)?;
+
+        let service_name = payload
+            .service_name
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .or_else(|| file.project_id.clone())
+            .ok_or_else(|| AppError::invalid_input("service_name is required when the file has no project_id"))?;
+        if let Some(existing) = self.repo.get_all_credentials().await?.into_iter().find(|c| c.client_id == file.client_id) {
+            let message = format!("Client '{}' is already registered as '{}'", file.client_id, existing.service_name);
+            return Err(AppError::conflict(message).with_details(serde_json::json!({ "credential_id": existing.id })).into());
+        }
+
+        let credential = self
+            .add_credential(AddCredentialPayload {
+                service_name,
+                client_id: file.client_id,
+                client_secret: file.client_secret,
+                provider: provider.key,
+                scopes: payload.scopes,
+                auth_params: None,
+            })
+            .await?;
+        Ok(ClientSecretImport { credential: credential.into(), client_type: file.client_type, redirect_ports, warnings })
+    }
+
+    // The only way a client secret leaves the backend; every other response carries a ServiceCredentialView
+    pub async fn reveal_client_secret(&self, id: i64) -> anyhow::Result<String> {
+        let cred = self
+            .repo
+            .get_credential_by_id(id)
+            .await?
+            .ok_or_else(|| AppError::not_found("Credential"))?;
+        println!("Client secret of credential {} was revealed", id);
+        Ok(cred.client_secret)
+    }
+
+    // Correct the service name or client id. Linked accounts are kept; note that tokens issued to
+    // a different client id cannot be refreshed with the new one.
+    pub async fn update_credential(&self, id: i64, mut payload: UpdateCredentialPayload) -> anyhow::Result<ServiceCredential> {
+        payload.service_name = payload.service_name.map(|n| required("service_name", &n)).transpose()?;
+        payload.client_id = payload.client_id.map(|c| required("client_id", &c)).transpose()?;
+        let credential = self.repo.update_credential(id, payload).await?;
+        Ok(credential.ok_or_else(|| AppError::not_found("Credential"))?)
+    }
+
+    // Replace the client secret, e.g. after rotating it in the provider's console. Linked tokens stay valid.
+    pub async fn rotate_client_secret(&self, id: i64, client_secret: String) -> anyhow::Result<ServiceCredential> {
+        let client_secret = required("client_secret", &client_secret)?;
+        let cred = self
+            .repo
+            .update_client_secret(id, &client_secret)
+            .await?
+            .ok_or_else(|| AppError::not_found("Credential"))?;
+        println!("Client secret of credential {} was rotated", id);
+        Ok(cred)
+    }
+
+    // Delete the credential with its accounts and tokens. Revoking the grants first is up to the caller
+    // (`OAuthService::revoke_credential_accounts`).
+    pub async fn delete_credential(&self, id: i64) -> anyhow::Result<()> {
+        if !self.repo.delete_credential(id).await? {
+            return Err(AppError::not_found("Credential").into());
+        }
+        println!("Credential {} deleted.", id);
+        Ok(())
+    }
+
+    // Set the scopes requested for this credential. An empty list falls back to the provider's defaults.
+    pub async fn set_credential_scopes(&self, id: i64, scopes: Vec<String>) -> anyhow::Result<ServiceCredential> {
+        let scopes = normalize_scopes(scopes);
+        let scopes = if scopes.is_empty() { None } else { Some(scopes.join(" ")) };
+        let credential = self.repo.update_credential_scopes(id, scopes).await?;
+        Ok(credential.ok_or_else(|| AppError::not_found("Credential"))?)
+    }
+
+    // Set per-credential authorization parameters (access_type, prompt, include_granted_scopes, login_hint, ...).
+    // They override the provider's params; an empty value removes a provider param. An empty map clears overrides.
+    pub async fn set_credential_auth_params(&self, id: i64, auth_params: BTreeMap<String, String>) -> anyhow::Result<ServiceCredential> {
+        validate_auth_params(&auth_params)?;
+        let auth_params = if auth_params.is_empty() { None } else { Some(serde_json::to_string(&auth_params)?) };
+        let credential = self.repo.update_credential_auth_params(id, auth_params).await?;
+        Ok(credential.ok_or_else(|| AppError::not_found("Credential"))?)
+    }
+
     //--- Business logic methods ---
     pub async fn get_credential_names(&self) -> anyhow::Result<Vec<String>> {
         let creds = self.repo.get_all_credentials().await?;
@@ -32,16 +141,48 @@ impl CredentialService {
     }
 }
 
+// Problems with an imported client file are the user's to fix
+fn invalid_file(e: anyhow::Error) -> AppError {
+    AppError::invalid_input(format!("{:#}", e))
+}
+
+fn required(field: &str, value: &str) -> anyhow::Result<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(AppError::invalid_input(format!("{} cannot be empty", field)).into());
+    }
+    Ok(value.to_string())
+}
+
+// Trim, drop empties and de-duplicate while keeping the caller's order
+fn normalize_scopes(scopes: Vec<String>) -> Vec<String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for scope in scopes.iter().flat_map(|s| s.split_whitespace()) {
+        if !normalized.iter().any(|s| s == scope) {
+            normalized.push(scope.to_string());
+        }
+    }
+    normalized
+}
+
 // --- CredentialServiceのユニットテスト ---
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::models::{AddOAuthProviderPayload, OAuthProviderRow};
+    use crate::db::repositories::ProviderRepository;
+    use crate::error::ErrorCode;
     use async_trait::async_trait;
 
+    fn code<T: std::fmt::Debug>(result: anyhow::Result<T>) -> ErrorCode {
+        AppError::from(result.unwrap_err()).code
+    }
+
     // 1. テスト用のモックリポジトリを定義
     #[derive(Default)]
     struct MockCredentialRepository {
         credentials: Vec<ServiceCredential>,
+        updated_scopes: std::sync::Mutex<Option<Option<String>>>,
     }
 
     #[async_trait]
@@ -56,6 +197,9 @@ mod tests {
                 service_name: payload.service_name,
                 client_id: payload.client_id,
                 client_secret: payload.client_secret,
+                provider: payload.provider,
+                scopes: payload.scopes.map(|s| s.join(" ")),
+                auth_params: payload.auth_params.map(|p| serde_json::to_string(&p).unwrap()),
             };
             // In a real mock, you might want to actually add to the vec
             // to test interactions between add and get.
@@ -65,21 +209,221 @@ mod tests {
         async fn get_credential_by_id(&self, id: i64) -> anyhow::Result<Option<ServiceCredential>> {
             Ok(self.credentials.iter().find(|c| c.id == id).cloned())
         }
+
+        async fn update_credential_scopes(&self, id: i64, scopes: Option<String>) -> anyhow::Result<Option<ServiceCredential>> {
+            *self.updated_scopes.lock().unwrap() = Some(scopes.clone());
+            Ok(self.credentials.iter().find(|c| c.id == id).cloned().map(|c| ServiceCredential { scopes, ..c }))
+        }
+
+        async fn update_credential_auth_params(&self, id: i64, auth_params: Option<String>) -> anyhow::Result<Option<ServiceCredential>> {
+            Ok(self.credentials.iter().find(|c| c.id == id).cloned().map(|c| ServiceCredential { auth_params, ..c }))
+        }
+
+        async fn update_credential(&self, id: i64, payload: UpdateCredentialPayload) -> anyhow::Result<Option<ServiceCredential>> {
+            Ok(self.credentials.iter().find(|c| c.id == id).cloned().map(|c| ServiceCredential {
+                service_name: payload.service_name.unwrap_or(c.service_name),
+                client_id: payload.client_id.unwrap_or(c.client_id),
+                ..c
+            }))
+        }
+
+        async fn update_client_secret(&self, id: i64, client_secret: &str) -> anyhow::Result<Option<ServiceCredential>> {
+            Ok(self
+                .credentials
+                .iter()
+                .find(|c| c.id == id)
+                .cloned()
+                .map(|c| ServiceCredential { client_secret: client_secret.to_string(), ..c }))
+        }
+
+        async fn delete_credential(&self, id: i64) -> anyhow::Result<bool> {
+            Ok(self.credentials.iter().any(|c| c.id == id))
+        }
+    }
+
+    // カスタムプロバイダを持たないモック
+    struct EmptyProviderRepository;
+
+    #[async_trait]
+    impl ProviderRepository for EmptyProviderRepository {
+        async fn get_all_providers(&self) -> anyhow::Result<Vec<OAuthProviderRow>> {
+            Ok(vec![])
+        }
+
+        async fn add_provider(&self, _payload: AddOAuthProviderPayload) -> anyhow::Result<OAuthProviderRow> {
+            anyhow::bail!("not supported in mock")
+        }
+
+        async fn get_provider_by_key(&self, _provider_key: &str) -> anyhow::Result<Option<OAuthProviderRow>> {
+            Ok(None)
+        }
+    }
+
+    fn mock_service() -> CredentialService {
+        let mock_repo = Arc::new(MockCredentialRepository::default());
+        CredentialService::new(mock_repo, ProviderRegistry::new(Arc::new(EmptyProviderRepository)))
+    }
+
+    fn mock_credential(id: i64) -> ServiceCredential {
+        ServiceCredential {
+            id,
+            service_name: "test".to_string(),
+            client_id: "test_id".to_string(),
+            client_secret: "test_secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
+        }
     }
 
     #[tokio::test]
     async fn test_add_credential_with_mock() {
-        let mock_repo = Arc::new(MockCredentialRepository::default());
-        let service = CredentialService::new(mock_repo.clone());
+        let service = mock_service();
 
         let payload = AddCredentialPayload {
             service_name: "test".to_string(),
             client_id: "test_id".to_string(),
             client_secret: "test_secret".to_string(),
+            provider: "google".to_string(),
+            scopes: Some(vec!["  a  b".to_string(), "a".to_string(), "".to_string()]),
+            auth_params: None,
         };
 
         let result = service.add_credential(payload).await;
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().service_name, "test");
+        let cred = result.unwrap();
+        assert_eq!(cred.service_name, "test");
+        assert_eq!(cred.scopes.as_deref(), Some("a b"));
+    }
+
+    #[tokio::test]
+    async fn test_set_credential_scopes() {
+        let mock_repo = Arc::new(MockCredentialRepository {
+            credentials: vec![mock_credential(1)],
+            ..Default::default()
+        });
+        let service = CredentialService::new(mock_repo.clone(), ProviderRegistry::new(Arc::new(EmptyProviderRepository)));
+
+        let cred = service
+            .set_credential_scopes(1, vec!["x".to_string(), "y x".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(cred.scopes.as_deref(), Some("x y"));
+
+        // Empty list resets to provider defaults
+        service.set_credential_scopes(1, vec![]).await.unwrap();
+        assert_eq!(*mock_repo.updated_scopes.lock().unwrap(), Some(None));
+
+        assert_eq!(code(service.set_credential_scopes(2, vec![]).await), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_set_credential_auth_params() {
+        let mock_repo = Arc::new(MockCredentialRepository {
+            credentials: vec![mock_credential(1)],
+            ..Default::default()
+        });
+        let service = CredentialService::new(mock_repo, ProviderRegistry::new(Arc::new(EmptyProviderRepository)));
+
+        let params: BTreeMap<String, String> = [("login_hint".to_string(), "me@example.com".to_string())].into_iter().collect();
+        let cred = service.set_credential_auth_params(1, params).await.unwrap();
+        assert_eq!(cred.auth_params.as_deref(), Some(r#"{"login_hint":"me@example.com"}"#));
+
+        let cleared = service.set_credential_auth_params(1, BTreeMap::new()).await.unwrap();
+        assert_eq!(cleared.auth_params, None);
+
+        // Flow-owned parameters cannot be overridden
+        let reserved: BTreeMap<String, String> = [("state".to_string(), "x".to_string())].into_iter().collect();
+        assert_eq!(code(service.set_credential_auth_params(1, reserved).await), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_reveal_client_secret() {
+        let mock_repo = Arc::new(MockCredentialRepository {
+            credentials: vec![mock_credential(1)],
+            ..Default::default()
+        });
+        let service = CredentialService::new(mock_repo, ProviderRegistry::new(Arc::new(EmptyProviderRepository)));
+
+        assert_eq!(service.reveal_client_secret(1).await.unwrap(), "test_secret");
+        assert_eq!(code(service.reveal_client_secret(2).await), ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn test_update_rotate_and_delete_credential() {
+        let mock_repo = Arc::new(MockCredentialRepository {
+            credentials: vec![mock_credential(1)],
+            ..Default::default()
+        });
+        let service = CredentialService::new(mock_repo, ProviderRegistry::new(Arc::new(EmptyProviderRepository)));
+
+        let update = UpdateCredentialPayload { service_name: Some(" renamed ".to_string()), client_id: None };
+        let updated = service.update_credential(1, update).await.unwrap();
+        assert_eq!((updated.service_name.as_str(), updated.client_id.as_str()), ("renamed", "test_id"));
+        let blank = UpdateCredentialPayload { service_name: None, client_id: Some("  ".to_string()) };
+        assert_eq!(service.update_credential(1, blank).await.unwrap_err().to_string(), "client_id cannot be empty");
+        assert_eq!(code(service.update_credential(2, UpdateCredentialPayload::default()).await), ErrorCode::NotFound);
+
+        assert_eq!(service.rotate_client_secret(1, "new_secret\n".to_string()).await.unwrap().client_secret, "new_secret");
+        assert_eq!(code(service.rotate_client_secret(1, String::new()).await), ErrorCode::InvalidInput);
+        assert_eq!(code(service.rotate_client_secret(2, "new_secret".to_string()).await), ErrorCode::NotFound);
+
+        service.delete_credential(1).await.unwrap();
+        assert_eq!(service.delete_credential(2).await.unwrap_err().to_string(), "Credential not found");
+    }
+
+    #[tokio::test]
+    async fn test_add_credential_rejects_unknown_provider() {
+        let service = mock_service();
+
+        let payload = AddCredentialPayload {
+            service_name: "test".to_string(),
+            client_id: "test_id".to_string(),
+            client_secret: "test_secret".to_string(),
+            provider: "unknown".to_string(),
+            scopes: None,
+            auth_params: None,
+        };
+
+        assert_eq!(code(service.add_credential(payload).await), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_import_client_secret() {
+        let mock_repo = Arc::new(MockCredentialRepository {
+            credentials: vec![mock_credential(1)],
+            ..Default::default()
+        });
+        let service = CredentialService::new(mock_repo, ProviderRegistry::new(Arc::new(EmptyProviderRepository)));
+        let ports = CallbackPorts::parse("1421-1422").unwrap();
+        let payload = |contents: &str, service_name: Option<&str>| ImportClientSecretPayload {
+            contents: contents.to_string(),
+            service_name: service_name.map(str::to_string),
+            scopes: None,
+        };
+        let contents = r#"{"installed":{"client_id":"123.apps.googleusercontent.com","project_id":"k3-live","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","client_secret":"GOCSPX-secret","redirect_uris":["http://localhost"]}}"#;
+
+        let imported = service.import_client_secret(payload(contents, None), &ports).await.unwrap();
+        assert_eq!(imported.credential.service_name, "k3-live");
+        assert_eq!(imported.credential.client_id, "123.apps.googleusercontent.com");
+        assert!(imported.credential.has_secret);
+        assert_eq!(imported.credential.secret_hint.as_deref(), Some("cret"));
+        assert_eq!(imported.credential.provider, "google");
+        assert_eq!(imported.redirect_ports, vec![1421, 1422]);
+
+        let named = service.import_client_secret(payload(contents, Some(" youtube ")), &ports).await.unwrap();
+        assert_eq!(named.credential.service_name, "youtube");
+
+        // The same client cannot be imported twice
+        let duplicate = contents.replace("123.apps.googleusercontent.com", "test_id");
+        let err = service.import_client_secret(payload(&duplicate, None), &ports).await.unwrap_err();
+        assert!(err.to_string().contains("already registered as 'test'"), "{}", err);
+        assert_eq!(AppError::from(err).code, ErrorCode::Conflict);
+
+        let foreign = contents.replace("https://oauth2.googleapis.com/token", "https://idp.example.com/token");
+        let err = service.import_client_secret(payload(&foreign, None), &ports).await.unwrap_err();
+        assert!(err.to_string().contains("not Google endpoints"), "{}", err);
+        assert_eq!(AppError::from(err).code, ErrorCode::InvalidInput);
+        assert_eq!(code(service.import_client_secret(payload("{", None), &ports).await), ErrorCode::InvalidInput);
     }
 }
@@ -0,0 +1,269 @@
+use crate::db::encryption::{DataKey, FieldCipher, KdfParams, KeyProvider, KeyVault, PassphraseKeyProvider, MIN_PASSPHRASE_LEN};
+use crate::db::models::WrappedKey;
+use crate::db::repositories::EncryptionKeyRepository;
+use crate::error::AppError;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// Passphrase used to unlock (or first initialize) the database at startup without asking, e.g. for development
+pub const PASSPHRASE_ENV: &str = "K3_DB_PASSPHRASE";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncryptionState {
+    // No data key yet: a passphrase has to be set before secrets can be stored or read
+    Uninitialized,
+    Locked,
+    Unlocked,
+}
+
+// Manages the data key that encrypts secret columns: creating it, unlocking it and re-wrapping it
+#[derive(Clone)]
+pub struct EncryptionService {
+    repo: Arc<dyn EncryptionKeyRepository + Send + Sync>,
+    vault: Arc<KeyVault>,
+    // KDF cost for newly wrapped keys
+    kdf: KdfParams,
+    // Serializes key changes so two callers cannot create or re-wrap the key at the same time
+    key_lock: Arc<Mutex<()>>,
+}
+
+impl EncryptionService {
+    pub fn new(repo: Arc<dyn EncryptionKeyRepository + Send + Sync>, vault: Arc<KeyVault>, kdf: KdfParams) -> Self {
+        Self { repo, vault, kdf, key_lock: Arc::new(Mutex::new(())) }
+    }
+
+    pub async fn state(&self) -> anyhow::Result<EncryptionState> {
+        if self.vault.is_unlocked() {
+            return Ok(EncryptionState::Unlocked);
+        }
+        Ok(match self.repo.get_wrapped_key().await? {
+            Some(_) => EncryptionState::Locked,
+            None => EncryptionState::Uninitialized,
+        })
+    }
+
+    // Create the data key protected by `provider`, encrypt the secrets stored so far and unlock
+    pub async fn initialize(&self, provider: Arc<dyn KeyProvider>) -> anyhow::Result<()> {
+        let _guard = self.key_lock.lock().await;
+        if self.repo.get_wrapped_key().await?.is_some() {
+            return Err(AppError::conflict("A database passphrase is already set").into());
+        }
+        let (key, wrapped) = wrap_key(provider, DataKey::generate()).await?;
+        // The key is saved before any value is encrypted with it, so a crash cannot leave unreadable rows
+        self.repo.insert_wrapped_key(&wrapped).await?;
+        self.open_vault(FieldCipher::new(key)).await
+    }
+
+    pub async fn unlock(&self, provider: Arc<dyn KeyProvider>) -> anyhow::Result<()> {
+        let _guard = self.key_lock.lock().await;
+        let wrapped = self.stored_key(provider.as_ref()).await?;
+        let key = unwrap_key(provider, wrapped).await?;
+        self.open_vault(FieldCipher::new(key)).await
+    }
+
+    // Re-wrap the data key for `new` once `current` has proven it can unwrap it. Stored values keep their encryption.
+    pub async fn rewrap(&self, current: Arc<dyn KeyProvider>, new: Arc<dyn KeyProvider>) -> anyhow::Result<()> {
+        let _guard = self.key_lock.lock().await;
+        let wrapped = self.stored_key(current.as_ref()).await?;
+        let key = unwrap_key(current, wrapped).await?;
+        let (_, rewrapped) = wrap_key(new, key).await?;
+        self.repo.update_wrapped_key(&rewrapped).await
+    }
+
+    pub async fn set_passphrase(&self, passphrase: &str) -> anyhow::Result<()> {
+        validate_passphrase(passphrase)?;
+        self.initialize(self.passphrase_provider(passphrase)).await
+    }
+
+    pub async fn unlock_with_passphrase(&self, passphrase: &str) -> anyhow::Result<()> {
+        self.unlock(self.passphrase_provider(passphrase)).await
+    }
+
+    pub async fn change_passphrase(&self, current: &str, new: &str) -> anyhow::Result<()> {
+        validate_passphrase(new)?;
+        self.rewrap(self.passphrase_provider(current), self.passphrase_provider(new)).await
+    }
+
+    // Unlock, or initialize on first run, with `K3_DB_PASSPHRASE` when it is set
+    pub async fn unlock_from_env(&self) -> anyhow::Result<EncryptionState> {
+        let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) else {
+            return self.state().await;
+        };
+        match self.state().await? {
+            EncryptionState::Uninitialized => self.set_passphrase(&passphrase).await?,
+            EncryptionState::Locked => self.unlock_with_passphrase(&passphrase).await?,
+            EncryptionState::Unlocked => {}
+        }
+        Ok(EncryptionState::Unlocked)
+    }
+
+    fn passphrase_provider(&self, passphrase: &str) -> Arc<dyn KeyProvider> {
+        Arc::new(PassphraseKeyProvider::with_params(passphrase, self.kdf))
+    }
+
+    async fn stored_key(&self, provider: &dyn KeyProvider) -> anyhow::Result<WrappedKey> {
+        let wrapped = self
+            .repo
+            .get_wrapped_key()
+            .await?
+            .ok_or_else(|| AppError::conflict("No database passphrase has been set"))?;
+        if wrapped.provider != provider.kind() {
+            return Err(AppError::conflict(format!("The data key is protected by the '{}' key provider", wrapped.provider)).into());
+        }
+        Ok(wrapped)
+    }
+
+    // Rows written before encryption existed are encrypted before the repository may read them
+    async fn open_vault(&self, cipher: FieldCipher) -> anyhow::Result<()> {
+        let migrated = self.repo.encrypt_plaintext_secrets(&cipher).await?;
+        if migrated > 0 {
+            println!("Encrypted secrets of {} existing rows", migrated);
+        }
+        self.vault.unlock(cipher);
+        Ok(())
+    }
+}
+
+fn validate_passphrase(passphrase: &str) -> anyhow::Result<()> {
+    if passphrase.chars().count() < MIN_PASSPHRASE_LEN {
+        return Err(AppError::invalid_input(format!("The passphrase must be at least {} characters long", MIN_PASSPHRASE_LEN)).into());
+    }
+    Ok(())
+}
+
+// Key derivation is deliberately slow, so it runs off the async workers
+async fn wrap_key(provider: Arc<dyn KeyProvider>, key: DataKey) -> anyhow::Result<(DataKey, WrappedKey)> {
+    tokio::task::spawn_blocking(move || provider.wrap(&key).map(|wrapped| (key, wrapped))).await?
+}
+
+async fn unwrap_key(provider: Arc<dyn KeyProvider>, wrapped: WrappedKey) -> anyhow::Result<DataKey> {
+    tokio::task::spawn_blocking(move || provider.unwrap(&wrapped)).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::encryption::{DatabaseLocked, TEST_KDF};
+    use crate::db::models::{AddCredentialPayload, AddTokenPayload};
+    use crate::db::repositories::{AccountRepository, CredentialRepository, SqliteRepository, TokenRepository};
+    use crate::db::setup::init_test_db;
+    use crate::error::ErrorCode;
+    use sqlx::SqlitePool;
+
+    fn code(result: anyhow::Result<()>) -> ErrorCode {
+        AppError::from(result.unwrap_err()).code
+    }
+
+    // A repository and service sharing one vault, like the app wires them
+    fn open(pool: &SqlitePool) -> (Arc<SqliteRepository>, EncryptionService) {
+        let vault = Arc::new(KeyVault::locked());
+        let repo = Arc::new(SqliteRepository::new(pool.clone(), vault.clone()));
+        (repo.clone(), EncryptionService::new(repo, vault, TEST_KDF))
+    }
+
+    fn credential() -> AddCredentialPayload {
+        AddCredentialPayload {
+            service_name: "youtube".to_string(),
+            client_id: "id".to_string(),
+            client_secret: "client-secret".to_string(),
+            provider: "google".to_string(),
+            scopes: None,
+            auth_params: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn passphrase_protects_the_stored_secrets() {
+        let pool = init_test_db().await.unwrap();
+        let (repo, service) = open(&pool);
+        assert_eq!(service.state().await.unwrap(), EncryptionState::Uninitialized);
+        let err = repo.add_credential(credential()).await.unwrap_err();
+        assert!(err.is::<DatabaseLocked>());
+
+        assert_eq!(code(service.set_passphrase("short").await), ErrorCode::InvalidInput);
+        service.set_passphrase("correct horse").await.unwrap();
+        assert_eq!(service.state().await.unwrap(), EncryptionState::Unlocked);
+        let cred = repo.add_credential(credential()).await.unwrap();
+        assert_eq!(cred.client_secret, "client-secret");
+        assert_eq!(code(service.set_passphrase("another passphrase").await), ErrorCode::Conflict);
+
+        // After a restart the key has to be unlocked again
+        let (repo, service) = open(&pool);
+        assert_eq!(service.state().await.unwrap(), EncryptionState::Locked);
+        assert_eq!(AppError::from(repo.get_credential_by_id(cred.id).await.unwrap_err()).code, ErrorCode::DatabaseLocked);
+        let err = service.unlock_with_passphrase("wrong horse").await.unwrap_err();
+        assert_eq!(err.to_string(), "Incorrect passphrase");
+        assert_eq!(AppError::from(err).code, ErrorCode::IncorrectPassphrase);
+        assert_eq!(service.state().await.unwrap(), EncryptionState::Locked);
+
+        service.unlock_with_passphrase("correct horse").await.unwrap();
+        assert_eq!(repo.get_credential_by_id(cred.id).await.unwrap().unwrap().client_secret, "client-secret");
+    }
+
+    #[tokio::test]
+    async fn changing_the_passphrase_rewraps_the_key_only() {
+        let pool = init_test_db().await.unwrap();
+        let (repo, service) = open(&pool);
+        service.set_passphrase("correct horse").await.unwrap();
+        let cred = repo.add_credential(credential()).await.unwrap();
+        let (stored,): (String,) = sqlx::query_as("SELECT client_secret FROM service_credentials").fetch_one(&pool).await.unwrap();
+
+        assert_eq!(code(service.change_passphrase("wrong horse", "battery staple").await), ErrorCode::IncorrectPassphrase);
+        assert_eq!(code(service.change_passphrase("correct horse", "short").await), ErrorCode::InvalidInput);
+        service.change_passphrase("correct horse", "battery staple").await.unwrap();
+        let (after,): (String,) = sqlx::query_as("SELECT client_secret FROM service_credentials").fetch_one(&pool).await.unwrap();
+        assert_eq!(after, stored);
+
+        let (repo, service) = open(&pool);
+        assert!(service.unlock_with_passphrase("correct horse").await.is_err());
+        service.unlock_with_passphrase("battery staple").await.unwrap();
+        assert_eq!(repo.get_credential_by_id(cred.id).await.unwrap().unwrap().client_secret, "client-secret");
+    }
+
+    #[tokio::test]
+    async fn plaintext_rows_are_encrypted_when_the_key_is_created() {
+        let pool = init_test_db().await.unwrap();
+        // Rows written by a version without encryption
+        sqlx::query("INSERT INTO service_credentials (service_name, client_id, client_secret, provider) VALUES ('yt', 'id', 'legacy-secret', 'google')")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO oauth_accounts (credentials_id) VALUES (1)").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO oauth_tokens (account_id, access_token, refresh_token, expires_at) VALUES (1, 'legacy-access', 'legacy-refresh', 'never')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let (repo, service) = open(&pool);
+        service.set_passphrase("correct horse").await.unwrap();
+
+        let (secret, access, refresh): (String, String, String) = sqlx::query_as(
+            "SELECT c.client_secret, t.access_token, t.refresh_token FROM service_credentials c, oauth_tokens t",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        for stored in [&secret, &access, &refresh] {
+            assert!(stored.starts_with("enc:v1:") && !stored.contains("legacy"), "{}", stored);
+        }
+        assert_eq!(repo.get_credential_by_id(1).await.unwrap().unwrap().client_secret, "legacy-secret");
+        let token = repo.get_token_by_account_id(1).await.unwrap().unwrap();
+        assert_eq!((token.access_token.as_str(), token.refresh_token.as_str()), ("legacy-access", "legacy-refresh"));
+
+        // New writes are encrypted too, and a second pass has nothing left to do
+        let account = repo.add_account(1, None).await.unwrap();
+        repo.upsert_token(AddTokenPayload {
+            account_id: account.id,
+            access_token: "a".to_string(),
+            refresh_token: "r".to_string(),
+            expires_at: "never".to_string(),
+            scope: None,
+        })
+        .await
+        .unwrap();
+        let cipher = FieldCipher::new(DataKey::generate());
+        assert_eq!(repo.encrypt_plaintext_secrets(&cipher).await.unwrap(), 0);
+    }
+}
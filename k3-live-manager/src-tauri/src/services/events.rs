@@ -0,0 +1,19 @@
+use serde::Serialize;
+
+// Event names emitted to the frontend
+pub const OAUTH_FLOW_EVENT: &str = "oauth-flow";
+pub const TOKEN_REFRESH_EVENT: &str = "token-refresh";
+pub const APP_STATUS_EVENT: &str = "app-status";
+
+// Destination for backend → frontend events. The app uses the Tauri `AppHandle`;
+// tests record the events instead.
+pub trait EventSink: Send + Sync {
+    fn emit_json(&self, event: &str, payload: serde_json::Value);
+}
+
+pub fn emit<T: Serialize>(sink: &dyn EventSink, event: &str, payload: &T) {
+    match serde_json::to_value(payload) {
+        Ok(value) => sink.emit_json(event, value),
+        Err(e) => eprintln!("Failed to serialize '{}' event: {}", event, e),
+    }
+}
@@ -1,2 +1,10 @@
 pub mod credential_service;
+pub mod client_secret;
+pub mod encryption_service;
 pub mod oauth_service;
+pub mod oauth_provider;
+pub mod oauth_flow;
+pub mod token_refresher;
+pub mod events;
+pub mod startup;
+pub mod backup_service;
@@ -0,0 +1,653 @@
+use crate::db::models::AccountIdentity;
+use crate::error::AppError;
+use crate::oauth_server::{self, CallbackPorts, OAuthCallback};
+use crate::services::events::{self, EventSink, OAUTH_FLOW_EVENT};
+use crate::services::oauth_service::{
+    AuthorizationRequest, DeviceAuthorization, DeviceFlowError, LinkTarget, LinkWarning, LinkedToken, MissingRefreshToken, OAuthService,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio_util::sync::CancellationToken;
+
+// Default time a flow waits for the browser callback before the listener is shut down
+pub const DEFAULT_FLOW_TIMEOUT_SECS: u64 = 300;
+
+// Finished flows are kept this long so the UI can still query their outcome
+const FINISHED_FLOW_RETENTION_SECS: i64 = 600;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowStatus {
+    Waiting,
+    Exchanging,
+    Succeeded,
+    Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl FlowStatus {
+    pub fn is_terminal(self) -> bool {
+        !matches!(self, FlowStatus::Waiting | FlowStatus::Exchanging)
+    }
+}
+
+// Machine-readable reason for a failed flow
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FlowErrorCode {
+    StateMismatch,
+    // The user declined consent (`error=access_denied`)
+    AccessDenied,
+    // Any other error redirect from the provider (invalid_scope, server_error, ...)
+    ProviderError,
+    ExchangeFailed,
+    MissingRefreshToken,
+    CallbackServerStopped,
+}
+
+// Public view of a pending or finished flow. Also the payload of every `oauth-flow` event.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct OAuthFlowSnapshot {
+    pub flow_id: String,
+    pub credential_id: i64,
+    // Account being re-authorized; on success, the account the token was stored under
+    pub account_id: Option<i64>,
+    pub status: FlowStatus,
+    pub error_code: Option<FlowErrorCode>,
+    pub error: Option<String>,
+    // Filled on success
+    pub granted_scopes: Vec<String>,
+    pub missing_scopes: Vec<String>,
+    pub account: Option<AccountIdentity>,
+    // Identity mismatch on re-authorization; the UI should warn
+    pub warning: Option<LinkWarning>,
+    pub started_at: String,
+    pub updated_at: String,
+}
+
+// Returned when a browser flow starts; the UI opens `auth_url` and follows `flow_id`
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthFlowStarted {
+    pub flow_id: String,
+    pub auth_url: String,
+}
+
+// Returned when a device flow starts; the UI shows `user_code` and `verification_uri`
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceFlowStarted {
+    pub flow_id: String,
+    pub user_code: String,
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+}
+
+// Handle given to the code driving a flow; `cancel` fires on cancel/timeout/finish
+#[derive(Debug, Clone)]
+pub struct FlowHandle {
+    pub flow_id: String,
+    pub cancel: CancellationToken,
+}
+
+struct FlowEntry {
+    snapshot: OAuthFlowSnapshot,
+    cancel: CancellationToken,
+    finished_at: Option<chrono::DateTime<Utc>>,
+}
+
+fn now_string() -> String {
+    Utc::now().format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+// Registry of OAuth flows keyed by flow id. Every state change is emitted as an `oauth-flow` event.
+#[derive(Clone)]
+pub struct OAuthFlowRegistry {
+    flows: Arc<Mutex<HashMap<String, FlowEntry>>>,
+    events: Arc<dyn EventSink>,
+}
+
+impl OAuthFlowRegistry {
+    pub fn new(events: Arc<dyn EventSink>) -> Self {
+        Self { flows: Arc::new(Mutex::new(HashMap::new())), events }
+    }
+
+    // Register a new flow in `Waiting` and arm its timeout
+    pub fn start(&self, target: LinkTarget, timeout: Duration) -> FlowHandle {
+        let flow_id = oauth2::CsrfToken::new_random().secret().to_string();
+        let cancel = CancellationToken::new();
+        let now = now_string();
+        let snapshot = OAuthFlowSnapshot {
+            flow_id: flow_id.clone(),
+            credential_id: target.credential_id,
+            account_id: target.account_id,
+            status: FlowStatus::Waiting,
+            error_code: None,
+            error: None,
+            granted_scopes: Vec::new(),
+            missing_scopes: Vec::new(),
+            account: None,
+            warning: None,
+            started_at: now.clone(),
+            updated_at: now,
+        };
+        {
+            let mut flows = self.flows.lock().unwrap();
+            prune_finished(&mut flows);
+            flows.insert(
+                flow_id.clone(),
+                FlowEntry {
+                    snapshot: snapshot.clone(),
+                    cancel: cancel.clone(),
+                    finished_at: None,
+                },
+            );
+        }
+        events::emit(self.events.as_ref(), OAUTH_FLOW_EVENT, &snapshot);
+
+        // Time out only while still waiting for the callback
+        let registry = self.clone();
+        let watchdog_id = flow_id.clone();
+        let watchdog_cancel = cancel.clone();
+        tokio::spawn(async move {
+            tokio::select! {
+                _ = tokio::time::sleep(timeout) => {
+                    registry.transition(&watchdog_id, &[FlowStatus::Waiting], FlowStatus::TimedOut, |_| {});
+                }
+                _ = watchdog_cancel.cancelled() => {}
+            }
+        });
+
+        FlowHandle { flow_id, cancel }
+    }
+
+    pub fn get(&self, flow_id: &str) -> Option<OAuthFlowSnapshot> {
+        self.flows.lock().unwrap().get(flow_id).map(|e| e.snapshot.clone())
+    }
+
+    // Callback received: move to `Exchanging`. Returns false if the flow already ended.
+    pub fn begin_exchange(&self, flow_id: &str) -> bool {
+        self.transition(flow_id, &[FlowStatus::Waiting], FlowStatus::Exchanging, |_| {})
+    }
+
+    pub fn succeed(&self, flow_id: &str, linked: &LinkedToken) -> bool {
+        self.transition(flow_id, &[FlowStatus::Exchanging], FlowStatus::Succeeded, |s| {
+            s.account_id = Some(linked.account_id);
+            s.granted_scopes = linked.scopes.granted.clone();
+            s.missing_scopes = linked.scopes.missing.clone();
+            s.account = linked.account.clone();
+            s.warning = linked.warning.clone();
+        })
+    }
+
+    pub fn fail(&self, flow_id: &str, code: FlowErrorCode, error: impl Into<String>) -> bool {
+        let error = error.into();
+        self.transition(
+            flow_id,
+            &[FlowStatus::Waiting, FlowStatus::Exchanging],
+            FlowStatus::Failed,
+            |s| {
+                s.error_code = Some(code);
+                s.error = Some(error);
+            },
+        )
+    }
+
+    // Start a browser flow: bind a callback port, build the auth URL and drive the flow in the background.
+    // The CSRF state and PKCE verifier stay with the background task; the outcome is emitted as `oauth-flow` events.
+    pub async fn start_authorization(
+        &self,
+        oauth_service: OAuthService,
+        callback_ports: &CallbackPorts,
+        target: LinkTarget,
+        timeout: Duration,
+    ) -> anyhow::Result<OAuthFlowStarted> {
+        let (tx, rx) = oneshot::channel();
+
+        // Bind the first free callback port before registering the flow so exhaustion is reported to the UI
+        let (listener, redirect_url) = oauth_server::bind_callback_listener(callback_ports)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to start OAuth callback server: {}", e))?;
+
+        // Generate the auth URL with state, PKCE challenge and the chosen redirect URL.
+        // The same redirect URL is carried through to the code exchange.
+        let request = oauth_service.generate_auth_url(target.credential_id, &redirect_url).await?;
+
+        // Register the pending flow; its token stops the listener on cancel/timeout/finish
+        let flow = self.start(target, timeout);
+        let started = OAuthFlowStarted { flow_id: flow.flow_id.clone(), auth_url: request.auth_url.clone() };
+
+        let server_cancel = flow.cancel.clone();
+        let expected_state = request.csrf_state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = oauth_server::serve_oauth_callback(listener, expected_state, tx, server_cancel).await {
+                eprintln!("OAuth server error: {:?}", e);
+            }
+        });
+        let flows = self.clone();
+        tokio::spawn(async move {
+            flows.complete_authorization(flow, oauth_service, target, request, redirect_url, rx).await;
+        });
+
+        Ok(started)
+    }
+
+    // Start an RFC 8628 device flow and poll for its outcome in the background.
+    // The flow times out together with the device code.
+    pub async fn start_device_authorization(&self, oauth_service: OAuthService, target: LinkTarget) -> anyhow::Result<DeviceFlowStarted> {
+        let device = oauth_service.request_device_code(target.credential_id).await?;
+
+        let flow = self.start(target, Duration::from_secs(device.expires_in));
+        let started = DeviceFlowStarted {
+            flow_id: flow.flow_id.clone(),
+            user_code: device.user_code.clone(),
+            verification_uri: device.verification_uri.clone(),
+            verification_uri_complete: device.verification_uri_complete.clone(),
+            expires_in: device.expires_in,
+        };
+
+        let flows = self.clone();
+        tokio::spawn(async move {
+            flows.complete_device_authorization(flow, oauth_service, target, device).await;
+        });
+
+        Ok(started)
+    }
+
+    // Drive a started flow: wait for the callback, verify state, exchange the code and record the outcome.
+    // Returns early when the flow is cancelled or times out.
+    pub async fn complete_authorization(
+        &self,
+        flow: FlowHandle,
+        oauth_service: OAuthService,
+        target: LinkTarget,
+        request: AuthorizationRequest,
+        redirect_url: String,
+        rx: oneshot::Receiver<OAuthCallback>,
+    ) {
+        let received = tokio::select! {
+            received = rx => received,
+            _ = flow.cancel.cancelled() => return,
+        };
+        let callback = match received {
+            Ok(callback) => callback,
+            Err(e) => {
+                eprintln!("Failed to receive OAuth code: {}", e);
+                self.fail(&flow.flow_id, FlowErrorCode::CallbackServerStopped, "OAuth callback server stopped without a callback");
+                return;
+            }
+        };
+
+        // The callback server already drops other states; checked again here as the receiver may be fed directly
+        if callback.state() != Some(request.csrf_state.as_str()) {
+            eprintln!("State mismatch in OAuth callback. Potential CSRF.");
+            self.fail(&flow.flow_id, FlowErrorCode::StateMismatch, "State mismatch in OAuth callback");
+            return;
+        }
+        let code = match callback {
+            OAuthCallback::Code { code, .. } => code,
+            OAuthCallback::Error { error, description, .. } => {
+                eprintln!("OAuth provider returned error: {}", error);
+                let code = if error == "access_denied" {
+                    FlowErrorCode::AccessDenied
+                } else {
+                    FlowErrorCode::ProviderError
+                };
+                let message = match description {
+                    Some(description) => format!("{}: {}", error, description),
+                    None => error,
+                };
+                self.fail(&flow.flow_id, code, message);
+                return;
+            }
+        };
+        if !self.begin_exchange(&flow.flow_id) {
+            return;
+        }
+
+        // Finalize OAuth with the received code
+        let saved = oauth_service
+            .exchange_code_and_save_token(code, request.pkce_verifier, target, &redirect_url)
+            .await;
+        self.finish_exchange(&flow.flow_id, saved);
+    }
+
+    // Drive a started device flow (RFC 8628): poll until the user approves on another device, then save the token.
+    // Returns early when the flow is cancelled or times out.
+    pub async fn complete_device_authorization(
+        &self,
+        flow: FlowHandle,
+        oauth_service: OAuthService,
+        target: LinkTarget,
+        device: DeviceAuthorization,
+    ) {
+        let polled = tokio::select! {
+            polled = oauth_service.poll_device_token(target.credential_id, &device) => polled,
+            _ = flow.cancel.cancelled() => return,
+        };
+        let grant = match polled {
+            Ok(grant) => grant,
+            Err(e) => {
+                eprintln!("Device authorization failed: {}", e);
+                match e.downcast_ref::<DeviceFlowError>().map(|d| d.error.as_str()) {
+                    Some("expired_token") => {
+                        self.transition(&flow.flow_id, &[FlowStatus::Waiting], FlowStatus::TimedOut, |_| {});
+                    }
+                    Some("access_denied") => {
+                        self.fail(&flow.flow_id, FlowErrorCode::AccessDenied, e.to_string());
+                    }
+                    Some(_) => {
+                        self.fail(&flow.flow_id, FlowErrorCode::ProviderError, e.to_string());
+                    }
+                    None => {
+                        self.fail(&flow.flow_id, FlowErrorCode::ExchangeFailed, e.to_string());
+                    }
+                }
+                return;
+            }
+        };
+        if !self.begin_exchange(&flow.flow_id) {
+            return;
+        }
+        let saved = oauth_service.save_token_grant(target, grant).await;
+        self.finish_exchange(&flow.flow_id, saved);
+    }
+
+    // Record the outcome of saving the token for an `Exchanging` flow
+    fn finish_exchange(&self, flow_id: &str, saved: anyhow::Result<LinkedToken>) {
+        match saved {
+            Ok(linked) => {
+                self.succeed(flow_id, &linked);
+            }
+            Err(e) => {
+                eprintln!("Failed to exchange OAuth code: {}", e);
+                let code = if e.downcast_ref::<MissingRefreshToken>().is_some() {
+                    FlowErrorCode::MissingRefreshToken
+                } else {
+                    FlowErrorCode::ExchangeFailed
+                };
+                self.fail(flow_id, code, e.to_string());
+            }
+        }
+    }
+
+    // Cancel a flow that is still waiting for its callback; shuts down the listener
+    pub fn cancel(&self, flow_id: &str) -> anyhow::Result<OAuthFlowSnapshot> {
+        let snapshot = self.get(flow_id).ok_or_else(|| AppError::not_found("OAuth flow"))?;
+        match snapshot.status {
+            FlowStatus::Waiting => {
+                self.transition(flow_id, &[FlowStatus::Waiting], FlowStatus::Cancelled, |_| {});
+            }
+            FlowStatus::Exchanging => return Err(AppError::conflict("OAuth flow is already exchanging the code").into()),
+            _ => {}
+        }
+        Ok(self.get(flow_id).ok_or_else(|| AppError::not_found("OAuth flow"))?)
+    }
+
+    // Move `flow_id` from one of `from` to `to` and emit the new snapshot.
+    // Terminal states fire the cancellation token, which stops the callback listener.
+    fn transition(
+        &self,
+        flow_id: &str,
+        from: &[FlowStatus],
+        to: FlowStatus,
+        update: impl FnOnce(&mut OAuthFlowSnapshot),
+    ) -> bool {
+        let snapshot = {
+            let mut flows = self.flows.lock().unwrap();
+            let Some(entry) = flows.get_mut(flow_id) else {
+                return false;
+            };
+            if !from.contains(&entry.snapshot.status) {
+                return false;
+            }
+            entry.snapshot.status = to;
+            entry.snapshot.updated_at = now_string();
+            update(&mut entry.snapshot);
+            if to.is_terminal() {
+                entry.finished_at = Some(Utc::now());
+                entry.cancel.cancel();
+            }
+            entry.snapshot.clone()
+        };
+        events::emit(self.events.as_ref(), OAUTH_FLOW_EVENT, &snapshot);
+        true
+    }
+}
+
+fn prune_finished(flows: &mut HashMap<String, FlowEntry>) {
+    let cutoff = Utc::now() - chrono::Duration::seconds(FINISHED_FLOW_RETENTION_SECS);
+    flows.retain(|_, e| e.finished_at.is_none_or(|t| t > cutoff));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repositories::SqliteRepository;
+    use crate::db::setup::{init_test_db, test_vault};
+    use crate::services::oauth_provider::ProviderRegistry;
+    use crate::services::oauth_service::ScopeCheck;
+    use crate::test_support::{error_json, mock_provider_credential, spawn_mock_endpoint, token_json, RecordingEventSink};
+    use hyper::StatusCode;
+
+    const LONG: Duration = Duration::from_secs(60);
+    const REDIRECT: &str = "http://localhost:1421/oauth/callback";
+    const TARGET: LinkTarget = LinkTarget { credential_id: 1, account_id: None };
+
+    fn registry() -> (OAuthFlowRegistry, Arc<RecordingEventSink>) {
+        let sink = Arc::new(RecordingEventSink::default());
+        (OAuthFlowRegistry::new(sink.clone()), sink)
+    }
+
+    fn emitted(sink: &RecordingEventSink) -> Vec<OAuthFlowSnapshot> {
+        sink.payloads(OAUTH_FLOW_EVENT)
+            .into_iter()
+            .map(|p| serde_json::from_value(p).unwrap())
+            .collect()
+    }
+
+    fn statuses(sink: &RecordingEventSink) -> Vec<FlowStatus> {
+        emitted(sink).into_iter().map(|s| s.status).collect()
+    }
+
+    // OAuth service backed by a custom provider whose token endpoint returns `token_body`.
+    // `/device` hands out a device code that may be polled immediately.
+    async fn mock_oauth_service(token_body: String) -> (OAuthService, i64) {
+        let base_url = spawn_mock_endpoint(move |path, _form| match path {
+            "/device" => {
+                let body = serde_json::json!({
+                    "device_code": "dev-code",
+                    "user_code": "ABCD-EFGH",
+                    "verification_uri": "https://idp.example.com/activate",
+                    "expires_in": 60,
+                    "interval": 0,
+                });
+                (StatusCode::OK, body.to_string())
+            }
+            _ if token_body.contains("\"error\"") => (StatusCode::BAD_REQUEST, token_body.clone()),
+            _ => (StatusCode::OK, token_body.clone()),
+        }).await;
+        let repo = Arc::new(SqliteRepository::new(init_test_db().await.unwrap(), test_vault()));
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read", "mock.write"]).await;
+        let service = OAuthService::new(repo.clone(), repo.clone(), repo.clone(), ProviderRegistry::new(repo));
+        (service, cred_id)
+    }
+
+    // Start a flow and drive it with the callback built from the flow's real state
+    async fn run_flow(token_body: String, callback: impl FnOnce(String) -> OAuthCallback) -> Arc<RecordingEventSink> {
+        let (service, cred_id) = mock_oauth_service(token_body).await;
+        let (registry, sink) = registry();
+        let request = service.generate_auth_url(cred_id, REDIRECT).await.unwrap();
+        let target = LinkTarget { credential_id: cred_id, account_id: None };
+        let flow = registry.start(target, LONG);
+        let (tx, rx) = oneshot::channel();
+        tx.send(callback(request.csrf_state.clone())).unwrap();
+        registry
+            .complete_authorization(flow, service, target, request, REDIRECT.to_string(), rx)
+            .await;
+        sink
+    }
+
+    fn code_callback(state: String) -> OAuthCallback {
+        OAuthCallback::Code { code: "code".to_string(), state }
+    }
+
+    fn error_callback(error: &str, state: Option<String>) -> OAuthCallback {
+        OAuthCallback::Error { error: error.to_string(), description: Some("denied by user".to_string()), state }
+    }
+
+    #[tokio::test]
+    async fn successful_flow_transitions() {
+        let (registry, sink) = registry();
+        let handle = registry.start(TARGET, LONG);
+        assert_eq!(registry.get(&handle.flow_id).unwrap().status, FlowStatus::Waiting);
+
+        assert!(registry.begin_exchange(&handle.flow_id));
+        assert_eq!(registry.get(&handle.flow_id).unwrap().status, FlowStatus::Exchanging);
+        assert!(!handle.cancel.is_cancelled());
+
+        let linked = LinkedToken {
+            account_id: 7,
+            scopes: ScopeCheck::compare(vec!["a".into()], vec!["a".into()]),
+            account: None,
+            warning: Some(LinkWarning::OtherLinkedAccount { requested_account_id: 3 }),
+        };
+        assert!(registry.succeed(&handle.flow_id, &linked));
+        let snapshot = registry.get(&handle.flow_id).unwrap();
+        assert_eq!(snapshot.status, FlowStatus::Succeeded);
+        assert_eq!(snapshot.account_id, Some(7));
+        assert_eq!(snapshot.warning, linked.warning);
+        assert!(handle.cancel.is_cancelled());
+
+        // Terminal states are final and emit nothing further
+        assert!(!registry.fail(&handle.flow_id, FlowErrorCode::ExchangeFailed, "late"));
+        assert_eq!(registry.get(&handle.flow_id).unwrap().status, FlowStatus::Succeeded);
+        assert_eq!(
+            statuses(&sink),
+            vec![FlowStatus::Waiting, FlowStatus::Exchanging, FlowStatus::Succeeded]
+        );
+    }
+
+    #[tokio::test]
+    async fn cancel_fires_token_and_blocks_late_callback() {
+        let (registry, sink) = registry();
+        let handle = registry.start(TARGET, LONG);
+
+        let snapshot = registry.cancel(&handle.flow_id).unwrap();
+        assert_eq!(snapshot.status, FlowStatus::Cancelled);
+        assert!(handle.cancel.is_cancelled());
+        assert!(!registry.begin_exchange(&handle.flow_id));
+        assert_eq!(statuses(&sink), vec![FlowStatus::Waiting, FlowStatus::Cancelled]);
+
+        let unknown = AppError::from(registry.cancel("unknown").unwrap_err());
+        assert_eq!(unknown.code, crate::error::ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn cancel_is_rejected_while_exchanging() {
+        let (registry, _sink) = registry();
+        let handle = registry.start(TARGET, LONG);
+        registry.begin_exchange(&handle.flow_id);
+
+        let error = AppError::from(registry.cancel(&handle.flow_id).unwrap_err());
+        assert_eq!(error.code, crate::error::ErrorCode::Conflict);
+        assert!(!handle.cancel.is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn waiting_flow_times_out() {
+        let (registry, sink) = registry();
+        let handle = registry.start(TARGET, Duration::from_millis(20));
+
+        tokio::time::timeout(Duration::from_secs(5), handle.cancel.cancelled())
+            .await
+            .expect("flow should time out");
+        let snapshot = registry.get(&handle.flow_id).unwrap();
+        assert_eq!(snapshot.status, FlowStatus::TimedOut);
+        assert!(!registry.begin_exchange(&handle.flow_id));
+        assert_eq!(statuses(&sink), vec![FlowStatus::Waiting, FlowStatus::TimedOut]);
+    }
+
+    #[tokio::test]
+    async fn completed_authorization_emits_granted_scopes() {
+        let sink = run_flow(token_json("at", Some("rt"), Some("mock.read")), code_callback).await;
+
+        let events = emitted(&sink);
+        assert_eq!(
+            events.iter().map(|s| s.status).collect::<Vec<_>>(),
+            vec![FlowStatus::Waiting, FlowStatus::Exchanging, FlowStatus::Succeeded]
+        );
+        let last = events.last().unwrap();
+        assert_eq!(last.granted_scopes, vec!["mock.read".to_string()]);
+        assert_eq!(last.missing_scopes, vec!["mock.write".to_string()]);
+        assert!(last.error_code.is_none());
+    }
+
+    #[tokio::test]
+    async fn state_mismatch_emits_failure_code() {
+        let sink = run_flow(token_json("at", Some("rt"), None), |_| code_callback("forged".into())).await;
+
+        let events = emitted(&sink);
+        assert_eq!(
+            events.iter().map(|s| s.status).collect::<Vec<_>>(),
+            vec![FlowStatus::Waiting, FlowStatus::Failed]
+        );
+        assert_eq!(events.last().unwrap().error_code, Some(FlowErrorCode::StateMismatch));
+    }
+
+    #[tokio::test]
+    async fn missing_refresh_token_emits_failure_code() {
+        let sink = run_flow(token_json("at", None, None), code_callback).await;
+
+        let last = emitted(&sink).pop().unwrap();
+        assert_eq!(last.status, FlowStatus::Failed);
+        assert_eq!(last.error_code, Some(FlowErrorCode::MissingRefreshToken));
+        assert!(last.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn provider_error_redirects_fail_with_typed_codes() {
+        let sink = run_flow(token_json("at", Some("rt"), None), |state| error_callback("access_denied", Some(state))).await;
+        let last = emitted(&sink).pop().unwrap();
+        assert_eq!(last.status, FlowStatus::Failed);
+        assert_eq!(last.error_code, Some(FlowErrorCode::AccessDenied));
+        assert_eq!(last.error.as_deref(), Some("access_denied: denied by user"));
+
+        let sink = run_flow(token_json("at", Some("rt"), None), |state| error_callback("invalid_scope", Some(state))).await;
+        assert_eq!(emitted(&sink).pop().unwrap().error_code, Some(FlowErrorCode::ProviderError));
+
+        // An error redirect without our state is treated like a forged callback
+        let sink = run_flow(token_json("at", Some("rt"), None), |_| error_callback("access_denied", None)).await;
+        assert_eq!(emitted(&sink).pop().unwrap().error_code, Some(FlowErrorCode::StateMismatch));
+    }
+
+    async fn run_device_flow(token_body: String) -> Arc<RecordingEventSink> {
+        let (service, cred_id) = mock_oauth_service(token_body).await;
+        let (registry, sink) = registry();
+        let device = service.request_device_code(cred_id).await.unwrap();
+        let target = LinkTarget { credential_id: cred_id, account_id: None };
+        let flow = registry.start(target, LONG);
+        registry.complete_device_authorization(flow, service, target, device).await;
+        sink
+    }
+
+    #[tokio::test]
+    async fn device_flow_emits_the_same_event_sequence() {
+        let sink = run_device_flow(token_json("at", Some("rt"), Some("mock.read mock.write"))).await;
+        let events = emitted(&sink);
+        assert_eq!(
+            events.iter().map(|s| s.status).collect::<Vec<_>>(),
+            vec![FlowStatus::Waiting, FlowStatus::Exchanging, FlowStatus::Succeeded]
+        );
+        assert!(events.last().unwrap().missing_scopes.is_empty());
+
+        let sink = run_device_flow(error_json("access_denied", "user declined")).await;
+        let last = emitted(&sink).pop().unwrap();
+        assert_eq!(last.status, FlowStatus::Failed);
+        assert_eq!(last.error_code, Some(FlowErrorCode::AccessDenied));
+    }
+}
@@ -0,0 +1,377 @@
+use crate::db::models::{AddOAuthProviderPayload, OAuthProviderRow};
+use crate::db::repositories::ProviderRepository;
+use crate::error::AppError;
+use anyhow::Context;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use url::Url;
+
+pub const GOOGLE: &str = "google";
+pub const TWITCH: &str = "twitch";
+
+// Overrides provider endpoint base URLs, e.g. `google=http://127.0.0.1:9000,twitch=https://idp.test`
+pub const ENDPOINT_OVERRIDES_ENV: &str = "K3_OAUTH_ENDPOINT_OVERRIDES";
+
+// Parameters set by the flow itself; providers and credentials may not override them
+const RESERVED_AUTH_PARAMS: &[&str] = &[
+    "response_type",
+    "client_id",
+    "redirect_uri",
+    "scope",
+    "state",
+    "code_challenge",
+    "code_challenge_method",
+];
+
+fn validate_url(field: &str, value: &str) -> Result<(), AppError> {
+    Url::parse(value).map(|_| ()).map_err(|e| AppError::invalid_input(format!("Invalid {}: {}", field, e)))
+}
+
+pub fn validate_auth_params(params: &BTreeMap<String, String>) -> anyhow::Result<()> {
+    for name in params.keys() {
+        if name.trim().is_empty() {
+            return Err(AppError::invalid_input("Authorization parameter name must not be empty").into());
+        }
+        if RESERVED_AUTH_PARAMS.contains(&name.as_str()) {
+            return Err(AppError::invalid_input(format!("'{}' is set by the OAuth flow and cannot be overridden", name)).into());
+        }
+    }
+    Ok(())
+}
+
+// OAuth provider definition: endpoints, default scopes and extra authorization params
+#[derive(Debug, Clone, Serialize)]
+pub struct OAuthProvider {
+    pub key: String,
+    pub display_name: String,
+    pub builtin: bool,
+    pub auth_url: String,
+    pub token_url: String,
+    pub revoke_url: Option<String>,
+    pub default_scopes: Vec<String>,
+    pub extra_params: BTreeMap<String, String>,
+    // Send client_id/client_secret in the token request body instead of HTTP Basic auth
+    pub client_secret_in_body: bool,
+    // A flow that returns no refresh token is an error rather than a usable link
+    pub requires_refresh_token: bool,
+    // RFC 8628 device authorization endpoint, when the provider supports the device flow
+    pub device_authorization_url: Option<String>,
+    // OIDC userinfo endpoint used to record which account was linked
+    pub userinfo_url: Option<String>,
+}
+
+impl OAuthProvider {
+    pub fn google() -> Self {
+        Self {
+            key: GOOGLE.to_string(),
+            display_name: "Google (YouTube)".to_string(),
+            builtin: true,
+            auth_url: "https://accounts.google.com/o/oauth2/v2/auth".to_string(),
+            token_url: "https://oauth2.googleapis.com/token".to_string(),
+            revoke_url: Some("https://oauth2.googleapis.com/revoke".to_string()),
+            default_scopes: vec![
+                "https://www.googleapis.com/auth/youtube".to_string(),
+                "https://www.googleapis.com/auth/userinfo.profile".to_string(),
+                "https://www.googleapis.com/auth/userinfo.email".to_string(),
+            ],
+            // Without offline access + consent Google often omits the refresh token
+            extra_params: [
+                ("access_type", "offline"),
+                ("prompt", "consent"),
+                ("include_granted_scopes", "true"),
+            ]
+            .into_iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            client_secret_in_body: false,
+            requires_refresh_token: true,
+            device_authorization_url: Some("https://oauth2.googleapis.com/device/code".to_string()),
+            userinfo_url: Some("https://openidconnect.googleapis.com/v1/userinfo".to_string()),
+        }
+    }
+
+    pub fn twitch() -> Self {
+        Self {
+            key: TWITCH.to_string(),
+            display_name: "Twitch".to_string(),
+            builtin: true,
+            auth_url: "https://id.twitch.tv/oauth2/authorize".to_string(),
+            token_url: "https://id.twitch.tv/oauth2/token".to_string(),
+            revoke_url: Some("https://id.twitch.tv/oauth2/revoke".to_string()),
+            default_scopes: vec![
+                "user:read:email".to_string(),
+                "chat:read".to_string(),
+                "chat:edit".to_string(),
+            ],
+            extra_params: BTreeMap::new(),
+            // Twitch rejects HTTP Basic client authentication
+            client_secret_in_body: true,
+            requires_refresh_token: true,
+            device_authorization_url: Some("https://id.twitch.tv/oauth2/device".to_string()),
+            // Twitch only returns OIDC claims for the `openid` scope with a `claims` request
+            userinfo_url: None,
+        }
+    }
+
+    // Same provider with every endpoint moved to `base`: scheme/host/port are replaced and
+    // `base`'s path is prefixed, so `https://oauth2.googleapis.com/token` becomes `<base>/token`.
+    // Lets a local stand-in (tests, staging IdP) serve a built-in provider.
+    pub fn with_base_url(mut self, base: &Url) -> Self {
+        let rebase = |endpoint: &mut String| {
+            if let Ok(original) = Url::parse(endpoint) {
+                let mut rebased = base.clone();
+                rebased.set_path(&format!("{}{}", base.path().trim_end_matches('/'), original.path()));
+                rebased.set_query(original.query());
+                *endpoint = rebased.to_string();
+            }
+        };
+        rebase(&mut self.auth_url);
+        rebase(&mut self.token_url);
+        for endpoint in [&mut self.revoke_url, &mut self.device_authorization_url, &mut self.userinfo_url]
+            .into_iter()
+            .flatten()
+        {
+            rebase(endpoint);
+        }
+        self
+    }
+
+    pub fn builtins() -> Vec<Self> {
+        vec![Self::google(), Self::twitch()]
+    }
+
+    fn from_row(row: OAuthProviderRow) -> anyhow::Result<Self> {
+        let extra_params: BTreeMap<String, String> = serde_json::from_str(&row.extra_params)
+            .with_context(|| format!("Invalid extra_params for provider '{}'", row.provider_key))?;
+        Ok(Self {
+            key: row.provider_key,
+            display_name: row.display_name,
+            builtin: false,
+            auth_url: row.auth_url,
+            token_url: row.token_url,
+            revoke_url: row.revoke_url,
+            default_scopes: row.default_scopes.split_whitespace().map(str::to_string).collect(),
+            extra_params,
+            client_secret_in_body: row.client_secret_in_body,
+            requires_refresh_token: row.requires_refresh_token,
+            device_authorization_url: row.device_authorization_url,
+            userinfo_url: row.userinfo_url,
+        })
+    }
+}
+
+// Runtime replacement base URLs keyed by provider key
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EndpointOverrides(BTreeMap<String, Url>);
+
+impl EndpointOverrides {
+    // Parse a comma separated list of `<provider_key>=<base URL>` pairs
+    pub fn parse(spec: &str) -> anyhow::Result<Self> {
+        let mut overrides = BTreeMap::new();
+        for part in spec.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, base) = part
+                .split_once('=')
+                .with_context(|| format!("Expected <provider>=<base URL>, got '{}'", part))?;
+            let key = key.trim();
+            if key.is_empty() {
+                anyhow::bail!("Missing provider key in '{}'", part);
+            }
+            let base = Url::parse(base.trim()).with_context(|| format!("Invalid base URL for provider '{}'", key))?;
+            overrides.insert(key.to_string(), base);
+        }
+        Ok(Self(overrides))
+    }
+
+    // Overrides from `K3_OAUTH_ENDPOINT_OVERRIDES`; none when unset or invalid
+    pub fn from_env() -> Self {
+        match std::env::var(ENDPOINT_OVERRIDES_ENV) {
+            Ok(spec) => Self::parse(&spec).unwrap_or_else(|e| {
+                eprintln!("Ignoring {}: {:#}", ENDPOINT_OVERRIDES_ENV, e);
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn insert(&mut self, provider_key: &str, base: Url) {
+        self.0.insert(provider_key.to_string(), base);
+    }
+
+    fn apply(&self, provider: OAuthProvider) -> OAuthProvider {
+        match self.0.get(&provider.key) {
+            Some(base) => provider.with_base_url(base),
+            None => provider,
+        }
+    }
+}
+
+// Resolves provider keys to definitions: built-in providers first, then custom rows in the DB.
+// Endpoint overrides are applied to both.
+#[derive(Clone)]
+pub struct ProviderRegistry {
+    repo: Arc<dyn ProviderRepository + Send + Sync>,
+    overrides: Arc<EndpointOverrides>,
+}
+
+impl ProviderRegistry {
+    pub fn new(repo: Arc<dyn ProviderRepository + Send + Sync>) -> Self {
+        Self::with_overrides(repo, EndpointOverrides::default())
+    }
+
+    pub fn with_overrides(repo: Arc<dyn ProviderRepository + Send + Sync>, overrides: EndpointOverrides) -> Self {
+        for (key, base) in &overrides.0 {
+            println!("OAuth provider '{}' endpoints overridden with {}", key, base);
+        }
+        Self { repo, overrides: Arc::new(overrides) }
+    }
+
+    pub async fn get_all_providers(&self) -> anyhow::Result<Vec<OAuthProvider>> {
+        let mut providers = OAuthProvider::builtins();
+        for row in self.repo.get_all_providers().await? {
+            providers.push(OAuthProvider::from_row(row)?);
+        }
+        Ok(providers.into_iter().map(|p| self.overrides.apply(p)).collect())
+    }
+
+    pub async fn get_provider(&self, key: &str) -> anyhow::Result<Option<OAuthProvider>> {
+        if let Some(builtin) = OAuthProvider::builtins().into_iter().find(|p| p.key == key) {
+            return Ok(Some(self.overrides.apply(builtin)));
+        }
+        match self.repo.get_provider_by_key(key).await? {
+            Some(row) => Ok(Some(self.overrides.apply(OAuthProvider::from_row(row)?))),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn resolve(&self, key: &str) -> anyhow::Result<OAuthProvider> {
+        self.get_provider(key)
+            .await?
+            .ok_or_else(|| AppError::invalid_input(format!("Unknown OAuth provider '{}'", key)).into())
+    }
+
+    pub async fn add_custom_provider(&self, payload: AddOAuthProviderPayload) -> anyhow::Result<OAuthProvider> {
+        let key = payload.provider_key.trim();
+        if key.is_empty() {
+            return Err(AppError::invalid_input("provider_key is required").into());
+        }
+        if OAuthProvider::builtins().iter().any(|p| p.key == key) {
+            return Err(AppError::conflict(format!("'{}' is a built-in provider and cannot be redefined", key)).into());
+        }
+        validate_url("auth_url", &payload.auth_url)?;
+        validate_url("token_url", &payload.token_url)?;
+        if let Some(revoke_url) = &payload.revoke_url {
+            validate_url("revoke_url", revoke_url)?;
+        }
+        if let Some(device_authorization_url) = &payload.device_authorization_url {
+            validate_url("device_authorization_url", device_authorization_url)?;
+        }
+        if let Some(userinfo_url) = &payload.userinfo_url {
+            validate_url("userinfo_url", userinfo_url)?;
+        }
+        validate_auth_params(&payload.extra_params)?;
+
+        let payload = AddOAuthProviderPayload { provider_key: key.to_string(), ..payload };
+        let row = self.repo.add_provider(payload).await?;
+        Ok(self.overrides.apply(OAuthProvider::from_row(row)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repositories::SqliteRepository;
+    use crate::db::setup::{init_test_db, test_vault};
+    use crate::error::ErrorCode;
+
+    fn code<T: std::fmt::Debug>(result: anyhow::Result<T>) -> ErrorCode {
+        AppError::from(result.unwrap_err()).code
+    }
+
+    async fn setup_registry() -> ProviderRegistry {
+        let pool = init_test_db().await.unwrap();
+        ProviderRegistry::new(Arc::new(SqliteRepository::new(pool, test_vault())))
+    }
+
+    fn custom_payload(key: &str) -> AddOAuthProviderPayload {
+        AddOAuthProviderPayload {
+            provider_key: key.to_string(),
+            display_name: "Custom".to_string(),
+            auth_url: "https://idp.example.com/authorize".to_string(),
+            token_url: "https://idp.example.com/token".to_string(),
+            revoke_url: None,
+            default_scopes: vec!["openid".to_string()],
+            extra_params: BTreeMap::new(),
+            client_secret_in_body: false,
+            requires_refresh_token: true,
+            device_authorization_url: None,
+            userinfo_url: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_builtin_and_custom_providers() {
+        let registry = setup_registry().await;
+        registry.add_custom_provider(custom_payload("custom")).await.unwrap();
+
+        let google = registry.resolve(GOOGLE).await.unwrap();
+        assert_eq!(google.token_url, "https://oauth2.googleapis.com/token");
+        assert_eq!(google.extra_params.get("access_type").map(String::as_str), Some("offline"));
+        assert_eq!(google.extra_params.get("prompt").map(String::as_str), Some("consent"));
+        assert!(registry.resolve(TWITCH).await.unwrap().client_secret_in_body);
+        let custom = registry.resolve("custom").await.unwrap();
+        assert!(!custom.builtin);
+        assert_eq!(custom.default_scopes, vec!["openid".to_string()]);
+        assert_eq!(code(registry.resolve("unknown").await), ErrorCode::InvalidInput);
+
+        let keys: Vec<String> = registry.get_all_providers().await.unwrap().into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec![GOOGLE.to_string(), TWITCH.to_string(), "custom".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn rejects_builtin_key_and_invalid_urls() {
+        let registry = setup_registry().await;
+        assert_eq!(code(registry.add_custom_provider(custom_payload(GOOGLE)).await), ErrorCode::Conflict);
+        registry.add_custom_provider(custom_payload("custom")).await.unwrap();
+        let duplicate = AppError::from(registry.add_custom_provider(custom_payload("custom")).await.unwrap_err());
+        assert_eq!((duplicate.code, duplicate.message.as_str()), (ErrorCode::Conflict, "A provider with key 'custom' already exists"));
+
+        let mut payload = custom_payload("broken");
+        payload.token_url = "not a url".to_string();
+        assert_eq!(code(registry.add_custom_provider(payload).await), ErrorCode::InvalidInput);
+
+        let mut payload = custom_payload("reserved");
+        payload.extra_params.insert("redirect_uri".to_string(), "http://evil".to_string());
+        assert_eq!(code(registry.add_custom_provider(payload).await), ErrorCode::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn endpoint_overrides_rebase_provider_urls() {
+        let pool = init_test_db().await.unwrap();
+        let overrides = EndpointOverrides::parse("google=http://127.0.0.1:9000/mock-google, custom=http://127.0.0.1:9001").unwrap();
+        let registry = ProviderRegistry::with_overrides(Arc::new(SqliteRepository::new(pool, test_vault())), overrides);
+        registry.add_custom_provider(custom_payload("custom")).await.unwrap();
+
+        let google = registry.resolve(GOOGLE).await.unwrap();
+        assert_eq!(google.auth_url, "http://127.0.0.1:9000/mock-google/o/oauth2/v2/auth");
+        assert_eq!(google.token_url, "http://127.0.0.1:9000/mock-google/token");
+        assert_eq!(google.revoke_url.as_deref(), Some("http://127.0.0.1:9000/mock-google/revoke"));
+        assert_eq!(google.device_authorization_url.as_deref(), Some("http://127.0.0.1:9000/mock-google/device/code"));
+        assert_eq!(google.userinfo_url.as_deref(), Some("http://127.0.0.1:9000/mock-google/v1/userinfo"));
+        // Everything but the endpoints is kept
+        assert_eq!(google.default_scopes, OAuthProvider::google().default_scopes);
+
+        assert_eq!(registry.resolve("custom").await.unwrap().token_url, "http://127.0.0.1:9001/token");
+        assert_eq!(registry.resolve(TWITCH).await.unwrap().token_url, "https://id.twitch.tv/oauth2/token");
+        let all = registry.get_all_providers().await.unwrap();
+        assert_eq!(all[0].token_url, "http://127.0.0.1:9000/mock-google/token");
+    }
+
+    #[test]
+    fn endpoint_overrides_reject_malformed_entries() {
+        assert_eq!(EndpointOverrides::parse("").unwrap(), EndpointOverrides::default());
+        assert!(EndpointOverrides::parse("google").is_err());
+        assert!(EndpointOverrides::parse("=http://127.0.0.1:9000").is_err());
+        assert!(EndpointOverrides::parse("google=not a url").is_err());
+    }
+}
@@ -1,167 +1,889 @@
-use crate::db::repositories::{CredentialRepository, TokenRepository};
-use crate::db::models::{AddTokenPayload, ServiceCredential};
+use crate::db::repositories::{AccountRepository, CredentialRepository, TokenRepository};
+use crate::db::models::{AccountIdentity, AddTokenPayload, OauthAccount, OauthToken, ServiceCredential, TokenAttention, TokenStatus};
+use crate::error::{AppError, ErrorCode};
+use crate::services::oauth_provider::{OAuthProvider, ProviderRegistry};
 use anyhow::Context;
-use oauth2::{AuthUrl, ClientId, ClientSecret, RedirectUrl, TokenUrl, TokenResponse, RefreshToken};
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use oauth2::basic::{BasicErrorResponse, BasicErrorResponseType};
+use oauth2::{AuthType, RequestTokenError, AuthUrl, ClientId, ClientSecret, PkceCodeChallenge, PkceCodeVerifier, RedirectUrl, TokenUrl, TokenResponse, RefreshToken};
+use std::sync::{Arc, Mutex};
+use tokio::sync::watch;
 use chrono::{NaiveDateTime, Utc, Duration as ChronoDuration, TimeZone};
 
-// Google OAuth2 Client using oauth2 v4.4.0 API
-fn create_google_oauth_client(
+// Result of starting an authorization-code flow.
+// `csrf_state` and `pkce_verifier` must be kept with the pending flow until the callback arrives.
+#[derive(Debug, Clone)]
+pub struct AuthorizationRequest {
+    pub auth_url: String,
+    pub csrf_state: String,
+    pub pkce_verifier: String,
+}
+
+// Comparison between the scopes a caller needs and the scopes actually granted to the token
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ScopeCheck {
+    pub requested: Vec<String>,
+    pub granted: Vec<String>,
+    pub missing: Vec<String>,
+    // true when the token lacks a required scope and the user must consent again
+    pub needs_reconsent: bool,
+}
+
+impl ScopeCheck {
+    pub fn compare(requested: Vec<String>, granted: Vec<String>) -> Self {
+        let missing: Vec<String> = requested
+            .iter()
+            .filter(|r| !granted.contains(r))
+            .cloned()
+            .collect();
+        let needs_reconsent = !missing.is_empty();
+        Self { requested, granted, missing, needs_reconsent }
+    }
+}
+
+// Result of unlinking an account. The local account and token are removed even when the provider call fails.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct RevocationOutcome {
+    pub account_id: i64,
+    // The provider's RFC 7009 endpoint accepted the revocation
+    pub revoked_at_provider: bool,
+    pub local_token_removed: bool,
+    // Why the provider call failed or was skipped, in the same model as command errors
+    pub provider_error: Option<AppError>,
+    // Set when the account could not be unlinked at all; it is still stored
+    pub error: Option<AppError>,
+}
+
+// Where a code or device flow stores its token: a new account of the credential,
+// or an existing account being re-authorized (`account_id`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkTarget {
+    pub credential_id: i64,
+    pub account_id: Option<i64>,
+}
+
+// Outcome of linking an account through the code or device flow
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct LinkedToken {
+    // Account the token was stored under
+    pub account_id: i64,
+    pub scopes: ScopeCheck,
+    // None when the provider has no userinfo endpoint or the lookup failed
+    pub account: Option<AccountIdentity>,
+    // Set when a re-authorization signed in as someone other than the account being re-authorized
+    pub warning: Option<LinkWarning>,
+}
+
+// Identity mismatch on re-authorization; the link still succeeds, but the UI must tell the user
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LinkWarning {
+    // Signed in as another linked account: that account was refreshed, the requested one is unchanged
+    OtherLinkedAccount { requested_account_id: i64 },
+    // The re-authorized account is now linked to a different provider account than before
+    IdentityChanged { previous: AccountIdentity },
+}
+
+// OIDC userinfo claims we keep (OpenID Connect Core 5.1)
+#[derive(Deserialize)]
+struct UserInfoClaims {
+    sub: String,
+    email: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    picture: Option<String>,
+}
+
+// Token endpoint response fields we persist, shared by the code and device flows (RFC 6749 5.1)
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenGrant {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_in: Option<u64>,
+    pub scope: Option<String>,
+}
+
+// Device authorization response (RFC 8628 3.2). Google names the URI `verification_url`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceAuthorization {
+    pub device_code: String,
+    pub user_code: String,
+    #[serde(alias = "verification_url")]
+    pub verification_uri: String,
+    pub verification_uri_complete: Option<String>,
+    pub expires_in: u64,
+    #[serde(default = "default_device_poll_interval")]
+    pub interval: u64,
+}
+
+// RFC 8628 3.5: poll every 5 seconds unless told otherwise, and back off 5 more on `slow_down`
+const DEVICE_POLL_INTERVAL_SECS: u64 = 5;
+
+fn default_device_poll_interval() -> u64 {
+    DEVICE_POLL_INTERVAL_SECS
+}
+
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+// Terminal error from the device flow, e.g. `access_denied` or `expired_token`
+#[derive(Debug)]
+pub struct DeviceFlowError {
+    pub error: String,
+    pub description: Option<String>,
+}
+
+impl std::fmt::Display for DeviceFlowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "Device authorization failed: {}: {}", self.error, description),
+            None => write!(f, "Device authorization failed: {}", self.error),
+        }
+    }
+}
+
+impl std::error::Error for DeviceFlowError {}
+
+#[derive(Deserialize)]
+struct OAuthErrorBody {
+    error: String,
+    error_description: Option<String>,
+}
+
+// Returned when the provider requires a refresh token but the flow came back without one.
+// Nothing is stored in that case; callers can `downcast_ref` to tell it apart from other failures.
+#[derive(Debug)]
+pub struct MissingRefreshToken {
+    pub credential_id: i64,
+}
+
+impl std::fmt::Display for MissingRefreshToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Provider returned no refresh_token for credential_id={}; re-authorize with offline access and consent prompt",
+            self.credential_id
+        )
+    }
+}
+
+impl std::error::Error for MissingRefreshToken {}
+
+// Returned when the account's refresh_token was rejected with `invalid_grant` (revoked, expired, or
+// Google "Testing" apps after 7 days), and for every later use until the account is authorized again.
+#[derive(Debug, Clone)]
+pub struct ReauthorizationRequired {
+    pub account_id: i64,
+    pub reason: String,
+}
+
+impl std::fmt::Display for ReauthorizationRequired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Account {} needs to be re-authorized: {}", self.account_id, self.reason)
+    }
+}
+
+impl std::error::Error for ReauthorizationRequired {}
+
+impl ReauthorizationRequired {
+    fn for_token(token: &OauthToken) -> Self {
+        Self {
+            account_id: token.account_id,
+            reason: token.last_error.clone().unwrap_or_else(|| "refresh token was rejected".to_string()),
+        }
+    }
+}
+
+// True when `expires_at` (UTC "YYYY-MM-DD HH:MM:SS") is past or within `skew_secs`; unparsable values count as expired
+fn expires_within(expires_at: &str, skew_secs: u64) -> bool {
+    match NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%d %H:%M:%S") {
+        Ok(ndt) => Utc.from_utc_datetime(&ndt) <= Utc::now() + ChronoDuration::seconds(skew_secs as i64),
+        Err(_) => true,
+    }
+}
+
+// Failure of a shared refresh. anyhow::Error is not Clone, so other errors are flattened to their AppError.
+#[derive(Clone)]
+enum SharedRefreshError {
+    Reauthorization(ReauthorizationRequired),
+    Other(AppError),
+}
+
+impl From<&anyhow::Error> for SharedRefreshError {
+    fn from(e: &anyhow::Error) -> Self {
+        match e.downcast_ref::<ReauthorizationRequired>() {
+            Some(reauth) => Self::Reauthorization(reauth.clone()),
+            None => Self::Other(AppError::from(e)),
+        }
+    }
+}
+
+impl From<SharedRefreshError> for anyhow::Error {
+    fn from(e: SharedRefreshError) -> Self {
+        match e {
+            SharedRefreshError::Reauthorization(reauth) => reauth.into(),
+            SharedRefreshError::Other(error) => error.into(),
+        }
+    }
+}
+
+// Outcome of one refresh, shared with every caller that waited on it
+type SharedRefresh = Result<(String, String), SharedRefreshError>;
+
+// Refreshes currently in flight, keyed by account_id
+type InFlightRefreshes = Arc<Mutex<HashMap<i64, watch::Receiver<Option<SharedRefresh>>>>>;
+
+// Removes the in-flight entry when the leading refresh finishes or is dropped,
+// so waiters see the channel close instead of hanging
+struct InFlightGuard<'a> {
+    in_flight: &'a InFlightRefreshes,
+    account_id: i64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.lock().unwrap().remove(&self.account_id);
+    }
+}
+
+fn parse_scopes(scope: &str) -> Vec<String> {
+    scope.split_whitespace().map(str::to_string).collect()
+}
+
+// Scopes to request for a credential: its own list when set, otherwise the provider's defaults
+fn requested_scopes(credential: &ServiceCredential, provider: &OAuthProvider) -> Vec<String> {
+    match credential.scopes.as_deref().map(parse_scopes) {
+        Some(scopes) if !scopes.is_empty() => scopes,
+        _ => provider.default_scopes.clone(),
+    }
+}
+
+// Authorization params: provider params overridden by the credential's params; empty values are dropped
+fn authorization_params(credential: &ServiceCredential, provider: &OAuthProvider) -> anyhow::Result<BTreeMap<String, String>> {
+    let mut params = provider.extra_params.clone();
+    if let Some(raw) = &credential.auth_params {
+        let overrides: BTreeMap<String, String> =
+            serde_json::from_str(raw).context("Invalid auth_params stored for credential")?;
+        params.extend(overrides);
+    }
+    params.retain(|_, v| !v.is_empty());
+    Ok(params)
+}
+
+// Error response from a plain (non-oauth2 crate) provider endpoint. The RFC 6749 error goes to the AppError's
+// details; the raw body only to the context, which is logged but never sent to the UI.
+fn endpoint_error(endpoint: &str, status: reqwest::StatusCode, body: &str) -> anyhow::Error {
+    let parsed = serde_json::from_str::<OAuthErrorBody>(body).ok();
+    let error = AppError::provider(
+        parsed.as_ref().map(|b| b.error.as_str()),
+        parsed.as_ref().and_then(|b| b.error_description.as_deref()),
+    );
+    anyhow::Error::new(error).context(format!("{} endpoint returned {}: {}", endpoint, status, body))
+}
+
+// Keep the provider's error code; oauth2 only says "Server returned error response"
+fn token_request_error(
+    context: &'static str,
+    e: RequestTokenError<oauth2::reqwest::AsyncHttpClientError, BasicErrorResponse>,
+) -> anyhow::Error {
+    match e {
+        RequestTokenError::ServerResponse(response) => {
+            let error = AppError::provider(Some(response.error().as_ref()), response.error_description().map(String::as_str));
+            anyhow::Error::new(error).context(format!("{}: {}", context, response))
+        }
+        e => anyhow::Error::new(e).context(context),
+    }
+}
+
+// What `last_error` keeps for the UI: the provider's error code when it sent one, otherwise the fixed message.
+// The full chain only goes to the log.
+fn stored_error(error: &anyhow::Error) -> String {
+    let app_error = AppError::from(error);
+    match app_error.details.as_ref().and_then(|d| d.get("error")).and_then(|e| e.as_str()) {
+        Some(code) => code.to_string(),
+        None => app_error.message,
+    }
+}
+
+// OAuth2 Client for the credential's provider using oauth2 v4.4.0 API.
+// `redirect_url` is only needed for the authorization-code flow; refresh requests carry none.
+fn create_oauth_client(
     credential: &ServiceCredential,
-    redirect_url: &str,
+    provider: &OAuthProvider,
+    redirect_url: Option<&str>,
 ) -> anyhow::Result<oauth2::basic::BasicClient> {
     let client_id = ClientId::new(credential.client_id.clone());
     let client_secret = ClientSecret::new(credential.client_secret.clone());
-    let auth_url = AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())?;
-    let token_url = TokenUrl::new("https://oauth2.googleapis.com/token".to_string())?;
-
-    let redirect_url = RedirectUrl::new(redirect_url.to_string())?;
+    let auth_url = AuthUrl::new(provider.auth_url.clone())?;
+    let token_url = TokenUrl::new(provider.token_url.clone())?;
 
-    let client = oauth2::basic::BasicClient::new(
+    let mut client = oauth2::basic::BasicClient::new(
         client_id, 
         Some(client_secret), 
         auth_url, 
         Some(token_url)
-    )
-    .set_redirect_uri(redirect_url);
+    );
+
+    if let Some(redirect_url) = redirect_url {
+        client = client.set_redirect_uri(RedirectUrl::new(redirect_url.to_string())?);
+    }
+
+    if provider.client_secret_in_body {
+        client = client.set_auth_type(AuthType::RequestBody);
+    }
 
     Ok(client)
 }
 
+// POST a form to a provider endpoint with client authentication (Basic or in the body, per provider)
+fn authenticated_post(
+    credential: &ServiceCredential,
+    provider: &OAuthProvider,
+    url: &str,
+    mut form: Vec<(&str, String)>,
+) -> reqwest::RequestBuilder {
+    let mut request = reqwest::Client::new().post(url);
+    if provider.client_secret_in_body {
+        form.push(("client_id", credential.client_id.clone()));
+        form.push(("client_secret", credential.client_secret.clone()));
+    } else {
+        request = request.basic_auth(&credential.client_id, Some(&credential.client_secret));
+    }
+    request.form(&form)
+}
+
+// POST the token to the revocation endpoint. The refresh token is preferred since revoking it ends the grant.
+async fn revoke_at_provider(
+    credential: &ServiceCredential,
+    provider: &OAuthProvider,
+    revoke_url: &str,
+    refresh_token: &str,
+    access_token: &str,
+) -> anyhow::Result<()> {
+    let (token, hint) = if refresh_token.is_empty() || refresh_token == "no_refresh_token" {
+        (access_token, "access_token")
+    } else {
+        (refresh_token, "refresh_token")
+    };
+    let form = vec![("token", token.to_string()), ("token_type_hint", hint.to_string())];
+    let response = authenticated_post(credential, provider, revoke_url, form)
+        .send()
+        .await
+        .context("Failed to reach revocation endpoint")?;
+    let status = response.status();
+    if !status.is_success() {
+        let body = response.text().await.unwrap_or_default();
+        return Err(endpoint_error("Revocation", status, &body));
+    }
+    Ok(())
+}
+
+// A duration from the device authorization response; a value too large to wait for is the provider's error
+fn device_seconds(field: &str, value: u64) -> anyhow::Result<u32> {
+    u32::try_from(value).map_err(|_| {
+        anyhow::Error::new(AppError::provider(None, None)).context(format!("Device authorization returned an invalid {}: {}", field, value))
+    })
+}
+
+// RFC 8628 3.4/3.5 polling loop, honouring `authorization_pending` and `slow_down`.
+// `unit` is one second in production; tests shrink it to keep the intervals short.
+async fn poll_token_endpoint(
+    credential: &ServiceCredential,
+    provider: &OAuthProvider,
+    device: &DeviceAuthorization,
+    unit: std::time::Duration,
+) -> anyhow::Result<TokenGrant> {
+    let deadline = tokio::time::Instant::now() + unit * device_seconds("expires_in", device.expires_in)?;
+    let mut interval = unit * device_seconds("interval", device.interval)?;
+
+    loop {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= deadline {
+            return Err(DeviceFlowError { error: "expired_token".to_string(), description: None }.into());
+        }
+
+        let form = vec![
+            ("grant_type", DEVICE_CODE_GRANT_TYPE.to_string()),
+            ("device_code", device.device_code.clone()),
+        ];
+        let response = authenticated_post(credential, provider, &provider.token_url, form)
+            .send()
+            .await
+            .context("Failed to reach token endpoint")?;
+        if response.status().is_success() {
+            return response.json().await.context("Invalid token response");
+        }
+
+        let error: OAuthErrorBody = response.json().await.context("Invalid token error response")?;
+        match error.error.as_str() {
+            "authorization_pending" => {}
+            "slow_down" => interval += unit * DEVICE_POLL_INTERVAL_SECS as u32,
+            _ => {
+                return Err(DeviceFlowError { error: error.error, description: error.error_description }.into());
+            }
+        }
+    }
+}
+
+async fn fetch_account_identity(userinfo_url: &str, access_token: &str) -> anyhow::Result<AccountIdentity> {
+    let response = reqwest::Client::new()
+        .get(userinfo_url)
+        .bearer_auth(access_token)
+        .send()
+        .await
+        .context("Failed to reach userinfo endpoint")?;
+    if !response.status().is_success() {
+        return Err(anyhow::Error::new(AppError::provider(None, None)).context(format!("Userinfo endpoint returned {}", response.status())));
+    }
+    let claims: UserInfoClaims = response.json().await.context("Invalid userinfo response")?;
+    Ok(AccountIdentity {
+        subject: claims.sub,
+        email: claims.email,
+        name: claims.name.or(claims.preferred_username),
+        picture: claims.picture,
+    })
+}
+
 #[derive(Clone)]
 pub struct OAuthService {
     credential_repo: Arc<dyn CredentialRepository + Send + Sync>,
+    account_repo: Arc<dyn AccountRepository + Send + Sync>,
     token_repo: Arc<dyn TokenRepository + Send + Sync>,
+    providers: ProviderRegistry,
+    // Shared by clones so every caller in the app joins the same refresh
+    in_flight: InFlightRefreshes,
 }
 
 impl OAuthService {
     pub fn new(
         credential_repo: Arc<dyn CredentialRepository + Send + Sync>,
+        account_repo: Arc<dyn AccountRepository + Send + Sync>,
         token_repo: Arc<dyn TokenRepository + Send + Sync>,
+        providers: ProviderRegistry,
     ) -> Self {
-        Self { credential_repo, token_repo }
+        Self { credential_repo, account_repo, token_repo, providers, in_flight: InFlightRefreshes::default() }
     }
 
-    pub async fn generate_auth_url(&self, credential_id: i64, redirect_url: &str) -> anyhow::Result<(String, String)> {
+    // Load a credential together with the provider it is bound to
+    async fn load_credential_and_provider(&self, credential_id: i64) -> anyhow::Result<(ServiceCredential, OAuthProvider)> {
         let credential = self
             .credential_repo
             .get_credential_by_id(credential_id)
             .await?
-            .context("Credential not found")?;
+            .ok_or_else(|| AppError::not_found("Credential"))?;
+        let provider = self.providers.resolve(&credential.provider).await?;
+        Ok((credential, provider))
+    }
+
+    // Load a linked account together with its credential and provider
+    async fn load_account(&self, account_id: i64) -> anyhow::Result<(OauthAccount, ServiceCredential, OAuthProvider)> {
+        let account = self
+            .account_repo
+            .get_account_by_id(account_id)
+            .await?
+            .ok_or_else(|| AppError::not_found("Account"))?;
+        let (credential, provider) = self.load_credential_and_provider(account.credentials_id).await?;
+        Ok((account, credential, provider))
+    }
+
+    async fn load_token(&self, account_id: i64) -> anyhow::Result<OauthToken> {
+        let token = self.token_repo.get_token_by_account_id(account_id).await?;
+        Ok(token.ok_or_else(|| AppError::not_found("Token"))?)
+    }
 
-    let client = create_google_oauth_client(&credential, redirect_url)?;
+    // Generate the authorization URL with a fresh CSRF state and PKCE (S256) verifier/challenge pair
+    pub async fn generate_auth_url(&self, credential_id: i64, redirect_url: &str) -> anyhow::Result<AuthorizationRequest> {
+        let (credential, provider) = self.load_credential_and_provider(credential_id).await?;
 
-    let (authorize_url, csrf_token) = client
+    let client = create_oauth_client(&credential, &provider, Some(redirect_url))?;
+
+    let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+
+    let mut auth_request = client
             .authorize_url(oauth2::CsrfToken::new_random)
-            .add_scope(oauth2::Scope::new("https://www.googleapis.com/auth/youtube".to_string()))
-            .add_scope(oauth2::Scope::new("https://www.googleapis.com/auth/userinfo.profile".to_string()))
-            .add_scope(oauth2::Scope::new("https://www.googleapis.com/auth/userinfo.email".to_string()))
-            .url();
+            .add_scopes(requested_scopes(&credential, &provider).into_iter().map(oauth2::Scope::new))
+            .set_pkce_challenge(pkce_challenge);
+    for (name, value) in authorization_params(&credential, &provider)? {
+        auth_request = auth_request.add_extra_param(name, value);
+    }
+    let (authorize_url, csrf_token) = auth_request.url();
 
-    Ok((authorize_url.to_string(), csrf_token.secret().to_string()))
+    Ok(AuthorizationRequest {
+        auth_url: authorize_url.to_string(),
+        csrf_state: csrf_token.secret().to_string(),
+        pkce_verifier: pkce_verifier.secret().to_string(),
+    })
     }
 
-    pub async fn exchange_code_and_save_token(&self, code: String, credential_id: i64, redirect_url: &str) -> anyhow::Result<()> {
-        println!("Starting token exchange for credential_id: {}", credential_id);
-        let credential = self
-            .credential_repo
-            .get_credential_by_id(credential_id)
-            .await?
-            .context("Credential not found")?;
+    // Exchange the code, persist the token and report granted vs. requested scopes
+    pub async fn exchange_code_and_save_token(&self, code: String, pkce_verifier: String, target: LinkTarget, redirect_url: &str) -> anyhow::Result<LinkedToken> {
+        println!("Starting token exchange for credential_id: {}", target.credential_id);
+        let (credential, provider) = self.load_credential_and_provider(target.credential_id).await?;
 
-    let client = create_google_oauth_client(&credential, redirect_url)?;
+    let client = create_oauth_client(&credential, &provider, Some(redirect_url))?;
 
         let token_result = client
             .exchange_code(oauth2::AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier))
             .request_async(oauth2::reqwest::async_http_client)
             .await
-            .context("Failed to exchange code for token")?;
+            .map_err(|e| token_request_error("Failed to exchange code for token", e))?;
 
     println!("Token exchange successful.");
 
-        let payload = AddTokenPayload {
-            credentials_id: credential_id,
+        let grant = TokenGrant {
             access_token: token_result.access_token().secret().to_string(),
-            refresh_token: token_result.refresh_token().map_or("no_refresh_token".to_string(), |t| t.secret().to_string()),
-            expires_at: match token_result.expires_in() {
-                Some(duration) => {
+            refresh_token: token_result.refresh_token().map(|t| t.secret().to_string()),
+            expires_in: token_result.expires_in().map(|d| d.as_secs()),
+            scope: token_result
+                .scopes()
+                .map(|s| s.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" ")),
+        };
+        self.store_grant(&credential, &provider, target.account_id, grant).await
+    }
+
+    // Persist a token obtained outside the code exchange (device flow) and report granted scopes
+    pub async fn save_token_grant(&self, target: LinkTarget, grant: TokenGrant) -> anyhow::Result<LinkedToken> {
+        let (credential, provider) = self.load_credential_and_provider(target.credential_id).await?;
+        self.store_grant(&credential, &provider, target.account_id, grant).await
+    }
+
+    async fn store_grant(
+        &self,
+        credential: &ServiceCredential,
+        provider: &OAuthProvider,
+        target_account_id: Option<i64>,
+        grant: TokenGrant,
+    ) -> anyhow::Result<LinkedToken> {
+        let credential_id = credential.id;
+
+        // Per RFC 6749 5.1 an omitted scope means the requested scopes were granted as-is
+        let requested = requested_scopes(credential, provider);
+        let granted = grant.scope.as_deref().map(parse_scopes).unwrap_or_else(|| requested.clone());
+
+        // Never persist a placeholder: a missing refresh token is either an error or an empty value
+        let refresh_token = match grant.refresh_token {
+            Some(t) => t,
+            None if provider.requires_refresh_token => {
+                return Err(MissingRefreshToken { credential_id }.into());
+            }
+            None => String::new(),
+        };
+
+        let target = match target_account_id {
+            Some(account_id) => {
+                let account = self
+                    .account_repo
+                    .get_account_by_id(account_id)
+                    .await?
+                    .ok_or_else(|| AppError::not_found("Account"))?;
+                if account.credentials_id != credential_id {
+                    return Err(AppError::invalid_input(format!("Account {} does not belong to credential_id={}", account_id, credential_id)).into());
+                }
+                Some(account)
+            }
+            None => None,
+        };
+
+        // An unverified identity is cleared rather than left pointing at the previous account
+        let identity = match &provider.userinfo_url {
+            Some(userinfo_url) => match fetch_account_identity(userinfo_url, &grant.access_token).await {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    eprintln!("Failed to fetch account identity for credential_id={}: {:#}", credential_id, e);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // The account is chosen by identity first, so signing in as an already linked account refreshes it
+        let existing = match &identity {
+            Some(identity) => self.account_repo.get_account_by_subject(credential_id, &identity.subject).await?,
+            None => None,
+        };
+        let (account_id, warning) = match (existing, target) {
+            (Some(existing), target) => {
+                let warning = match target {
+                    Some(target) if target.id != existing.id => {
+                        eprintln!(
+                            "Signed in as account {} instead of account {} being re-authorized; refreshed that account",
+                            existing.id, target.id
+                        );
+                        Some(LinkWarning::OtherLinkedAccount { requested_account_id: target.id })
+                    }
+                    _ => None,
+                };
+                (existing.id, warning)
+            }
+            (None, Some(target)) => {
+                let warning = match (target.identity(), &identity) {
+                    (Some(previous), Some(current)) if previous.subject != current.subject => {
+                        eprintln!("Account {} is now linked to a different provider account than before", target.id);
+                        Some(LinkWarning::IdentityChanged { previous })
+                    }
+                    _ => None,
+                };
+                (target.id, warning)
+            }
+            (None, None) => (self.account_repo.add_account(credential_id, identity.as_ref()).await?.id, None),
+        };
+        self.account_repo
+            .update_account_identity(account_id, identity.as_ref())
+            .await
+            .context("Failed to save account identity")?;
+
+        let payload = AddTokenPayload {
+            account_id,
+            access_token: grant.access_token,
+            refresh_token,
+            expires_at: match grant.expires_in {
+                Some(secs) => {
                     let now = std::time::SystemTime::now();
-                    let future = now + duration;
+                    let future = now + std::time::Duration::from_secs(secs);
                     let datetime: chrono::DateTime<chrono::Utc> = future.into();
                     datetime.format("%Y-%m-%d %H:%M:%S").to_string()
                 },
                 None => "2099-12-31 23:59:59".to_string(),
             },
-            scope: Some(token_result.scopes().map_or("".to_string(), |s| s.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" "))),
+            scope: Some(granted.join(" ")),
         };
-
         self.token_repo.upsert_token(payload).await.context("Failed to save token to database")?;
 
-        println!("Token saved to database.");
-        Ok(())
+        println!("Token saved to database for account_id: {}", account_id);
+        let check = ScopeCheck::compare(requested, granted);
+        if check.needs_reconsent {
+            eprintln!("Token for account_id={} is missing scopes: {}", account_id, check.missing.join(" "));
+        }
+
+        Ok(LinkedToken { account_id, scopes: check, account: identity, warning })
     }
 
-    // Ensure the access token is valid; refresh if expired or within skew seconds
-    pub async fn ensure_valid_access_token(&self, credential_id: i64, skew_secs: u64) -> anyhow::Result<(String, String)> {
-        let token_opt = self
-            .token_repo
-            .get_token_by_credential_id(credential_id)
-            .await?;
-
-        let token = token_opt.context("Token not found")?;
-
-        // Parse expires_at in UTC "YYYY-MM-DD HH:MM:SS"; if parse fails, treat as expired
-        let need_refresh =
-        match NaiveDateTime::parse_from_str(&token.expires_at, "%Y-%m-%d %H:%M:%S") {
-                Ok(ndt) => {
-            let exp = Utc.from_utc_datetime(&ndt);
-                    let skew = ChronoDuration::seconds(skew_secs as i64);
-                    let threshold = Utc::now() + skew;
-                    exp <= threshold
-                }
-                Err(_) => true,
-            };
+    // Accounts linked to the credential, oldest first
+    pub async fn list_accounts(&self, credential_id: i64) -> anyhow::Result<Vec<OauthAccount>> {
+        self.account_repo.get_accounts_by_credential_id(credential_id).await
+    }
+
+    // Accounts whose token needs re-authorization or whose last refresh failed
+    pub async fn list_tokens_needing_attention(&self) -> anyhow::Result<Vec<TokenAttention>> {
+        self.token_repo.get_tokens_needing_attention().await
+    }
+
+    // Start an RFC 8628 device flow: returns the user code and verification URI to show the user
+    pub async fn request_device_code(&self, credential_id: i64) -> anyhow::Result<DeviceAuthorization> {
+        let (credential, provider) = self.load_credential_and_provider(credential_id).await?;
+        let device_url = provider
+            .device_authorization_url
+            .as_deref()
+            .ok_or_else(|| AppError::invalid_input(format!("Provider '{}' does not support the device flow", provider.key)))?;
+
+        let form = [
+            ("client_id", credential.client_id.clone()),
+            ("scope", requested_scopes(&credential, &provider).join(" ")),
+        ];
+        let response = reqwest::Client::new()
+            .post(device_url)
+            .form(&form)
+            .send()
+            .await
+            .context("Failed to reach device authorization endpoint")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(endpoint_error("Device authorization", status, &body));
+        }
+        let device: DeviceAuthorization = response.json().await.context("Invalid device authorization response")?;
+        // Checked before a flow starts, since its deadline is derived from `expires_in` too
+        device_seconds("expires_in", device.expires_in)?;
+        device_seconds("interval", device.interval)?;
+        Ok(device)
+    }
 
-        if !need_refresh {
+    // Poll the token endpoint until the user approves on another device.
+    // Terminal errors (`access_denied`, `expired_token`, ...) are returned as `DeviceFlowError`.
+    pub async fn poll_device_token(&self, credential_id: i64, device: &DeviceAuthorization) -> anyhow::Result<TokenGrant> {
+        let (credential, provider) = self.load_credential_and_provider(credential_id).await?;
+        poll_token_endpoint(&credential, &provider, device, std::time::Duration::from_secs(1)).await
+    }
+
+    // Report whether the account's token covers the scopes a feature needs (e.g. youtube.force-ssl)
+    pub async fn check_token_scopes(&self, account_id: i64, required_scopes: Vec<String>) -> anyhow::Result<ScopeCheck> {
+        let token = self.load_token(account_id).await?;
+        let granted = token.scope.as_deref().map(parse_scopes).unwrap_or_default();
+        Ok(ScopeCheck::compare(required_scopes, granted))
+    }
+
+    // Ensure the account's access token is valid; refresh if expired or within skew seconds
+    pub async fn ensure_valid_access_token(&self, account_id: i64, skew_secs: u64) -> anyhow::Result<(String, String)> {
+        let token = self.load_token(account_id).await?;
+
+        // A rejected refresh_token will not work again; don't hand out its access token either
+        if token.status == TokenStatus::NeedsReauth {
+            return Err(ReauthorizationRequired::for_token(&token).into());
+        }
+
+        if !expires_within(&token.expires_at, skew_secs) {
             return Ok((token.access_token, token.expires_at));
         }
 
         // Require refresh_token for refresh flow
         if token.refresh_token.is_empty() || token.refresh_token == "no_refresh_token" {
-            anyhow::bail!("No refresh_token available for credential_id={}", credential_id);
+            return Err(AppError::new(ErrorCode::NeedsReauth, format!("No refresh_token available for account_id={}", account_id))
+                .with_details(serde_json::json!({ "account_id": account_id }))
+                .into());
         }
 
-        // Perform refresh and return updated token info
-        let (access_token, expires_at) = self.refresh_access_token(credential_id).await?;
-        Ok((access_token, expires_at))
+        self.refresh_single_flight(account_id, Some(skew_secs)).await
     }
 
-    // Refresh the access token using the stored refresh_token and persist the new values
-    pub async fn refresh_access_token(&self, credential_id: i64) -> anyhow::Result<(String, String)> {
-        // Load credential for client configuration
-        let credential = self
-            .credential_repo
-            .get_credential_by_id(credential_id)
-            .await?
-            .context("Credential not found")?;
+    // Revoke the account's token at the provider (RFC 7009) and delete the account locally.
+    // A provider failure is reported in the outcome instead of keeping the account around.
+    pub async fn revoke_token(&self, account_id: i64) -> anyhow::Result<RevocationOutcome> {
+        let (_, credential, provider) = self.load_account(account_id).await?;
+        let token = self.token_repo.get_token_by_account_id(account_id).await?;
+
+        let provider_result = match (&provider.revoke_url, &token) {
+            (Some(revoke_url), Some(token)) => {
+                revoke_at_provider(&credential, &provider, revoke_url, &token.refresh_token, &token.access_token).await
+            }
+            (None, _) => Err(AppError::invalid_input(format!("Provider '{}' has no revocation endpoint", provider.key)).into()),
+            (_, None) => Err(AppError::not_found("Token").into()),
+        };
+        let provider_error = provider_result.err().map(|e| {
+            eprintln!("Token revocation failed for account_id={}: {:#}", account_id, e);
+            AppError::from(e)
+        });
+
+        self.account_repo
+            .delete_account(account_id)
+            .await
+            .context("Failed to delete account from database")?;
+        println!("Account {} unlinked.", account_id);
+
+        Ok(RevocationOutcome {
+            account_id,
+            revoked_at_provider: provider_error.is_none(),
+            local_token_removed: token.is_some(),
+            provider_error,
+            error: None,
+        })
+    }
+
+    // Unlink every account of a credential, revoking each grant at the provider first (see `revoke_token`).
+    // Used before deleting the credential, while its client id and secret can still authenticate the revocation.
+    // A failing account does not stop the others: every account gets an outcome, failures carry `error`.
+    pub async fn revoke_credential_accounts(&self, credential_id: i64) -> anyhow::Result<Vec<RevocationOutcome>> {
+        let mut outcomes = Vec::new();
+        for account in self.account_repo.get_accounts_by_credential_id(credential_id).await? {
+            let outcome = match self.revoke_token(account.id).await {
+                Ok(outcome) => outcome,
+                Err(e) => {
+                    eprintln!("Failed to unlink account_id={}: {:#}", account.id, e);
+                    RevocationOutcome {
+                        account_id: account.id,
+                        revoked_at_provider: false,
+                        local_token_removed: false,
+                        provider_error: None,
+                        error: Some(AppError::from(e)),
+                    }
+                }
+            };
+            outcomes.push(outcome);
+        }
+        Ok(outcomes)
+    }
+
+    // Refresh the account's access token using the stored refresh_token and persist the new values.
+    // Concurrent refreshes of the same account share one request to the provider.
+    pub async fn refresh_access_token(&self, account_id: i64) -> anyhow::Result<(String, String)> {
+        self.refresh_single_flight(account_id, None).await
+    }
+
+    // Join the account's in-flight refresh, or lead a new one when none is running.
+    // Providers that rotate refresh tokens invalidate the old one on use, so two parallel
+    // refreshes with the same refresh_token would leave one caller with a revoked token.
+    // With `skew_secs`, the leader first re-reads the token and skips the request when
+    // a refresh that finished just before it already made the token fresh enough.
+    async fn refresh_single_flight(&self, account_id: i64, skew_secs: Option<u64>) -> anyhow::Result<(String, String)> {
+        let (leader, mut rx) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&account_id) {
+                Some(rx) => (None, rx.clone()),
+                None => {
+                    let (tx, rx) = watch::channel(None);
+                    in_flight.insert(account_id, rx.clone());
+                    (Some(tx), rx)
+                }
+            }
+        };
+
+        let Some(tx) = leader else {
+            let shared = rx
+                .wait_for(Option::is_some)
+                .await
+                .map_err(|_| anyhow::anyhow!("Token refresh for account_id={} was abandoned", account_id))?
+                .clone()
+                .expect("waited for a result");
+            return shared.map_err(anyhow::Error::from);
+        };
+
+        let _guard = InFlightGuard { in_flight: &self.in_flight, account_id };
+        let result = match skew_secs {
+            Some(skew_secs) => match self.load_token(account_id).await {
+                Ok(token) if !expires_within(&token.expires_at, skew_secs) => Ok((token.access_token, token.expires_at)),
+                _ => self.refresh_and_save(account_id).await,
+            },
+            None => self.refresh_and_save(account_id).await,
+        };
+        tx.send_replace(Some(result.as_ref().map(Clone::clone).map_err(SharedRefreshError::from)));
+        result
+    }
+
+    // Run the refresh grant against the provider and persist the new token
+    async fn refresh_and_save(&self, account_id: i64) -> anyhow::Result<(String, String)> {
+        // Load credential and provider for client configuration
+        let (_, credential, provider) = self.load_account(account_id).await?;
 
         // Load current token to obtain refresh_token
-        let current_token = self
-            .token_repo
-            .get_token_by_credential_id(credential_id)
-            .await?
-            .context("Token not found")?;
+        let current_token = self.load_token(account_id).await?;
+        if current_token.status == TokenStatus::NeedsReauth {
+            return Err(ReauthorizationRequired::for_token(&current_token).into());
+        }
 
-        // Use fixed redirect URL per design
-        let redirect_url = "http://localhost:1421/oauth/callback";
-        let client = create_google_oauth_client(&credential, redirect_url)?;
+        // The refresh grant has no redirect_uri (RFC 6749 6)
+        let client = create_oauth_client(&credential, &provider, None)?;
 
         let refresh_token_val = current_token.refresh_token.clone();
-        let token_result = client
+        let token_result = match client
             .exchange_refresh_token(&RefreshToken::new(refresh_token_val.clone()))
             .request_async(oauth2::reqwest::async_http_client)
             .await
-            .context("Failed to refresh access token")?;
+        {
+            Ok(token_result) => token_result,
+            // invalid_grant is final: retrying with the same refresh_token cannot succeed
+            Err(RequestTokenError::ServerResponse(response)) if *response.error() == BasicErrorResponseType::InvalidGrant => {
+                // Only the error code is kept; the description is free text from the provider
+                eprintln!("Refresh token rejected for account_id={}: {}", account_id, response);
+                let reason = response.error().as_ref().to_string();
+                self.token_repo
+                    .record_token_error(account_id, TokenStatus::NeedsReauth, &reason)
+                    .await
+                    .context("Failed to save token status")?;
+                return Err(ReauthorizationRequired { account_id, reason }.into());
+            }
+            Err(e) => {
+                let error = token_request_error("Failed to refresh access token", e);
+                eprintln!("Failed to refresh token for account_id={}: {:#}", account_id, error);
+                if let Err(save_error) = self
+                    .token_repo
+                    .record_token_error(account_id, TokenStatus::Active, &stored_error(&error))
+                    .await
+                {
+                    eprintln!("Failed to save refresh error for account_id={}: {:#}", account_id, save_error);
+                }
+                return Err(error);
+            }
+        };
 
         // Some providers don't return refresh_token on refresh; keep existing if absent
         let new_refresh_token = token_result
@@ -179,12 +901,22 @@ impl OAuthService {
             None => "2099-12-31 23:59:59".to_string(),
         };
 
+        // Keep the previously granted scopes when the refresh response omits them
+        let granted = token_result
+            .scopes()
+            .map(|s| s.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+            .unwrap_or_else(|| current_token.scope.as_deref().map(parse_scopes).unwrap_or_default());
+        let check = ScopeCheck::compare(requested_scopes(&credential, &provider), granted.clone());
+        if check.needs_reconsent {
+            eprintln!("Refreshed token for account_id={} is missing scopes: {}", account_id, check.missing.join(" "));
+        }
+
         let payload = AddTokenPayload {
-            credentials_id: credential_id,
+            account_id,
             access_token: token_result.access_token().secret().to_string(),
             refresh_token: new_refresh_token,
             expires_at: expires_at.clone(),
-            scope: Some(token_result.scopes().map_or("".to_string(), |s| s.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" "))),
+            scope: Some(granted.join(" ")),
         };
 
         self
@@ -200,58 +932,785 @@ impl OAuthService {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::db::repositories::{SqliteRepository, CredentialRepository, TokenRepository};
-    use crate::db::models::{AddCredentialPayload};
-    use crate::db::setup::init_test_db;
+    use crate::db::repositories::{SqliteRepository, AccountRepository, CredentialRepository, TokenRepository};
+    use crate::db::models::AddCredentialPayload;
+    use crate::db::setup::{init_test_db, test_vault};
+    use crate::error::ErrorCode;
+    use crate::test_support::{add_token, error_json, mock_provider_credential, spawn_mock_endpoint, token_json};
+    use hyper::StatusCode;
     use sqlx::SqlitePool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use url::Url;
+
+    fn query_param(url: &str, key: &str) -> Option<String> {
+        Url::parse(url).unwrap().query_pairs().find(|(k, _)| k == key).map(|(_, v)| v.to_string())
+    }
+
+    fn service(repo: &Arc<SqliteRepository>) -> OAuthService {
+        OAuthService::new(repo.clone(), repo.clone(), repo.clone(), ProviderRegistry::new(repo.clone()))
+    }
+
+    fn app_error<T: std::fmt::Debug>(result: anyhow::Result<T>) -> AppError {
+        AppError::from(result.unwrap_err())
+    }
+
+    fn new_account(cred_id: i64) -> LinkTarget {
+        LinkTarget { credential_id: cred_id, account_id: None }
+    }
+
+    // Token of the credential's only linked account, if any
+    async fn only_token(repo: &Arc<SqliteRepository>, cred_id: i64) -> Option<OauthToken> {
+        let accounts = repo.get_accounts_by_credential_id(cred_id).await.unwrap();
+        assert!(accounts.len() <= 1, "expected at most one account, got {}", accounts.len());
+        match accounts.first() {
+            Some(account) => repo.get_token_by_account_id(account.id).await.unwrap(),
+            None => None,
+        }
+    }
 
     async fn setup_repo() -> (Arc<SqliteRepository>, i64) {
         let pool: SqlitePool = init_test_db().await.unwrap();
-        let repo = Arc::new(SqliteRepository::new(pool));
+        let repo = Arc::new(SqliteRepository::new(pool, test_vault()));
         let cred = repo.add_credential(AddCredentialPayload{
             service_name: "google".into(),
             client_id: "cid".into(),
             client_secret: "csec".into(),
+            provider: "google".into(),
+            scopes: None,
+            auth_params: None,
         }).await.unwrap();
         (repo, cred.id)
     }
 
+    // Token endpoint that accepts a code only when S256(code_verifier) matches the stored challenge
+    async fn spawn_pkce_token_endpoint(expected_challenge: Arc<Mutex<String>>) -> String {
+        spawn_mock_endpoint(move |_path, form| {
+            let accepted = form.get("code_verifier").is_some_and(|v| {
+                let challenge = PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(v.clone()));
+                challenge.as_str() == *expected_challenge.lock().unwrap()
+            });
+            if accepted {
+                (StatusCode::OK, token_json("mock_access", Some("mock_refresh"), None))
+            } else {
+                (StatusCode::BAD_REQUEST, error_json("invalid_grant", "code_verifier mismatch"))
+            }
+        }).await
+    }
+
     #[tokio::test]
     async fn ensure_valid_access_token_returns_existing_when_not_expiring() {
         let (repo, cred_id) = setup_repo().await;
-        let svc = OAuthService::new(repo.clone(), repo.clone());
+        let svc = service(&repo);
 
         // Insert token that expires far in the future
-        let payload = AddTokenPayload{
-            credentials_id: cred_id,
-            access_token: "a1".into(),
-            refresh_token: "r1".into(),
-            expires_at: "2099-12-31 23:59:59".into(),
-            scope: Some("s".into()),
-        };
-        repo.upsert_token(payload).await.unwrap();
+        let account_id = add_token(&repo, cred_id, "r1", "2099-12-31 23:59:59", Some("s")).await;
 
-        let (at, exp) = svc.ensure_valid_access_token(cred_id, 120).await.unwrap();
-        assert_eq!(at, "a1");
+        let (at, exp) = svc.ensure_valid_access_token(account_id, 120).await.unwrap();
+        assert_eq!(at, "old_access");
         assert_eq!(exp, "2099-12-31 23:59:59");
     }
 
     #[tokio::test]
     async fn ensure_valid_access_token_errors_without_refresh_token() {
         let (repo, cred_id) = setup_repo().await;
-        let svc = OAuthService::new(repo.clone(), repo.clone());
+        let svc = service(&repo);
 
         // Insert expired token and no refresh token
-        let payload = AddTokenPayload{
-            credentials_id: cred_id,
-            access_token: "a1".into(),
-            refresh_token: "".into(),
-            expires_at: "2000-01-01 00:00:00".into(),
-            scope: None,
-        };
-        repo.upsert_token(payload).await.unwrap();
+        let account_id = add_token(&repo, cred_id, "", "2000-01-01 00:00:00", None).await;
+
+        let error = app_error(svc.ensure_valid_access_token(account_id, 0).await);
+        assert_eq!(error.code, ErrorCode::NeedsReauth);
+        assert_eq!(error.details, Some(serde_json::json!({ "account_id": account_id })));
+    }
+
+    #[tokio::test]
+    async fn missing_records_are_not_found() {
+        let (repo, _) = setup_repo().await;
+        let svc = service(&repo);
+
+        let error = app_error(svc.generate_auth_url(999, "http://localhost:1421/oauth/callback").await);
+        assert_eq!((error.code, error.message.as_str()), (ErrorCode::NotFound, "Credential not found"));
+        assert_eq!(app_error(svc.refresh_access_token(999).await).code, ErrorCode::NotFound);
+        assert_eq!(app_error(svc.check_token_scopes(999, vec![]).await).code, ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn unreachable_token_endpoint_is_a_retryable_network_error() {
+        let (repo, _) = setup_repo().await;
+        // Nothing listens on port 9 of localhost
+        let cred_id = mock_provider_credential(&repo, "http://127.0.0.1:9", &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        let error = app_error(svc.refresh_access_token(account_id).await);
+        assert_eq!((error.code, error.retryable), (ErrorCode::Network, true));
+        assert_eq!(error.message, "Could not reach the OAuth provider");
+    }
+
+    #[tokio::test]
+    async fn generate_auth_url_includes_s256_pkce_challenge() {
+        let (repo, cred_id) = setup_repo().await;
+        let svc = service(&repo);
+
+        let req = svc.generate_auth_url(cred_id, "http://localhost:1421/oauth/callback").await.unwrap();
+
+        assert_eq!(query_param(&req.auth_url, "code_challenge_method").as_deref(), Some("S256"));
+        let expected = PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(req.pkce_verifier.clone()));
+        assert_eq!(query_param(&req.auth_url, "code_challenge").as_deref(), Some(expected.as_str()));
+        assert_eq!(query_param(&req.auth_url, "state").as_deref(), Some(req.csrf_state.as_str()));
 
-        let res = svc.ensure_valid_access_token(cred_id, 0).await;
+        // Each flow gets its own verifier
+        let other = svc.generate_auth_url(cred_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert_ne!(req.pkce_verifier, other.pkce_verifier);
+    }
+
+    #[tokio::test]
+    async fn generate_auth_url_uses_credential_provider() {
+        let (repo, google_id) = setup_repo().await;
+        let mock_id = mock_provider_credential(&repo, "http://127.0.0.1:9", &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let google = svc.generate_auth_url(google_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert!(google.auth_url.starts_with("https://accounts.google.com/o/oauth2/v2/auth?"));
+        assert!(query_param(&google.auth_url, "scope").unwrap().contains("https://www.googleapis.com/auth/youtube"));
+
+        let mock = svc.generate_auth_url(mock_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert!(mock.auth_url.starts_with("http://127.0.0.1:9/authorize?"));
+        assert_eq!(query_param(&mock.auth_url, "scope").as_deref(), Some("mock.read"));
+        assert_eq!(query_param(&mock.auth_url, "audience").as_deref(), Some("mock-api"));
+        assert_eq!(query_param(&mock.auth_url, "client_id").as_deref(), Some("mock_cid"));
+    }
+
+    #[tokio::test]
+    async fn exchange_succeeds_with_matching_pkce_verifier() {
+        let (repo, _) = setup_repo().await;
+        let redirect = "http://localhost:1421/oauth/callback";
+        let expected_challenge = Arc::new(Mutex::new(String::new()));
+        let base_url = spawn_pkce_token_endpoint(expected_challenge.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let req = svc.generate_auth_url(cred_id, redirect).await.unwrap();
+        *expected_challenge.lock().unwrap() = query_param(&req.auth_url, "code_challenge").unwrap();
+
+        svc.exchange_code_and_save_token("code".into(), req.pkce_verifier, new_account(cred_id), redirect)
+            .await
+            .unwrap();
+
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.access_token, "mock_access");
+        assert_eq!(token.refresh_token, "mock_refresh");
+    }
+
+    #[tokio::test]
+    async fn exchange_rejected_without_matching_pkce_verifier() {
+        let (repo, _) = setup_repo().await;
+        let redirect = "http://localhost:1421/oauth/callback";
+        let expected_challenge = Arc::new(Mutex::new(String::new()));
+        let base_url = spawn_pkce_token_endpoint(expected_challenge.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let req = svc.generate_auth_url(cred_id, redirect).await.unwrap();
+        let other = svc.generate_auth_url(cred_id, redirect).await.unwrap();
+        *expected_challenge.lock().unwrap() = query_param(&req.auth_url, "code_challenge").unwrap();
+
+        // Verifier from a different flow
+        let res = svc
+            .exchange_code_and_save_token("code".into(), other.pkce_verifier, new_account(cred_id), redirect)
+            .await;
+        assert!(res.is_err());
+
+        // Missing verifier
+        let res = svc
+            .exchange_code_and_save_token("code".into(), String::new(), new_account(cred_id), redirect)
+            .await;
         assert!(res.is_err());
+
+        assert!(only_token(&repo, cred_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn generate_auth_url_requests_credential_scopes() {
+        let (repo, cred_id) = setup_repo().await;
+        repo.update_credential_scopes(cred_id, Some("https://www.googleapis.com/auth/youtube.force-ssl openid".into()))
+            .await
+            .unwrap();
+        let svc = service(&repo);
+
+        let req = svc.generate_auth_url(cred_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert_eq!(
+            query_param(&req.auth_url, "scope").as_deref(),
+            Some("https://www.googleapis.com/auth/youtube.force-ssl openid")
+        );
+    }
+
+    #[tokio::test]
+    async fn exchange_reports_missing_granted_scopes() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::OK, token_json("mock_access", Some("mock_refresh"), Some("a")))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        repo.update_credential_scopes(cred_id, Some("a b".into())).await.unwrap();
+        let svc = service(&repo);
+
+        let check = svc
+            .exchange_code_and_save_token("code".into(), "verifier".into(), new_account(cred_id), "http://localhost:1421/oauth/callback")
+            .await
+            .unwrap()
+            .scopes;
+        assert_eq!(check.requested, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(check.granted, vec!["a".to_string()]);
+        assert_eq!(check.missing, vec!["b".to_string()]);
+        assert!(check.needs_reconsent);
+
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.scope.as_deref(), Some("a"));
+    }
+
+    #[tokio::test]
+    async fn exchange_without_scope_in_response_assumes_requested() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::OK, token_json("mock_access", Some("mock_refresh"), None))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let check = svc
+            .exchange_code_and_save_token("code".into(), "verifier".into(), new_account(cred_id), "http://localhost:1421/oauth/callback")
+            .await
+            .unwrap()
+            .scopes;
+        assert!(!check.needs_reconsent);
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.scope.as_deref(), Some("mock.read"));
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_previous_scope_when_omitted() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::OK, token_json("new_access", None, None))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", Some("mock.read extra")).await;
+        let svc = service(&repo);
+
+        let (access_token, _) = svc.refresh_access_token(account_id).await.unwrap();
+        assert_eq!(access_token, "new_access");
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.scope.as_deref(), Some("mock.read extra"));
+        assert_eq!(token.refresh_token, "r1");
+    }
+
+    // Token endpoint that rotates refresh tokens like Google/Twitch: each refresh_token works once.
+    // Responses are delayed so concurrent callers overlap; `requests` counts refresh grants.
+    async fn spawn_rotating_token_endpoint(requests: Arc<AtomicUsize>) -> String {
+        let current = Arc::new(Mutex::new(1usize));
+        spawn_mock_endpoint(move |_path, form| {
+            std::thread::sleep(Duration::from_millis(200));
+            let n = requests.fetch_add(1, Ordering::SeqCst) + 1;
+            let mut current = current.lock().unwrap();
+            if form.get("refresh_token") != Some(&format!("r{}", *current)) {
+                return (StatusCode::BAD_REQUEST, error_json("invalid_grant", "refresh token already used"));
+            }
+            *current += 1;
+            (StatusCode::OK, token_json(&format!("access_{}", n), Some(&format!("r{}", *current)), None))
+        }).await
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_ensure_valid_access_token_shares_one_refresh() {
+        let (repo, _) = setup_repo().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = spawn_rotating_token_endpoint(requests.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        let callers: Vec<_> = (0..5)
+            .map(|_| {
+                let svc = svc.clone();
+                tokio::spawn(async move { svc.ensure_valid_access_token(account_id, 60).await })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for caller in callers {
+            results.push(caller.await.unwrap().unwrap());
+        }
+
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+        assert!(results.iter().all(|r| *r == results[0]));
+        assert_eq!(results[0].0, "access_1");
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.refresh_token, "r2");
+
+        // The token is fresh now, so a later call does not refresh again
+        svc.ensure_valid_access_token(account_id, 60).await.unwrap();
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_refresh_failure_is_shared_and_not_retried() {
+        let (repo, _) = setup_repo().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = spawn_rotating_token_endpoint(requests.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "revoked", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        let callers: Vec<_> = (0..3)
+            .map(|_| {
+                let svc = svc.clone();
+                tokio::spawn(async move { svc.refresh_access_token(account_id).await })
+            })
+            .collect();
+        // Waiters get the same typed error as the caller that ran the request
+        for caller in callers {
+            let err = caller.await.unwrap().unwrap_err();
+            assert_eq!(err.downcast_ref::<ReauthorizationRequired>().unwrap().account_id, account_id);
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn invalid_grant_marks_token_and_fails_fast_until_reauthorized() {
+        let (repo, _) = setup_repo().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        let base_url = spawn_mock_endpoint(move |path, form| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match (path, form.get("grant_type").map(String::as_str)) {
+                ("/token", Some("refresh_token")) => (StatusCode::BAD_REQUEST, error_json("invalid_grant", "Token has been expired or revoked.")),
+                ("/token", _) => (StatusCode::OK, token_json("new_access", Some("new_refresh"), None)),
+                _ => (StatusCode::NOT_FOUND, "{}".into()),
+            }
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        let err = svc.ensure_valid_access_token(account_id, 60).await.unwrap_err();
+        let reauth = err.downcast_ref::<ReauthorizationRequired>().unwrap();
+        assert_eq!(reauth.reason, "invalid_grant");
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.status, TokenStatus::NeedsReauth);
+        assert_eq!(token.last_error.as_deref(), Some("invalid_grant"));
+        let attention = svc.list_tokens_needing_attention().await.unwrap();
+        assert_eq!(attention.iter().map(|a| (a.credential_id, a.account_id)).collect::<Vec<_>>(), vec![(cred_id, account_id)]);
+
+        // Neither call reaches the provider again, even with a far-off skew that would allow the access token
+        assert!(svc.ensure_valid_access_token(account_id, 0).await.unwrap_err().downcast_ref::<ReauthorizationRequired>().is_some());
+        assert!(svc.refresh_access_token(account_id).await.unwrap_err().downcast_ref::<ReauthorizationRequired>().is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+
+        // Re-authenticating the account stores a healthy token again
+        let target = LinkTarget { credential_id: cred_id, account_id: Some(account_id) };
+        svc.exchange_code_and_save_token("code".into(), "verifier".into(), target, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert_eq!(only_token(&repo, cred_id).await.unwrap().status, TokenStatus::Active);
+        assert!(svc.list_tokens_needing_attention().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn transient_refresh_failure_is_recorded_without_reauth() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::SERVICE_UNAVAILABLE, error_json("temporarily_unavailable", "try again later"))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        let err = svc.refresh_access_token(account_id).await.unwrap_err();
+        assert!(err.downcast_ref::<ReauthorizationRequired>().is_none());
+        let error = AppError::from(err);
+        assert_eq!((error.code, error.retryable), (ErrorCode::ProviderError, true));
+        assert_eq!(error.details, Some(serde_json::json!({ "error": "temporarily_unavailable", "error_description": "try again later" })));
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.status, TokenStatus::Active);
+        assert_eq!(token.last_error.as_deref(), Some("temporarily_unavailable"));
+        assert_eq!(svc.list_tokens_needing_attention().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn sequential_refreshes_each_reach_the_provider() {
+        let (repo, _) = setup_repo().await;
+        let requests = Arc::new(AtomicUsize::new(0));
+        let base_url = spawn_rotating_token_endpoint(requests.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_token(&repo, cred_id, "r1", "2000-01-01 00:00:00", None).await;
+        let svc = service(&repo);
+
+        svc.refresh_access_token(account_id).await.unwrap();
+        let (access_token, _) = svc.refresh_access_token(account_id).await.unwrap();
+        assert_eq!(access_token, "access_2");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert_eq!(only_token(&repo, cred_id).await.unwrap().refresh_token, "r3");
+    }
+
+    #[tokio::test]
+    async fn exchange_uses_flow_redirect_and_refresh_sends_none() {
+        let (repo, _) = setup_repo().await;
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorder = seen.clone();
+        let base_url = spawn_mock_endpoint(move |path, form| {
+            if path == "/token" {
+                recorder.lock().unwrap().push((form.get("grant_type").cloned(), form.get("redirect_uri").cloned()));
+            }
+            (StatusCode::OK, token_json("at", Some("rt"), None))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let redirect = "http://localhost:1427/oauth/callback";
+        let linked = svc.exchange_code_and_save_token("code".into(), "verifier".into(), new_account(cred_id), redirect).await.unwrap();
+        svc.refresh_access_token(linked.account_id).await.unwrap();
+
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen[0], (Some("authorization_code".to_string()), Some(redirect.to_string())));
+        assert_eq!(seen[1], (Some("refresh_token".to_string()), None));
+    }
+
+    #[tokio::test]
+    async fn exchange_links_one_account_per_identity() {
+        let (repo, other_cred_id) = setup_repo().await;
+        let subject = Arc::new(Mutex::new("channel-a"));
+        let current = subject.clone();
+        let base_url = spawn_mock_endpoint(move |path, _form| match path {
+            "/userinfo" => {
+                let sub = *current.lock().unwrap();
+                let body = serde_json::json!({
+                    "sub": sub,
+                    "email": format!("{}@example.com", sub),
+                    "preferred_username": sub,
+                    "picture": "https://example.com/a.png",
+                });
+                (StatusCode::OK, body.to_string())
+            }
+            _ => (StatusCode::OK, token_json(*current.lock().unwrap(), Some("rt"), None)),
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+        let redirect = "http://localhost:1421/oauth/callback";
+        let link = |target: LinkTarget| svc.exchange_code_and_save_token("code".into(), "verifier".into(), target, redirect);
+
+        let linked = link(new_account(cred_id)).await.unwrap();
+        let main_id = linked.account_id;
+        let account = linked.account.unwrap();
+        assert_eq!(account.subject, "channel-a");
+        assert_eq!(account.email.as_deref(), Some("channel-a@example.com"));
+        assert_eq!(account.name.as_deref(), Some("channel-a"));
+        assert!(linked.warning.is_none());
+
+        // Signing in as the same account again refreshes it instead of adding another
+        let linked = link(new_account(cred_id)).await.unwrap();
+        assert_eq!(linked.account_id, main_id);
+
+        // A second channel becomes a second account with its own token
+        *subject.lock().unwrap() = "channel-b";
+        let sub_id = link(new_account(cred_id)).await.unwrap().account_id;
+        assert_ne!(sub_id, main_id);
+        let accounts = svc.list_accounts(cred_id).await.unwrap();
+        assert_eq!(accounts.iter().map(|a| a.subject.as_deref()).collect::<Vec<_>>(), vec![Some("channel-a"), Some("channel-b")]);
+        assert_eq!(repo.get_token_by_account_id(main_id).await.unwrap().unwrap().access_token, "channel-a");
+        assert_eq!(repo.get_token_by_account_id(sub_id).await.unwrap().unwrap().access_token, "channel-b");
+
+        // Re-authorizing the main account as an already linked channel refreshes that channel instead
+        let linked = link(LinkTarget { credential_id: cred_id, account_id: Some(main_id) }).await.unwrap();
+        assert_eq!(linked.account_id, sub_id);
+        assert_eq!(linked.warning, Some(LinkWarning::OtherLinkedAccount { requested_account_id: main_id }));
+        assert_eq!(repo.get_token_by_account_id(main_id).await.unwrap().unwrap().access_token, "channel-a");
+
+        // Re-authorizing it as a new channel replaces its identity and reports the previous one
+        *subject.lock().unwrap() = "channel-c";
+        let linked = link(LinkTarget { credential_id: cred_id, account_id: Some(main_id) }).await.unwrap();
+        assert_eq!(linked.account_id, main_id);
+        assert_eq!(linked.warning, Some(LinkWarning::IdentityChanged { previous: account }));
+        assert_eq!(svc.list_accounts(cred_id).await.unwrap().len(), 2);
+
+        // An account of another credential cannot be targeted
+        assert!(link(LinkTarget { credential_id: other_cred_id, account_id: Some(main_id) }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_identity_lookup_clears_stale_account() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|path, _form| match path {
+            "/userinfo" => (StatusCode::UNAUTHORIZED, error_json("invalid_token", "expired")),
+            _ => (StatusCode::OK, token_json("at", Some("rt"), None)),
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_refresh_token(&repo, cred_id).await;
+        let stale = AccountIdentity { subject: "old".into(), email: None, name: None, picture: None };
+        repo.update_account_identity(account_id, Some(&stale)).await.unwrap();
+        let svc = service(&repo);
+
+        let target = LinkTarget { credential_id: cred_id, account_id: Some(account_id) };
+        let linked = svc
+            .exchange_code_and_save_token("code".into(), "verifier".into(), target, "http://localhost:1421/oauth/callback")
+            .await
+            .unwrap();
+        assert_eq!(linked.account_id, account_id);
+        assert!(linked.account.is_none());
+        assert!(linked.warning.is_none());
+        assert!(repo.get_account_by_id(account_id).await.unwrap().unwrap().identity().is_none());
+    }
+
+    async fn add_refresh_token(repo: &Arc<SqliteRepository>, cred_id: i64) -> i64 {
+        add_token(repo, cred_id, "r1", "2099-12-31 23:59:59", Some("mock.read")).await
+    }
+
+    #[tokio::test]
+    async fn revoke_token_calls_provider_and_removes_token() {
+        let (repo, _) = setup_repo().await;
+        let seen = Arc::new(Mutex::new(None));
+        let recorder = seen.clone();
+        let base_url = spawn_mock_endpoint(move |path, form| {
+            *recorder.lock().unwrap() = Some((path.to_string(), form));
+            (StatusCode::OK, String::new())
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_refresh_token(&repo, cred_id).await;
+
+        let outcome = service(&repo).revoke_token(account_id).await.unwrap();
+        assert_eq!(outcome, RevocationOutcome {
+            account_id,
+            revoked_at_provider: true,
+            local_token_removed: true,
+            provider_error: None,
+            error: None,
+        });
+        let (path, form) = seen.lock().unwrap().clone().unwrap();
+        assert_eq!(path, "/revoke");
+        assert_eq!(form.get("token").map(String::as_str), Some("r1"));
+        assert_eq!(form.get("token_type_hint").map(String::as_str), Some("refresh_token"));
+        assert!(only_token(&repo, cred_id).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn revoke_token_removes_local_token_when_provider_fails() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::SERVICE_UNAVAILABLE, error_json("temporarily_unavailable", "try later"))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let account_id = add_refresh_token(&repo, cred_id).await;
+        let svc = service(&repo);
+
+        let outcome = svc.revoke_token(account_id).await.unwrap();
+        assert!(!outcome.revoked_at_provider);
+        assert!(outcome.local_token_removed);
+        let error = outcome.provider_error.unwrap();
+        assert_eq!((error.code, error.retryable), (ErrorCode::ProviderError, true));
+        assert_eq!(error.details.unwrap()["error"], "temporarily_unavailable");
+        assert!(only_token(&repo, cred_id).await.is_none());
+
+        // Nothing left to unlink
+        assert!(repo.get_account_by_id(account_id).await.unwrap().is_none());
+        assert_eq!(app_error(svc.revoke_token(account_id).await).code, ErrorCode::NotFound);
+    }
+
+    #[tokio::test]
+    async fn revoking_credential_accounts_continues_past_a_failing_account() {
+        let pool: SqlitePool = init_test_db().await.unwrap();
+        let repo = Arc::new(SqliteRepository::new(pool.clone(), test_vault()));
+        let base_url = spawn_mock_endpoint(|_path, _form| (StatusCode::OK, String::new())).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let broken = add_refresh_token(&repo, cred_id).await;
+        let healthy = add_refresh_token(&repo, cred_id).await;
+        // The first account's token can no longer be decrypted
+        sqlx::query("UPDATE oauth_tokens SET refresh_token = 'enc:v1:AAAA' WHERE account_id = ?")
+            .bind(broken)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let outcomes = service(&repo).revoke_credential_accounts(cred_id).await.unwrap();
+        assert_eq!(outcomes.iter().map(|o| o.account_id).collect::<Vec<_>>(), vec![broken, healthy]);
+        assert!(outcomes[0].error.is_some());
+        assert!(!outcomes[0].revoked_at_provider && !outcomes[0].local_token_removed);
+        assert!(repo.get_account_by_id(broken).await.unwrap().is_some());
+        assert_eq!((outcomes[1].revoked_at_provider, outcomes[1].error.as_ref()), (true, None));
+        assert!(repo.get_account_by_id(healthy).await.unwrap().is_none());
+    }
+
+    // Device + token endpoints: the token endpoint answers from `replies` in order and records when it was polled
+    async fn spawn_device_endpoints(
+        replies: Vec<(StatusCode, String)>,
+        polled_at: Arc<Mutex<Vec<tokio::time::Instant>>>,
+    ) -> String {
+        let replies = Mutex::new(replies.into_iter());
+        spawn_mock_endpoint(move |path, form| match path {
+            "/device" => {
+                assert_eq!(form.get("scope").map(String::as_str), Some("mock.read"));
+                let body = serde_json::json!({
+                    "device_code": "dev-code",
+                    "user_code": "ABCD-EFGH",
+                    "verification_url": "https://idp.example.com/activate",
+                    "expires_in": 1800,
+                    "interval": 1,
+                });
+                (StatusCode::OK, body.to_string())
+            }
+            _ => {
+                assert_eq!(form.get("grant_type").map(String::as_str), Some(DEVICE_CODE_GRANT_TYPE));
+                assert_eq!(form.get("device_code").map(String::as_str), Some("dev-code"));
+                polled_at.lock().unwrap().push(tokio::time::Instant::now());
+                replies.lock().unwrap().next().expect("unexpected poll")
+            }
+        }).await
+    }
+
+    #[tokio::test]
+    async fn device_flow_polls_with_pending_and_slow_down() {
+        let (repo, _) = setup_repo().await;
+        let polled_at = Arc::new(Mutex::new(Vec::new()));
+        let base_url = spawn_device_endpoints(vec![
+            (StatusCode::BAD_REQUEST, error_json("authorization_pending", "waiting")),
+            (StatusCode::BAD_REQUEST, error_json("slow_down", "too fast")),
+            (StatusCode::OK, token_json("device_access", Some("device_refresh"), Some("mock.read"))),
+        ], polled_at.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let device = svc.request_device_code(cred_id).await.unwrap();
+        assert_eq!(device.user_code, "ABCD-EFGH");
+        assert_eq!(device.verification_uri, "https://idp.example.com/activate");
+
+        // Poll with 10ms standing in for one second
+        let unit = Duration::from_millis(10);
+        let (credential, provider) = svc.load_credential_and_provider(cred_id).await.unwrap();
+        let started = tokio::time::Instant::now();
+        let grant = poll_token_endpoint(&credential, &provider, &device, unit).await.unwrap();
+        svc.save_token_grant(new_account(cred_id), grant).await.unwrap();
+
+        // 1 unit, 1 unit, then 1 + 5 units after slow_down
+        let polled_at = polled_at.lock().unwrap().clone();
+        assert_eq!(polled_at.len(), 3);
+        assert!(polled_at[0] - started >= unit);
+        assert!(polled_at[1] - polled_at[0] >= unit);
+        assert!(polled_at[2] - polled_at[1] >= unit * 6);
+        let token = only_token(&repo, cred_id).await.unwrap();
+        assert_eq!(token.access_token, "device_access");
+        assert_eq!(token.refresh_token, "device_refresh");
+    }
+
+    #[tokio::test]
+    async fn device_flow_reports_access_denied() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_device_endpoints(vec![
+            (StatusCode::BAD_REQUEST, error_json("access_denied", "user declined")),
+        ], Arc::new(Mutex::new(Vec::new()))).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let device = svc.request_device_code(cred_id).await.unwrap();
+        let (credential, provider) = svc.load_credential_and_provider(cred_id).await.unwrap();
+        let err = poll_token_endpoint(&credential, &provider, &device, Duration::from_millis(10)).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<DeviceFlowError>().unwrap().error, "access_denied");
+    }
+
+    #[tokio::test]
+    async fn device_flow_rejects_out_of_range_timing() {
+        let (repo, _) = setup_repo().await;
+        let polled_at = Arc::new(Mutex::new(Vec::new()));
+        let base_url = spawn_device_endpoints(Vec::new(), polled_at.clone()).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let device = svc.request_device_code(cred_id).await.unwrap();
+        let (credential, provider) = svc.load_credential_and_provider(cred_id).await.unwrap();
+        for device in [
+            DeviceAuthorization { expires_in: u64::from(u32::MAX) + 1, ..device.clone() },
+            DeviceAuthorization { interval: u64::MAX, ..device },
+        ] {
+            let err = poll_token_endpoint(&credential, &provider, &device, Duration::from_millis(10)).await.unwrap_err();
+            assert_eq!(AppError::from(err).code, ErrorCode::ProviderError);
+        }
+        assert!(polled_at.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn device_code_with_out_of_range_expiry_is_rejected() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            let body = serde_json::json!({
+                "device_code": "dev-code",
+                "user_code": "ABCD-EFGH",
+                "verification_uri": "https://idp.example.com/activate",
+                "expires_in": u64::MAX,
+            });
+            (StatusCode::OK, body.to_string())
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let err = service(&repo).request_device_code(cred_id).await.unwrap_err();
+        assert_eq!(AppError::from(err).code, ErrorCode::ProviderError);
+    }
+
+    #[tokio::test]
+    async fn check_token_scopes_reports_missing_feature_scope() {
+        let (repo, cred_id) = setup_repo().await;
+        let account_id = add_token(
+            &repo,
+            cred_id,
+            "r1",
+            "2099-12-31 23:59:59",
+            Some("https://www.googleapis.com/auth/youtube https://www.googleapis.com/auth/userinfo.email"),
+        ).await;
+        let svc = service(&repo);
+
+        let force_ssl = "https://www.googleapis.com/auth/youtube.force-ssl".to_string();
+        let check = svc.check_token_scopes(account_id, vec![force_ssl.clone()]).await.unwrap();
+        assert!(check.needs_reconsent);
+        assert_eq!(check.missing, vec![force_ssl]);
+
+        let check = svc
+            .check_token_scopes(account_id, vec!["https://www.googleapis.com/auth/youtube".into()])
+            .await
+            .unwrap();
+        assert!(!check.needs_reconsent);
+    }
+
+    #[tokio::test]
+    async fn generate_auth_url_requests_offline_access_with_credential_overrides() {
+        let (repo, cred_id) = setup_repo().await;
+        let svc = service(&repo);
+
+        let req = svc.generate_auth_url(cred_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert_eq!(query_param(&req.auth_url, "access_type").as_deref(), Some("offline"));
+        assert_eq!(query_param(&req.auth_url, "prompt").as_deref(), Some("consent"));
+        assert_eq!(query_param(&req.auth_url, "include_granted_scopes").as_deref(), Some("true"));
+        assert_eq!(query_param(&req.auth_url, "login_hint"), None);
+
+        repo.update_credential_auth_params(
+            cred_id,
+            Some(r#"{"prompt":"select_account consent","login_hint":"main@example.com","include_granted_scopes":""}"#.into()),
+        ).await.unwrap();
+
+        let req = svc.generate_auth_url(cred_id, "http://localhost:1421/oauth/callback").await.unwrap();
+        assert_eq!(query_param(&req.auth_url, "access_type").as_deref(), Some("offline"));
+        assert_eq!(query_param(&req.auth_url, "prompt").as_deref(), Some("select_account consent"));
+        assert_eq!(query_param(&req.auth_url, "login_hint").as_deref(), Some("main@example.com"));
+        assert_eq!(query_param(&req.auth_url, "include_granted_scopes"), None);
+    }
+
+    #[tokio::test]
+    async fn exchange_without_required_refresh_token_is_distinct_error() {
+        let (repo, _) = setup_repo().await;
+        let base_url = spawn_mock_endpoint(|_path, _form| {
+            (StatusCode::OK, token_json("mock_access", None, None))
+        }).await;
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        let svc = service(&repo);
+
+        let err = svc
+            .exchange_code_and_save_token("code".into(), "verifier".into(), new_account(cred_id), "http://localhost:1421/oauth/callback")
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<MissingRefreshToken>().is_some());
+        assert!(only_token(&repo, cred_id).await.is_none());
     }
 }
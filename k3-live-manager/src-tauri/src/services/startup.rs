@@ -0,0 +1,113 @@
+use crate::error::{AppError, ErrorCode};
+use crate::services::events::{self, EventSink, APP_STATUS_EVENT};
+use serde::Serialize;
+use std::sync::{Arc, Mutex, OnceLock};
+
+// Startup phase reported to the UI. Also the payload of every `app-status` event.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AppStatus {
+    Initializing,
+    Ready,
+    Failed { error: AppError },
+}
+
+// Holds the app state once initialization finishes. Managed before init starts, so commands
+// can always reach it and get a defined error instead of a missing-state panic while it runs.
+pub struct Startup<T> {
+    status: Mutex<AppStatus>,
+    state: OnceLock<T>,
+    events: Arc<dyn EventSink>,
+}
+
+impl<T> Startup<T> {
+    pub fn new(events: Arc<dyn EventSink>) -> Self {
+        Self { status: Mutex::new(AppStatus::Initializing), state: OnceLock::new(), events }
+    }
+
+    pub fn status(&self) -> AppStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    // The initialized state, or `not_ready` (retryable) / `startup_failed` while it is unavailable
+    pub fn state(&self) -> Result<&T, AppError> {
+        if let Some(state) = self.state.get() {
+            return Ok(state);
+        }
+        Err(match self.status() {
+            AppStatus::Failed { error } => AppError::new(ErrorCode::StartupFailed, format!("The app failed to start: {}", error.message)),
+            _ => AppError::new(ErrorCode::NotReady, "The app is still starting").retryable(true),
+        })
+    }
+
+    // Publish the initialized state. Only the first outcome counts.
+    pub fn ready(&self, state: T) {
+        self.transition(AppStatus::Ready, || {
+            let _ = self.state.set(state);
+        });
+    }
+
+    pub fn fail(&self, error: AppError) {
+        self.transition(AppStatus::Failed { error }, || {});
+    }
+
+    fn transition(&self, status: AppStatus, apply: impl FnOnce()) {
+        {
+            let mut current = self.status.lock().unwrap();
+            if *current != AppStatus::Initializing {
+                return;
+            }
+            apply();
+            *current = status.clone();
+        }
+        events::emit(self.events.as_ref(), APP_STATUS_EVENT, &status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::RecordingEventSink;
+
+    fn startup() -> (Startup<&'static str>, Arc<RecordingEventSink>) {
+        let sink = Arc::new(RecordingEventSink::default());
+        (Startup::new(sink.clone()), sink)
+    }
+
+    #[test]
+    fn state_is_unavailable_until_ready() {
+        let (startup, sink) = startup();
+        assert_eq!(startup.status(), AppStatus::Initializing);
+        let error = startup.state().unwrap_err();
+        assert_eq!((error.code, error.retryable), (ErrorCode::NotReady, true));
+
+        startup.ready("state");
+        assert_eq!(startup.state(), Ok(&"state"));
+        assert_eq!(sink.payloads(APP_STATUS_EVENT), vec![serde_json::json!({ "status": "ready" })]);
+
+        // A late failure does not hide a working app
+        startup.fail(AppError::new(ErrorCode::Internal, "late"));
+        assert_eq!(startup.status(), AppStatus::Ready);
+    }
+
+    #[test]
+    fn failure_is_reported_to_commands_and_events() {
+        let (startup, sink) = startup();
+        startup.fail(AppError::new(ErrorCode::Database, "migration 20250908000001 failed"));
+
+        let error = startup.state().unwrap_err();
+        assert_eq!((error.code, error.retryable), (ErrorCode::StartupFailed, false));
+        assert_eq!(error.message, "The app failed to start: migration 20250908000001 failed");
+        assert_eq!(
+            sink.payloads(APP_STATUS_EVENT),
+            vec![serde_json::json!({
+                "status": "failed",
+                "error": { "code": "database", "message": "migration 20250908000001 failed", "retryable": false },
+            })]
+        );
+
+        startup.ready("state");
+        assert!(startup.state().is_err());
+        assert_eq!(sink.payloads(APP_STATUS_EVENT).len(), 1);
+    }
+}
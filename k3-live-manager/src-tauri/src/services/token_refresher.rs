@@ -0,0 +1,418 @@
+use crate::db::encryption::DatabaseLocked;
+use crate::db::models::TokenStatus;
+use crate::db::repositories::TokenRepository;
+use crate::error::AppError;
+use crate::services::events::{self, EventSink, TOKEN_REFRESH_EVENT};
+use crate::services::oauth_service::{OAuthService, ReauthorizationRequired};
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+// Tokens are refreshed this long before they expire
+pub const DEFAULT_REFRESH_MARGIN_SECS: u64 = 300;
+
+// Overrides the refresh margin in seconds, e.g. `600`
+pub const REFRESH_MARGIN_ENV: &str = "K3_TOKEN_REFRESH_MARGIN_SECS";
+
+// How long app exit waits for a refresh that is already in flight
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Timing of the background refresh loop
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshSchedule {
+    // Refresh this long before `expires_at`
+    pub margin: Duration,
+    // Longest sleep between scans, so accounts linked in the meantime are picked up
+    pub max_sleep: Duration,
+    // Retry delay after the first failure, doubled per consecutive failure up to `max_backoff`.
+    // Also the shortest gap between two refreshes of the same account.
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RefreshSchedule {
+    fn default() -> Self {
+        Self {
+            margin: Duration::from_secs(DEFAULT_REFRESH_MARGIN_SECS),
+            max_sleep: Duration::from_secs(60),
+            min_backoff: Duration::from_secs(30),
+            max_backoff: Duration::from_secs(30 * 60),
+        }
+    }
+}
+
+impl RefreshSchedule {
+    // Default schedule with the margin from `K3_TOKEN_REFRESH_MARGIN_SECS` when set and valid
+    pub fn from_env() -> Self {
+        let mut schedule = Self::default();
+        if let Ok(value) = std::env::var(REFRESH_MARGIN_ENV) {
+            match value.trim().parse::<u64>() {
+                Ok(secs) => schedule.margin = Duration::from_secs(secs),
+                Err(e) => eprintln!("Ignoring {}: {}", REFRESH_MARGIN_ENV, e),
+            }
+        }
+        schedule
+    }
+
+    fn backoff(&self, consecutive_failures: u32) -> Duration {
+        let factor = 2u32.saturating_pow(consecutive_failures.saturating_sub(1));
+        self.min_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshOutcome {
+    Refreshed,
+    Failed,
+    // invalid_grant: not retried until the account is authorized again
+    NeedsReauth,
+}
+
+// Payload of every `token-refresh` event
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct TokenRefreshStatus {
+    pub account_id: i64,
+    pub status: RefreshOutcome,
+    // New expiry after a successful refresh
+    pub expires_at: Option<String>,
+    // Same model as command errors; the full chain is only logged
+    pub error: Option<AppError>,
+    pub consecutive_failures: u32,
+    // Delay before the next attempt after a failure
+    pub retry_in_secs: Option<u64>,
+}
+
+// Retry state of one account
+#[derive(Default)]
+struct AccountBackoff {
+    consecutive_failures: u32,
+    not_before: Option<DateTime<Utc>>,
+}
+
+// `expires_at` is stored as UTC "YYYY-MM-DD HH:MM:SS"; None when it cannot be parsed
+fn parse_expires_at(expires_at: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(expires_at, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|ndt| Utc.from_utc_datetime(&ndt))
+}
+
+fn chrono_duration(duration: Duration) -> ChronoDuration {
+    ChronoDuration::from_std(duration).unwrap_or_else(|_| ChronoDuration::zero())
+}
+
+// Background task that refreshes every stored token a margin before it expires.
+// Each outcome is emitted as a `token-refresh` event.
+pub struct TokenRefreshScheduler {
+    cancel: CancellationToken,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl TokenRefreshScheduler {
+    pub fn start(
+        oauth_service: OAuthService,
+        token_repo: Arc<dyn TokenRepository + Send + Sync>,
+        events: Arc<dyn EventSink>,
+        schedule: RefreshSchedule,
+    ) -> Self {
+        let cancel = CancellationToken::new();
+        let worker = RefreshWorker {
+            oauth_service,
+            token_repo,
+            events,
+            schedule,
+            backoff: HashMap::new(),
+        };
+        let task = tokio::spawn(worker.run(cancel.clone()));
+        Self { cancel, task: Mutex::new(Some(task)) }
+    }
+
+    // Stop the loop. A refresh already in flight may finish so its token is saved.
+    pub async fn shutdown(&self) {
+        self.cancel.cancel();
+        let task = self.task.lock().unwrap().take();
+        if let Some(task) = task {
+            if tokio::time::timeout(SHUTDOWN_TIMEOUT, task).await.is_err() {
+                eprintln!("Token refresh scheduler did not stop within {:?}", SHUTDOWN_TIMEOUT);
+            }
+        }
+    }
+}
+
+struct RefreshWorker {
+    oauth_service: OAuthService,
+    token_repo: Arc<dyn TokenRepository + Send + Sync>,
+    events: Arc<dyn EventSink>,
+    schedule: RefreshSchedule,
+    backoff: HashMap<i64, AccountBackoff>,
+}
+
+impl RefreshWorker {
+    async fn run(mut self, cancel: CancellationToken) {
+        loop {
+            let sleep = self.refresh_due_tokens(&cancel).await;
+            tokio::select! {
+                _ = tokio::time::sleep(sleep) => {}
+                _ = cancel.cancelled() => break,
+            }
+        }
+        println!("Token refresh scheduler stopped.");
+    }
+
+    // Refresh every token that is due and return how long to sleep until the next one is
+    async fn refresh_due_tokens(&mut self, cancel: &CancellationToken) -> Duration {
+        let tokens = match self.token_repo.get_all_tokens().await {
+            Ok(tokens) => tokens,
+            // Nothing to do until the user unlocks the database
+            Err(e) if e.is::<DatabaseLocked>() => return self.schedule.max_sleep,
+            Err(e) => {
+                eprintln!("Failed to load tokens for background refresh: {:#}", e);
+                return self.schedule.max_sleep;
+            }
+        };
+        // Forget unlinked accounts
+        self.backoff.retain(|account_id, _| tokens.iter().any(|t| t.account_id == *account_id));
+
+        let margin = chrono_duration(self.schedule.margin);
+        let mut next_wake = Utc::now() + chrono_duration(self.schedule.max_sleep);
+        for token in tokens {
+            if cancel.is_cancelled() {
+                break;
+            }
+            // Tokens without a refresh token cannot be renewed in the background
+            if token.refresh_token.is_empty() || token.refresh_token == "no_refresh_token" {
+                continue;
+            }
+            // Rejected refresh tokens wait for the user to re-authenticate
+            if token.status == TokenStatus::NeedsReauth {
+                continue;
+            }
+
+            // An unparsable expiry is treated as expired, like `ensure_valid_access_token` does
+            let mut due = parse_expires_at(&token.expires_at).map_or_else(Utc::now, |exp| exp - margin);
+            if let Some(not_before) = self.backoff.get(&token.account_id).and_then(|b| b.not_before) {
+                due = due.max(not_before);
+            }
+            if due > Utc::now() {
+                next_wake = next_wake.min(due);
+                continue;
+            }
+
+            if let Some(not_before) = self.refresh(token.account_id).await {
+                next_wake = next_wake.min(not_before);
+            }
+        }
+        (next_wake - Utc::now()).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    // Refresh one account and record when it may be tried again
+    async fn refresh(&mut self, account_id: i64) -> Option<DateTime<Utc>> {
+        let result = self.oauth_service.refresh_access_token(account_id).await;
+        let state = self.backoff.entry(account_id).or_default();
+        let status = match result {
+            Ok((_, expires_at)) => {
+                println!("Refreshed token for account_id={} in the background", account_id);
+                state.consecutive_failures = 0;
+                state.not_before = Some(Utc::now() + chrono_duration(self.schedule.min_backoff));
+                TokenRefreshStatus {
+                    account_id,
+                    status: RefreshOutcome::Refreshed,
+                    expires_at: Some(expires_at),
+                    error: None,
+                    consecutive_failures: 0,
+                    retry_in_secs: None,
+                }
+            }
+            Err(e) if e.downcast_ref::<ReauthorizationRequired>().is_some() => {
+                // Skipped by later scans while the token is `needs_reauth`
+                self.backoff.remove(&account_id);
+                let status = TokenRefreshStatus {
+                    account_id,
+                    status: RefreshOutcome::NeedsReauth,
+                    expires_at: None,
+                    error: Some(AppError::from(&e)),
+                    consecutive_failures: 0,
+                    retry_in_secs: None,
+                };
+                events::emit(self.events.as_ref(), TOKEN_REFRESH_EVENT, &status);
+                return None;
+            }
+            Err(e) => {
+                state.consecutive_failures += 1;
+                let retry_in = self.schedule.backoff(state.consecutive_failures);
+                state.not_before = Some(Utc::now() + chrono_duration(retry_in));
+                eprintln!(
+                    "Background refresh failed for account_id={} ({} in a row, retrying in {:?}): {:#}",
+                    account_id, state.consecutive_failures, retry_in, e
+                );
+                TokenRefreshStatus {
+                    account_id,
+                    status: RefreshOutcome::Failed,
+                    expires_at: None,
+                    error: Some(AppError::from(&e)),
+                    consecutive_failures: state.consecutive_failures,
+                    retry_in_secs: Some(retry_in.as_secs()),
+                }
+            }
+        };
+        events::emit(self.events.as_ref(), TOKEN_REFRESH_EVENT, &status);
+        state.not_before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::repositories::SqliteRepository;
+    use crate::db::setup::{init_test_db, test_vault};
+    use crate::error::ErrorCode;
+    use crate::services::oauth_provider::ProviderRegistry;
+    use crate::test_support::{add_token, error_json, mock_provider_credential, spawn_mock_endpoint, token_json, RecordingEventSink};
+    use hyper::StatusCode;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn fast_schedule() -> RefreshSchedule {
+        RefreshSchedule {
+            margin: Duration::from_secs(300),
+            max_sleep: Duration::from_millis(20),
+            min_backoff: Duration::from_millis(30),
+            max_backoff: Duration::from_millis(60),
+        }
+    }
+
+    // Repository with a credential on a mock provider whose token endpoint answers with `token_response`
+    async fn mock_repo(token_response: (StatusCode, String), polls: Arc<AtomicUsize>) -> (Arc<SqliteRepository>, i64) {
+        let base_url = spawn_mock_endpoint(move |_path, _form| {
+            polls.fetch_add(1, Ordering::SeqCst);
+            token_response.clone()
+        }).await;
+        let repo = Arc::new(SqliteRepository::new(init_test_db().await.unwrap(), test_vault()));
+        let cred_id = mock_provider_credential(&repo, &base_url, &["mock.read"]).await;
+        (repo, cred_id)
+    }
+
+    fn expires_in(secs: i64) -> String {
+        (Utc::now() + ChronoDuration::seconds(secs)).format("%Y-%m-%d %H:%M:%S").to_string()
+    }
+
+    fn start(repo: &Arc<SqliteRepository>, sink: &Arc<RecordingEventSink>, schedule: RefreshSchedule) -> TokenRefreshScheduler {
+        let service = OAuthService::new(repo.clone(), repo.clone(), repo.clone(), ProviderRegistry::new(repo.clone()));
+        TokenRefreshScheduler::start(service, repo.clone(), sink.clone(), schedule)
+    }
+
+    async fn wait_for_events(sink: &RecordingEventSink, count: usize) -> Vec<TokenRefreshStatus> {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let events = sink.payloads(TOKEN_REFRESH_EVENT);
+                if events.len() >= count {
+                    return events.into_iter().map(|e| serde_json::from_value(e).unwrap()).collect();
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("expected token-refresh events")
+    }
+
+    #[tokio::test]
+    async fn refreshes_only_tokens_within_the_margin() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (repo, cred_id) = mock_repo((StatusCode::OK, token_json("new_access", None, None)), polls.clone()).await;
+        let expiring = add_token(&repo, cred_id, "r1", &expires_in(60), Some("mock.read")).await;
+        let fresh = add_token(&repo, cred_id, "r2", &expires_in(3600), Some("mock.read")).await;
+        let no_refresh_token = add_token(&repo, cred_id, "", &expires_in(-60), Some("mock.read")).await;
+        let sink = Arc::new(RecordingEventSink::default());
+
+        let scheduler = start(&repo, &sink, fast_schedule());
+        let events = wait_for_events(&sink, 1).await;
+        // Several more scans happen without refreshing anything else
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        scheduler.shutdown().await;
+
+        assert_eq!(events[0].account_id, expiring);
+        assert_eq!(events[0].status, RefreshOutcome::Refreshed);
+        assert!(events[0].expires_at.is_some());
+        assert_eq!(sink.payloads(TOKEN_REFRESH_EVENT).len(), 1);
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        assert_eq!(repo.get_token_by_account_id(expiring).await.unwrap().unwrap().access_token, "new_access");
+        assert_eq!(repo.get_token_by_account_id(fresh).await.unwrap().unwrap().access_token, "old_access");
+        assert_eq!(repo.get_token_by_account_id(no_refresh_token).await.unwrap().unwrap().access_token, "old_access");
+    }
+
+    #[tokio::test]
+    async fn failed_refresh_backs_off_and_reports_each_attempt() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let response = (StatusCode::SERVICE_UNAVAILABLE, error_json("temporarily_unavailable", "try again later"));
+        let (repo, cred_id) = mock_repo(response, polls.clone()).await;
+        let account_id = add_token(&repo, cred_id, "r1", &expires_in(-60), Some("mock.read")).await;
+        let sink = Arc::new(RecordingEventSink::default());
+
+        let scheduler = start(&repo, &sink, fast_schedule());
+        let events = wait_for_events(&sink, 3).await;
+        scheduler.shutdown().await;
+
+        assert!(events.iter().all(|e| e.account_id == account_id && e.status == RefreshOutcome::Failed));
+        let error = events[0].error.as_ref().unwrap();
+        assert_eq!((error.code, error.retryable), (ErrorCode::ProviderError, true));
+        assert_eq!(error.details.as_ref().unwrap()["error"], "temporarily_unavailable");
+        assert_eq!(events.iter().take(3).map(|e| e.consecutive_failures).collect::<Vec<_>>(), vec![1, 2, 3]);
+        // Transient failures are recorded but keep the token active
+        let token = repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+        assert_eq!(token.status, TokenStatus::Active);
+        assert!(token.last_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn invalid_grant_marks_token_and_stops_retrying() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let response = (StatusCode::BAD_REQUEST, error_json("invalid_grant", "Token has been expired or revoked."));
+        let (repo, cred_id) = mock_repo(response, polls.clone()).await;
+        let account_id = add_token(&repo, cred_id, "revoked", &expires_in(-60), Some("mock.read")).await;
+        let sink = Arc::new(RecordingEventSink::default());
+
+        let scheduler = start(&repo, &sink, fast_schedule());
+        let events = wait_for_events(&sink, 1).await;
+        // Later scans skip the token instead of backing off and retrying
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        scheduler.shutdown().await;
+
+        assert_eq!(events[0].account_id, account_id);
+        assert_eq!(events[0].status, RefreshOutcome::NeedsReauth);
+        let error = events[0].error.as_ref().unwrap();
+        assert_eq!((error.code, error.message.as_str()), (ErrorCode::NeedsReauth, "This account needs to be re-authorized"));
+        assert_eq!(sink.payloads(TOKEN_REFRESH_EVENT).len(), 1);
+        assert_eq!(polls.load(Ordering::SeqCst), 1);
+        assert_eq!(repo.get_token_by_account_id(account_id).await.unwrap().unwrap().status, TokenStatus::NeedsReauth);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let schedule = RefreshSchedule::default();
+        assert_eq!(schedule.backoff(1), Duration::from_secs(30));
+        assert_eq!(schedule.backoff(2), Duration::from_secs(60));
+        assert_eq!(schedule.backoff(4), Duration::from_secs(240));
+        assert_eq!(schedule.backoff(10), Duration::from_secs(30 * 60));
+        assert_eq!(schedule.backoff(u32::MAX), Duration::from_secs(30 * 60));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_loop() {
+        let polls = Arc::new(AtomicUsize::new(0));
+        let (repo, cred_id) = mock_repo((StatusCode::OK, token_json("new_access", None, None)), polls.clone()).await;
+        let sink = Arc::new(RecordingEventSink::default());
+        let scheduler = start(&repo, &sink, fast_schedule());
+
+        tokio::time::timeout(Duration::from_secs(1), scheduler.shutdown())
+            .await
+            .expect("shutdown should not hang");
+        // Tokens that become due afterwards are left alone
+        add_token(&repo, cred_id, "r1", &expires_in(-60), Some("mock.read")).await;
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(polls.load(Ordering::SeqCst), 0);
+        assert!(sink.payloads(TOKEN_REFRESH_EVENT).is_empty());
+    }
+}
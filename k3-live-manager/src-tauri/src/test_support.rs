@@ -0,0 +1,156 @@
+// Test-only helpers shared by the service unit tests
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::db::models::{AddCredentialPayload, AddOAuthProviderPayload, AddTokenPayload};
+use crate::db::repositories::{AccountRepository, CredentialRepository, ProviderRepository, SqliteRepository, TokenRepository};
+use crate::services::events::EventSink;
+
+// Collects emitted events so tests can assert on their order and payloads
+#[derive(Default)]
+pub struct RecordingEventSink {
+    pub events: Mutex<Vec<(String, serde_json::Value)>>,
+}
+
+impl RecordingEventSink {
+    pub fn payloads(&self, event: &str) -> Vec<serde_json::Value> {
+        self.events.lock().unwrap().iter().filter(|(name, _)| name == event).map(|(_, p)| p.clone()).collect()
+    }
+}
+
+impl EventSink for RecordingEventSink {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) {
+        self.events.lock().unwrap().push((event.to_string(), payload));
+    }
+}
+
+// Fresh directory under the system temp dir, removed when dropped. `name` must be unique per test.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("k3-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+// Starts a local stand-in for provider endpoints (token, revoke, ...) on an ephemeral port.
+// `handler` receives the request path and the form-encoded body and returns (status, JSON body).
+// Returns the base URL, e.g. `http://127.0.0.1:54321`.
+pub async fn spawn_mock_endpoint<F>(handler: F) -> String
+where
+    F: Fn(&str, HashMap<String, String>) -> (StatusCode, String) + Send + Sync + 'static,
+{
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    let handler = handler.clone();
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        let form: HashMap<String, String> = url::form_urlencoded::parse(&body).into_owned().collect();
+                        let (status, json) = handler(&path, form);
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .header("Content-Type", "application/json")
+                                .body(Full::new(Bytes::from(json)))
+                                .unwrap(),
+                        )
+                    }
+                });
+                let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+            });
+        }
+    });
+    format!("http://{}", addr)
+}
+
+// Standard successful token response body
+pub fn token_json(access_token: &str, refresh_token: Option<&str>, scope: Option<&str>) -> String {
+    let mut body = serde_json::json!({
+        "access_token": access_token,
+        "token_type": "bearer",
+        "expires_in": 3600,
+    });
+    if let Some(refresh_token) = refresh_token {
+        body["refresh_token"] = refresh_token.into();
+    }
+    if let Some(scope) = scope {
+        body["scope"] = scope.into();
+    }
+    body.to_string()
+}
+
+// RFC 6749 error response body
+pub fn error_json(error: &str, description: &str) -> String {
+    serde_json::json!({ "error": error, "error_description": description }).to_string()
+}
+
+// Register a custom provider whose endpoints all live under `base_url` (see `spawn_mock_endpoint`)
+// and a credential bound to it. Returns the credential id.
+pub async fn mock_provider_credential(repo: &Arc<SqliteRepository>, base_url: &str, default_scopes: &[&str]) -> i64 {
+    repo.add_provider(AddOAuthProviderPayload {
+        provider_key: "mock".into(),
+        display_name: "Mock IdP".into(),
+        auth_url: format!("{}/authorize", base_url),
+        token_url: format!("{}/token", base_url),
+        revoke_url: Some(format!("{}/revoke", base_url)),
+        default_scopes: default_scopes.iter().map(|s| s.to_string()).collect(),
+        extra_params: [("audience".to_string(), "mock-api".to_string())].into_iter().collect(),
+        client_secret_in_body: false,
+        requires_refresh_token: true,
+        device_authorization_url: Some(format!("{}/device", base_url)),
+        userinfo_url: Some(format!("{}/userinfo", base_url)),
+    }).await.unwrap();
+    repo.add_credential(AddCredentialPayload {
+        service_name: "mock".into(),
+        client_id: "mock_cid".into(),
+        client_secret: "mock_csec".into(),
+        provider: "mock".into(),
+        scopes: None,
+        auth_params: None,
+    }).await.unwrap().id
+}
+
+// Link an account to the credential with a stored token (access token `old_access`); returns the account id
+pub async fn add_token(repo: &Arc<SqliteRepository>, cred_id: i64, refresh_token: &str, expires_at: &str, scope: Option<&str>) -> i64 {
+    let account = repo.add_account(cred_id, None).await.unwrap();
+    repo.upsert_token(AddTokenPayload {
+        account_id: account.id,
+        access_token: "old_access".into(),
+        refresh_token: refresh_token.into(),
+        expires_at: expires_at.into(),
+        scope: scope.map(str::to_string),
+    }).await.unwrap();
+    account.id
+}
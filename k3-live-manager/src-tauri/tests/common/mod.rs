@@ -0,0 +1,463 @@
+// Shared harness for the integration tests: a mock authorization server on hyper and
+// an app wired the same way as `db::setup::init`, with Google's endpoints pointed at the mock.
+#![allow(dead_code)]
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::header::{AUTHORIZATION, LOCATION};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use k3_live_manager_lib::db::encryption::{DataKey, KdfParams, KeyVault};
+use k3_live_manager_lib::db::location::{DatabaseLocation, DatabaseSource};
+use k3_live_manager_lib::db::models::AddCredentialPayload;
+use k3_live_manager_lib::db::repositories::{CredentialRepository, SqliteRepository};
+use k3_live_manager_lib::db::setup::{self, AppState};
+use k3_live_manager_lib::oauth_server::CallbackPorts;
+use k3_live_manager_lib::services::backup_service::{BackupSchedule, BackupScheduler, BackupService};
+use k3_live_manager_lib::services::credential_service::CredentialService;
+use k3_live_manager_lib::services::encryption_service::EncryptionService;
+use k3_live_manager_lib::services::events::EventSink;
+use k3_live_manager_lib::services::oauth_flow::{OAuthFlowRegistry, OAuthFlowSnapshot};
+use k3_live_manager_lib::services::oauth_provider::{EndpointOverrides, ProviderRegistry, GOOGLE};
+use k3_live_manager_lib::services::oauth_service::OAuthService;
+use k3_live_manager_lib::services::token_refresher::{RefreshSchedule, TokenRefreshScheduler};
+use oauth2::{PkceCodeChallenge, PkceCodeVerifier};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use url::Url;
+
+// Ports for the flows' callback listeners; tests running in parallel each take the next free one
+const CALLBACK_PORTS: &str = "18421-18460";
+
+// How the mock IdP answers. Tests change it between steps through `MockIdp::configure`.
+#[derive(Debug, Clone)]
+pub struct Behavior {
+    // Account the "user" signs in as on the consent screen
+    pub subject: String,
+    // Redirect back with `error=<value>` instead of a code, e.g. `access_denied`
+    pub authorize_error: Option<String>,
+    // RFC 6749 error code returned by the token endpoint for every grant
+    pub token_error: Option<String>,
+    // Client secret the token and revocation endpoints accept (Basic auth or in the body)
+    pub client_secret: String,
+    // Delay before each token endpoint response
+    pub token_delay: Duration,
+    // Issue a new refresh token on every refresh and reject the previous one (Google/Twitch behaviour)
+    pub rotate_refresh_tokens: bool,
+    pub expires_in: u64,
+    // `authorization_pending` answers before the device code is approved
+    pub device_pending_polls: usize,
+}
+
+impl Default for Behavior {
+    fn default() -> Self {
+        Self {
+            subject: "channel-1".to_string(),
+            authorize_error: None,
+            token_error: None,
+            client_secret: "mock-secret".to_string(),
+            token_delay: Duration::ZERO,
+            rotate_refresh_tokens: true,
+            expires_in: 3600,
+            device_pending_polls: 0,
+        }
+    }
+}
+
+// Authorization code waiting to be exchanged
+struct PendingCode {
+    challenge: String,
+    redirect_uri: String,
+    subject: String,
+    scope: String,
+}
+
+#[derive(Default)]
+struct IdpState {
+    behavior: Mutex<Behavior>,
+    codes: Mutex<HashMap<String, PendingCode>>,
+    // Refresh token -> subject, only for tokens that are still valid
+    refresh_tokens: Mutex<HashMap<String, String>>,
+    // Access token -> subject, for userinfo
+    access_tokens: Mutex<HashMap<String, String>>,
+    // Query of every authorization request
+    authorize_requests: Mutex<Vec<HashMap<String, String>>>,
+    device_polls: AtomicUsize,
+    issued: AtomicUsize,
+    code_grants: AtomicUsize,
+    refresh_grants: AtomicUsize,
+    revocations: AtomicUsize,
+}
+
+// Local stand-in for Google's authorization server, served on Google's endpoint paths
+#[derive(Clone)]
+pub struct MockIdp {
+    pub base_url: Url,
+    state: Arc<IdpState>,
+}
+
+impl MockIdp {
+    pub async fn start(behavior: Behavior) -> Self {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let state = Arc::new(IdpState { behavior: Mutex::new(behavior), ..Default::default() });
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| {
+                        let state = state.clone();
+                        async move { Ok::<_, Infallible>(handle(&state, req).await) }
+                    });
+                    let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+        Self { base_url, state }
+    }
+
+    pub fn configure(&self, update: impl FnOnce(&mut Behavior)) {
+        update(&mut self.state.behavior.lock().unwrap());
+    }
+
+    // Invalidate every refresh token, as when the user revokes access in their account settings
+    pub fn revoke_all_grants(&self) {
+        self.state.refresh_tokens.lock().unwrap().clear();
+    }
+
+    pub fn is_valid_refresh_token(&self, refresh_token: &str) -> bool {
+        self.state.refresh_tokens.lock().unwrap().contains_key(refresh_token)
+    }
+
+    pub fn authorize_requests(&self) -> Vec<HashMap<String, String>> {
+        self.state.authorize_requests.lock().unwrap().clone()
+    }
+
+    pub fn code_grants(&self) -> usize {
+        self.state.code_grants.load(Ordering::SeqCst)
+    }
+
+    pub fn refresh_grants(&self) -> usize {
+        self.state.refresh_grants.load(Ordering::SeqCst)
+    }
+
+    pub fn revocations(&self) -> usize {
+        self.state.revocations.load(Ordering::SeqCst)
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Full::new(Bytes::from(body.to_string())))
+        .unwrap()
+}
+
+fn oauth_error(error: &str) -> Response<Full<Bytes>> {
+    json_response(
+        StatusCode::BAD_REQUEST,
+        serde_json::json!({ "error": error, "error_description": format!("mock {}", error) }),
+    )
+}
+
+fn redirect(location: Url) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location.as_str())
+        .body(Full::new(Bytes::new()))
+        .unwrap()
+}
+
+async fn handle(state: &IdpState, req: Request<hyper::body::Incoming>) -> Response<Full<Bytes>> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let query: HashMap<String, String> = req
+        .uri()
+        .query()
+        .map(|q| url::form_urlencoded::parse(q.as_bytes()).into_owned().collect())
+        .unwrap_or_default();
+    let authorization = req.headers().get(AUTHORIZATION).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let bearer = authorization.as_deref().and_then(|v| v.strip_prefix("Bearer ")).map(str::to_string);
+    let body = req.into_body().collect().await.unwrap().to_bytes();
+    let form: HashMap<String, String> = url::form_urlencoded::parse(&body).into_owned().collect();
+    let client_authenticated = client_secret(authorization.as_deref(), &form) == Some(state.behavior.lock().unwrap().client_secret.clone());
+
+    match (method, path.as_str()) {
+        (Method::GET, "/o/oauth2/v2/auth") => authorize(state, query),
+        (Method::POST, "/token" | "/revoke") if !client_authenticated => {
+            json_response(StatusCode::UNAUTHORIZED, serde_json::json!({ "error": "invalid_client" }))
+        }
+        (Method::POST, "/token") => {
+            let delay = state.behavior.lock().unwrap().token_delay;
+            tokio::time::sleep(delay).await;
+            token(state, form)
+        }
+        (Method::POST, "/device/code") => json_response(
+            StatusCode::OK,
+            serde_json::json!({
+                "device_code": "mock-device-code",
+                "user_code": "MOCK-CODE",
+                "verification_url": "https://www.google.com/device",
+                "expires_in": 60,
+                "interval": 0,
+            }),
+        ),
+        (Method::GET, "/v1/userinfo") => {
+            let subject = bearer.and_then(|token| state.access_tokens.lock().unwrap().get(&token).cloned());
+            match subject {
+                Some(subject) => json_response(
+                    StatusCode::OK,
+                    serde_json::json!({ "sub": subject, "email": format!("{}@example.com", subject), "name": subject }),
+                ),
+                None => json_response(StatusCode::UNAUTHORIZED, serde_json::json!({ "error": "invalid_token" })),
+            }
+        }
+        (Method::POST, "/revoke") => {
+            state.revocations.fetch_add(1, Ordering::SeqCst);
+            if let Some(token) = form.get("token") {
+                state.refresh_tokens.lock().unwrap().remove(token);
+            }
+            json_response(StatusCode::OK, serde_json::json!({}))
+        }
+        _ => json_response(StatusCode::NOT_FOUND, serde_json::json!({ "error": "not_found" })),
+    }
+}
+
+// Secret from `Authorization: Basic base64(id:secret)` or the `client_secret` form field.
+// The test secrets need no URL encoding, so the Basic credentials are compared as sent.
+fn client_secret(authorization: Option<&str>, form: &HashMap<String, String>) -> Option<String> {
+    let basic = authorization
+        .and_then(|v| v.strip_prefix("Basic "))
+        .and_then(|encoded| BASE64.decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .and_then(|credentials| credentials.split_once(':').map(|(_, secret)| secret.to_string()));
+    basic.or_else(|| form.get("client_secret").cloned())
+}
+
+// Consent screen: the user approves (or declines) immediately and the browser is redirected back
+fn authorize(state: &IdpState, query: HashMap<String, String>) -> Response<Full<Bytes>> {
+    state.authorize_requests.lock().unwrap().push(query.clone());
+    let behavior = state.behavior.lock().unwrap().clone();
+    let Some(mut location) = query.get("redirect_uri").and_then(|u| Url::parse(u).ok()) else {
+        return oauth_error("invalid_request");
+    };
+    let csrf_state = query.get("state").cloned().unwrap_or_default();
+
+    if let Some(error) = behavior.authorize_error {
+        location.query_pairs_mut().append_pair("error", &error).append_pair("state", &csrf_state);
+        return redirect(location);
+    }
+    if query.get("code_challenge_method").map(String::as_str) != Some("S256") {
+        return oauth_error("invalid_request");
+    }
+
+    let code = format!("code-{}", state.issued.fetch_add(1, Ordering::SeqCst));
+    state.codes.lock().unwrap().insert(
+        code.clone(),
+        PendingCode {
+            challenge: query.get("code_challenge").cloned().unwrap_or_default(),
+            redirect_uri: location.to_string(),
+            subject: behavior.subject,
+            scope: query.get("scope").cloned().unwrap_or_default(),
+        },
+    );
+    location.query_pairs_mut().append_pair("code", &code).append_pair("state", &csrf_state);
+    redirect(location)
+}
+
+fn issue_tokens(state: &IdpState, subject: &str, refresh_token: Option<String>, scope: Option<&str>) -> Response<Full<Bytes>> {
+    let n = state.issued.fetch_add(1, Ordering::SeqCst);
+    let expires_in = state.behavior.lock().unwrap().expires_in;
+    let access_token = format!("access-{}", n);
+    state.access_tokens.lock().unwrap().insert(access_token.clone(), subject.to_string());
+    let mut body = serde_json::json!({
+        "access_token": access_token,
+        "token_type": "Bearer",
+        "expires_in": expires_in,
+    });
+    if let Some(refresh_token) = refresh_token {
+        state.refresh_tokens.lock().unwrap().insert(refresh_token.clone(), subject.to_string());
+        body["refresh_token"] = refresh_token.into();
+    }
+    if let Some(scope) = scope {
+        body["scope"] = scope.into();
+    }
+    json_response(StatusCode::OK, body)
+}
+
+fn token(state: &IdpState, form: HashMap<String, String>) -> Response<Full<Bytes>> {
+    let behavior = state.behavior.lock().unwrap().clone();
+    match form.get("grant_type").map(String::as_str) {
+        Some("authorization_code") => {
+            state.code_grants.fetch_add(1, Ordering::SeqCst);
+            if let Some(error) = &behavior.token_error {
+                return oauth_error(error);
+            }
+            let pending = form.get("code").and_then(|code| state.codes.lock().unwrap().remove(code));
+            let Some(pending) = pending else {
+                return oauth_error("invalid_grant");
+            };
+            let verifier_matches = form.get("code_verifier").is_some_and(|v| {
+                PkceCodeChallenge::from_code_verifier_sha256(&PkceCodeVerifier::new(v.clone())).as_str() == pending.challenge
+            });
+            if !verifier_matches || form.get("redirect_uri") != Some(&pending.redirect_uri) {
+                return oauth_error("invalid_grant");
+            }
+            let refresh_token = format!("refresh-{}", state.issued.fetch_add(1, Ordering::SeqCst));
+            issue_tokens(state, &pending.subject, Some(refresh_token), Some(&pending.scope))
+        }
+        Some("refresh_token") => {
+            state.refresh_grants.fetch_add(1, Ordering::SeqCst);
+            if let Some(error) = &behavior.token_error {
+                return oauth_error(error);
+            }
+            let presented = form.get("refresh_token").cloned().unwrap_or_default();
+            let subject = if behavior.rotate_refresh_tokens {
+                state.refresh_tokens.lock().unwrap().remove(&presented)
+            } else {
+                state.refresh_tokens.lock().unwrap().get(&presented).cloned()
+            };
+            let Some(subject) = subject else {
+                return oauth_error("invalid_grant");
+            };
+            let rotated = behavior
+                .rotate_refresh_tokens
+                .then(|| format!("refresh-{}", state.issued.fetch_add(1, Ordering::SeqCst)));
+            issue_tokens(state, &subject, rotated, None)
+        }
+        Some("urn:ietf:params:oauth:grant-type:device_code") => {
+            if let Some(error) = &behavior.token_error {
+                return oauth_error(error);
+            }
+            if state.device_polls.fetch_add(1, Ordering::SeqCst) < behavior.device_pending_polls {
+                return oauth_error("authorization_pending");
+            }
+            let refresh_token = format!("refresh-{}", state.issued.fetch_add(1, Ordering::SeqCst));
+            issue_tokens(state, &behavior.subject, Some(refresh_token), None)
+        }
+        _ => oauth_error("unsupported_grant_type"),
+    }
+}
+
+// Keeps every event sent to the "frontend" so tests can inspect what the webview would receive
+#[derive(Default)]
+pub struct RecordingEventSink {
+    events: Mutex<Vec<(String, serde_json::Value)>>,
+}
+
+impl RecordingEventSink {
+    pub fn events(&self) -> Vec<(String, serde_json::Value)> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+impl EventSink for RecordingEventSink {
+    fn emit_json(&self, event: &str, payload: serde_json::Value) {
+        self.events.lock().unwrap().push((event.to_string(), payload));
+    }
+}
+
+// Services wired like `db::setup::init`, on an in-memory DB, with a Google credential served by `idp`.
+// `state` is what the commands receive; the other service fields share its repository and flows.
+pub struct TestApp {
+    pub idp: MockIdp,
+    pub state: AppState,
+    pub repo: Arc<SqliteRepository>,
+    pub credential_service: CredentialService,
+    pub oauth_service: OAuthService,
+    pub flows: OAuthFlowRegistry,
+    pub events: Arc<RecordingEventSink>,
+    pub callback_ports: CallbackPorts,
+    pub credential_id: i64,
+    browser: reqwest::Client,
+}
+
+impl TestApp {
+    pub async fn start(behavior: Behavior) -> Self {
+        let idp = MockIdp::start(behavior).await;
+        let pool = setup::connect("sqlite::memory:").await.unwrap();
+        let vault = Arc::new(KeyVault::unlocked(DataKey::generate()));
+        let repo = Arc::new(SqliteRepository::new(pool.clone(), vault.clone()));
+        let mut overrides = EndpointOverrides::default();
+        overrides.insert(GOOGLE, idp.base_url.clone());
+        let providers = ProviderRegistry::with_overrides(repo.clone(), overrides);
+        let credential_service = CredentialService::new(repo.clone(), providers.clone());
+        let oauth_service = OAuthService::new(repo.clone(), repo.clone(), repo.clone(), providers.clone());
+        let events = Arc::new(RecordingEventSink::default());
+        let flows = OAuthFlowRegistry::new(events.clone());
+        let callback_ports = CallbackPorts::parse(CALLBACK_PORTS).unwrap();
+        let credential_id = repo
+            .add_credential(AddCredentialPayload {
+                service_name: "youtube".into(),
+                client_id: "mock-client".into(),
+                client_secret: "mock-secret".into(),
+                provider: GOOGLE.into(),
+                scopes: None,
+                auth_params: None,
+            })
+            .await
+            .unwrap()
+            .id;
+
+        // The tests drive refreshes themselves and make no scheduled backups
+        let token_refresher = TokenRefreshScheduler::start(oauth_service.clone(), repo.clone(), events.clone(), RefreshSchedule::default());
+        token_refresher.shutdown().await;
+        let database = DatabaseLocation {
+            path: std::env::temp_dir().join(format!("k3-live-manager-test-{}", idp.base_url.port().unwrap())).join("app.sqlite"),
+            source: DatabaseSource::CommandLine,
+        };
+        let backup_service = BackupService::new(pool, database, 1);
+        let state = AppState {
+            encryption_service: EncryptionService::new(repo.clone(), vault, KdfParams::default()),
+            credential_service: CredentialService::new(repo.clone(), providers.clone()),
+            oauth_service: oauth_service.clone(),
+            provider_registry: providers,
+            oauth_flows: flows.clone(),
+            callback_ports: callback_ports.clone(),
+            token_refresher,
+            backup_scheduler: BackupScheduler::start(backup_service.clone(), BackupSchedule { interval: None, retention: 1 }),
+            backup_service,
+        };
+        Self {
+            idp,
+            state,
+            repo,
+            credential_service,
+            oauth_service,
+            flows,
+            events,
+            callback_ports,
+            credential_id,
+            browser: reqwest::Client::new(),
+        }
+    }
+
+    // Open the auth URL like the system browser would: the mock consents and redirects to the callback listener
+    pub async fn open_in_browser(&self, auth_url: &str) -> reqwest::Response {
+        self.browser.get(auth_url).send().await.expect("browser request failed")
+    }
+
+    // Wait until the flow leaves Waiting/Exchanging
+    pub async fn wait_for_flow(&self, flow_id: &str) -> OAuthFlowSnapshot {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let snapshot = self.flows.get(flow_id).expect("flow is registered");
+                if snapshot.status.is_terminal() {
+                    return snapshot;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("flow did not finish")
+    }
+}
@@ -0,0 +1,218 @@
+// End-to-end OAuth tests: flow start -> browser consent -> callback -> code exchange -> refresh,
+// against the mock IdP in `common`, which serves the built-in Google provider through an endpoint override.
+mod common;
+
+use common::{Behavior, TestApp};
+use k3_live_manager_lib::db::models::TokenStatus;
+use k3_live_manager_lib::db::repositories::{AccountRepository, CredentialRepository, TokenRepository};
+use k3_live_manager_lib::services::oauth_flow::{FlowErrorCode, FlowStatus, OAuthFlowSnapshot};
+use k3_live_manager_lib::services::oauth_service::{LinkTarget, ReauthorizationRequired};
+use std::time::Duration;
+use url::Url;
+
+const FLOW_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Run a browser flow to completion for the test app's credential
+async fn link_account(app: &TestApp, account_id: Option<i64>) -> OAuthFlowSnapshot {
+    let target = LinkTarget { credential_id: app.credential_id, account_id };
+    let started = app
+        .flows
+        .start_authorization(app.oauth_service.clone(), &app.callback_ports, target, FLOW_TIMEOUT)
+        .await
+        .unwrap();
+    let page = app.open_in_browser(&started.auth_url).await;
+    assert!(page.status().is_success());
+    app.wait_for_flow(&started.flow_id).await
+}
+
+#[tokio::test]
+async fn browser_flow_links_account_and_refresh_rotates_token() {
+    // Short-lived tokens so `ensure_valid_access_token` has to refresh
+    let app = TestApp::start(Behavior { expires_in: 30, ..Default::default() }).await;
+
+    let flow = link_account(&app, None).await;
+    assert_eq!(flow.status, FlowStatus::Succeeded, "{:?}", flow.error);
+    assert!(flow.missing_scopes.is_empty());
+    assert_eq!(flow.account.as_ref().unwrap().email.as_deref(), Some("channel-1@example.com"));
+    let account_id = flow.account_id.unwrap();
+
+    // The built-in Google provider was served by the mock, with Google's authorization params
+    let request = &app.idp.authorize_requests()[0];
+    assert_eq!(request.get("client_id").map(String::as_str), Some("mock-client"));
+    assert_eq!(request.get("access_type").map(String::as_str), Some("offline"));
+    assert_eq!(request.get("code_challenge_method").map(String::as_str), Some("S256"));
+    let redirect = Url::parse(request.get("redirect_uri").unwrap()).unwrap();
+    assert_eq!((redirect.host_str(), redirect.path()), (Some("localhost"), "/oauth/callback"));
+
+    let linked = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+    assert!(app.idp.is_valid_refresh_token(&linked.refresh_token));
+
+    let (access_token, _) = app.oauth_service.ensure_valid_access_token(account_id, 60).await.unwrap();
+    assert_ne!(access_token, linked.access_token);
+    let refreshed = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+    assert_eq!(refreshed.access_token, access_token);
+    // The provider rotated the refresh token: the new one is stored, the old one is dead
+    assert_ne!(refreshed.refresh_token, linked.refresh_token);
+    assert!(app.idp.is_valid_refresh_token(&refreshed.refresh_token));
+    assert!(!app.idp.is_valid_refresh_token(&linked.refresh_token));
+    assert_eq!(refreshed.scope, linked.scope);
+
+    // And the next refresh uses the rotated token successfully
+    app.oauth_service.refresh_access_token(account_id).await.unwrap();
+    assert_eq!(app.idp.refresh_grants(), 2);
+}
+
+#[tokio::test]
+async fn declined_consent_fails_the_flow_without_exchanging() {
+    let app = TestApp::start(Behavior { authorize_error: Some("access_denied".into()), ..Default::default() }).await;
+
+    let flow = link_account(&app, None).await;
+    assert_eq!(flow.status, FlowStatus::Failed);
+    assert_eq!(flow.error_code, Some(FlowErrorCode::AccessDenied));
+    assert_eq!(app.idp.code_grants(), 0);
+    assert!(app.repo.get_accounts_by_credential_id(app.credential_id).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn token_endpoint_error_fails_the_exchange() {
+    let app = TestApp::start(Behavior { token_error: Some("invalid_client".into()), ..Default::default() }).await;
+
+    let flow = link_account(&app, None).await;
+    assert_eq!(flow.status, FlowStatus::Failed);
+    assert_eq!(flow.error_code, Some(FlowErrorCode::ExchangeFailed));
+    assert_eq!(app.idp.code_grants(), 1);
+    assert!(app.repo.get_all_tokens().await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn revoked_grant_needs_reauthorization_until_the_account_signs_in_again() {
+    let app = TestApp::start(Behavior { expires_in: 30, ..Default::default() }).await;
+    let account_id = link_account(&app, None).await.account_id.unwrap();
+
+    app.idp.revoke_all_grants();
+    let err = app.oauth_service.ensure_valid_access_token(account_id, 60).await.unwrap_err();
+    assert_eq!(err.downcast_ref::<ReauthorizationRequired>().unwrap().account_id, account_id);
+    let attention = app.oauth_service.list_tokens_needing_attention().await.unwrap();
+    assert_eq!(attention.len(), 1);
+    assert_eq!(attention[0].status, TokenStatus::NeedsReauth);
+
+    // Failing fast: no further refresh attempts reach the provider
+    assert!(app.oauth_service.ensure_valid_access_token(account_id, 60).await.is_err());
+    assert_eq!(app.idp.refresh_grants(), 1);
+
+    let flow = link_account(&app, Some(account_id)).await;
+    assert_eq!(flow.status, FlowStatus::Succeeded);
+    assert_eq!(flow.account_id, Some(account_id));
+    assert!(flow.warning.is_none());
+    assert_eq!(app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap().status, TokenStatus::Active);
+    app.oauth_service.ensure_valid_access_token(account_id, 60).await.unwrap();
+}
+
+#[tokio::test]
+async fn slow_token_endpoint_refreshes_once_for_concurrent_callers() {
+    let app = TestApp::start(Behavior { expires_in: 30, ..Default::default() }).await;
+    let account_id = link_account(&app, None).await.account_id.unwrap();
+    app.idp.configure(|b| b.token_delay = Duration::from_millis(300));
+
+    let callers: Vec<_> = (0..5)
+        .map(|_| {
+            let service = app.oauth_service.clone();
+            tokio::spawn(async move { service.ensure_valid_access_token(account_id, 60).await })
+        })
+        .collect();
+    let mut results = Vec::new();
+    for caller in callers {
+        results.push(caller.await.unwrap().unwrap());
+    }
+
+    // With rotation, a second parallel refresh would have been rejected as invalid_grant
+    assert_eq!(app.idp.refresh_grants(), 1);
+    assert!(results.iter().all(|r| *r == results[0]));
+    let token = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+    assert_eq!(token.status, TokenStatus::Active);
+    assert!(app.idp.is_valid_refresh_token(&token.refresh_token));
+}
+
+#[tokio::test]
+async fn flow_times_out_and_releases_the_callback_port_without_a_callback() {
+    let app = TestApp::start(Behavior::default()).await;
+    let target = LinkTarget { credential_id: app.credential_id, account_id: None };
+    let started = app
+        .flows
+        .start_authorization(app.oauth_service.clone(), &app.callback_ports, target, Duration::from_millis(200))
+        .await
+        .unwrap();
+
+    let flow = app.wait_for_flow(&started.flow_id).await;
+    assert_eq!(flow.status, FlowStatus::TimedOut);
+
+    let redirect = Url::parse(&Url::parse(&started.auth_url).unwrap().query_pairs().find(|(k, _)| k == "redirect_uri").unwrap().1).unwrap();
+    let port = redirect.port().unwrap();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while tokio::net::TcpListener::bind(("127.0.0.1", port)).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("callback port was not released");
+}
+
+#[tokio::test]
+async fn device_flow_links_account_after_pending_polls() {
+    let app = TestApp::start(Behavior { device_pending_polls: 2, subject: "channel-2".into(), ..Default::default() }).await;
+    let target = LinkTarget { credential_id: app.credential_id, account_id: None };
+
+    let started = app.flows.start_device_authorization(app.oauth_service.clone(), target).await.unwrap();
+    assert_eq!(started.user_code, "MOCK-CODE");
+    let flow = app.wait_for_flow(&started.flow_id).await;
+
+    assert_eq!(flow.status, FlowStatus::Succeeded, "{:?}", flow.error);
+    assert_eq!(flow.account.unwrap().subject, "channel-2");
+    assert!(app.repo.get_token_by_account_id(flow.account_id.unwrap()).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn unlinking_revokes_the_grant_at_the_provider() {
+    let app = TestApp::start(Behavior::default()).await;
+    let account_id = link_account(&app, None).await.account_id.unwrap();
+    let token = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+
+    let outcome = app.oauth_service.revoke_token(account_id).await.unwrap();
+    assert!(outcome.revoked_at_provider, "{:?}", outcome.provider_error);
+    assert_eq!(app.idp.revocations(), 1);
+    assert!(!app.idp.is_valid_refresh_token(&token.refresh_token));
+    assert!(app.repo.get_account_by_id(account_id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn rotated_client_secret_keeps_the_linked_token_working() {
+    let app = TestApp::start(Behavior::default()).await;
+    let account_id = link_account(&app, None).await.account_id.unwrap();
+
+    // The secret is rotated in the provider's console: the old one stops authenticating
+    app.idp.configure(|b| b.client_secret = "rotated-secret".into());
+    assert!(app.oauth_service.refresh_access_token(account_id).await.is_err());
+
+    app.credential_service.rotate_client_secret(app.credential_id, "rotated-secret".into()).await.unwrap();
+    app.oauth_service.refresh_access_token(account_id).await.unwrap();
+    assert_eq!(app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap().status, TokenStatus::Active);
+}
+
+#[tokio::test]
+async fn deleting_a_credential_revokes_and_removes_its_accounts() {
+    let app = TestApp::start(Behavior::default()).await;
+    let first = link_account(&app, None).await.account_id.unwrap();
+    app.idp.configure(|b| b.subject = "channel-2".into());
+    let second = link_account(&app, None).await.account_id.unwrap();
+    let token = app.repo.get_token_by_account_id(first).await.unwrap().unwrap();
+
+    let outcomes = app.oauth_service.revoke_credential_accounts(app.credential_id).await.unwrap();
+    assert_eq!(outcomes.iter().map(|o| o.account_id).collect::<Vec<_>>(), vec![first, second]);
+    assert!(outcomes.iter().all(|o| o.revoked_at_provider), "{:?}", outcomes);
+    assert_eq!(app.idp.revocations(), 2);
+    assert!(!app.idp.is_valid_refresh_token(&token.refresh_token));
+
+    app.credential_service.delete_credential(app.credential_id).await.unwrap();
+    assert!(app.repo.get_credential_by_id(app.credential_id).await.unwrap().is_none());
+    assert!(app.repo.get_all_tokens().await.unwrap().is_empty());
+}
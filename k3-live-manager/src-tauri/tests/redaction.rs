@@ -0,0 +1,85 @@
+// Secrets must stay in the backend: serialize everything the commands and events hand to the webview
+// after a full link/refresh/revoke cycle against the mock IdP, and look for the client secret and tokens.
+mod common;
+
+use common::{Behavior, TestApp};
+use k3_live_manager_lib::db::handlers;
+use k3_live_manager_lib::db::models::ImportClientSecretPayload;
+use k3_live_manager_lib::db::repositories::TokenRepository;
+use k3_live_manager_lib::services::oauth_service::LinkTarget;
+use serde::Serialize;
+use std::time::Duration;
+
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap()
+}
+
+fn assert_no_secrets(responses: &[String], secrets: &[String]) {
+    for response in responses {
+        for secret in secrets {
+            assert!(!response.contains(secret.as_str()), "response leaks a secret: {}", response);
+        }
+    }
+}
+
+#[tokio::test]
+async fn command_responses_never_contain_secrets_or_tokens() {
+    let app = TestApp::start(Behavior { expires_in: 30, ..Default::default() }).await;
+    let state = &app.state;
+    let mut responses = Vec::new();
+    let mut secrets = vec!["mock-secret".to_string()];
+
+    responses.push(to_json(&handlers::get_service_credentials(state).await.unwrap()));
+    responses.push(to_json(&handlers::set_credential_scopes(state, app.credential_id, vec!["openid".into()]).await.unwrap()));
+
+    let imported_secret = "GOCSPX-imported-secret";
+    secrets.push(imported_secret.to_string());
+    let contents = format!(
+        r#"{{"installed":{{"client_id":"imported.apps.googleusercontent.com","project_id":"k3-live","auth_uri":"https://accounts.google.com/o/oauth2/auth","token_uri":"https://oauth2.googleapis.com/token","client_secret":"{}","redirect_uris":["http://localhost"]}}}}"#,
+        imported_secret
+    );
+    let payload = ImportClientSecretPayload { contents, service_name: None, scopes: None };
+    let imported = handlers::import_client_secret_json(state, payload).await.unwrap();
+    assert_eq!(imported.credential.secret_hint.as_deref(), Some("cret"));
+    responses.push(to_json(&imported));
+
+    // start_oauth_flow -> callback -> get_oauth_flow_status
+    let target = LinkTarget { credential_id: app.credential_id, account_id: None };
+    let started = handlers::start_oauth_flow(state, target, Duration::from_secs(30)).await.unwrap();
+    responses.push(to_json(&started));
+    app.open_in_browser(&started.auth_url).await;
+    app.wait_for_flow(&started.flow_id).await;
+    let flow = handlers::get_oauth_flow_status(state, &started.flow_id).unwrap();
+    responses.push(to_json(&flow));
+    let account_id = flow.account_id.unwrap();
+    let linked = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+    secrets.extend([linked.access_token, linked.refresh_token]);
+
+    // ensure_valid_access_token refreshes the short-lived token; only its expiry goes to the UI
+    responses.push(to_json(&handlers::ensure_valid_access_token(state, account_id, 60).await.unwrap()));
+    let refreshed = app.repo.get_token_by_account_id(account_id).await.unwrap().unwrap();
+    secrets.extend([refreshed.access_token, refreshed.refresh_token]);
+
+    responses.push(to_json(&handlers::get_linked_accounts(state, app.credential_id).await.unwrap()));
+    responses.push(to_json(&handlers::check_token_scopes(state, account_id, vec!["openid".into()]).await.unwrap()));
+
+    // get_credentials_needing_attention, with the provider's error recorded
+    app.idp.revoke_all_grants();
+    let error = handlers::ensure_valid_access_token(state, account_id, 60).await.unwrap_err();
+    responses.push(to_json(&error));
+    let attention = handlers::get_credentials_needing_attention(state).await.unwrap();
+    assert_eq!(attention.len(), 1);
+    responses.push(to_json(&attention));
+
+    responses.push(to_json(&handlers::revoke_account_token(state, account_id).await.unwrap()));
+
+    // Events emitted to the webview during the flow
+    let events = app.events.events();
+    assert!(!events.is_empty());
+    responses.extend(events.iter().map(|(_, payload)| payload.to_string()));
+
+    assert_no_secrets(&responses, &secrets);
+
+    // Revealing is the explicit way to get the secret back
+    assert_eq!(handlers::reveal_client_secret(state, app.credential_id).await.unwrap(), "mock-secret");
+}
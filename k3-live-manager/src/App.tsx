@@ -1,10 +1,97 @@
-import React from 'react';
+import React, { useCallback, useEffect, useState } from 'react';
 import { Routes, Route, Link } from 'react-router-dom';
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { AppError, errorMessage } from './errors';
 import HomePage from './pages/HomePage';
 import CredentialsListPage from './pages/CredentialsListPage';
 import AddCredentialPage from './pages/AddCredentialPage';
+import SecurityPage from './pages/SecurityPage';
+import BackupPage from './pages/BackupPage';
+import UnlockPage, { EncryptionState } from './pages/UnlockPage';
+
+// BackendのAppStatusと型を合わせる（app-status イベントのペイロードも同じ）
+type AppStatus =
+  | { status: 'initializing' }
+  | { status: 'ready' }
+  | { status: 'failed'; error: AppError };
 
 const App: React.FC = () => {
+  const [appStatus, setAppStatus] = useState<AppStatus | null>(null);
+  const [encryptionState, setEncryptionState] = useState<EncryptionState | null>(null);
+  const [error, setError] = useState('');
+
+  const fetchEncryptionState = useCallback(() => {
+    invoke<EncryptionState>('get_encryption_state')
+      .then((state) => {
+        setEncryptionState(state);
+        setError('');
+      })
+      .catch((err) => {
+        console.error("Failed to fetch encryption state:", err);
+        setError(errorMessage(err));
+      });
+  }, []);
+
+  // 起動処理（DB接続・マイグレーション）の完了を待ってから他のコマンドを呼ぶ
+  useEffect(() => {
+    const unlisten = listen<AppStatus>('app-status', (event) => setAppStatus(event.payload));
+    invoke<AppStatus>('get_app_status')
+      .then((status) => setAppStatus((prev) => prev ?? status))
+      .catch((err) => {
+        console.error("Failed to fetch app status:", err);
+        setError(errorMessage(err));
+      });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  useEffect(() => {
+    if (appStatus?.status === 'ready') {
+      fetchEncryptionState();
+    }
+  }, [appStatus, fetchEncryptionState]);
+
+  if (appStatus?.status === 'failed') {
+    return (
+      <div className="App">
+        <main>
+          <h1>The app failed to start</h1>
+          <p style={{ color: 'red' }}>{appStatus.error.message}</p>
+          <p>Fix the cause (for example the database location or file permissions) and restart the app.</p>
+        </main>
+      </div>
+    );
+  }
+
+  if (appStatus?.status !== 'ready') {
+    return (
+      <div className="App">
+        <main>
+          {error ? <p style={{ color: 'red' }}>{error}</p> : <p>Starting...</p>}
+        </main>
+      </div>
+    );
+  }
+
+  // 解錠されるまでは資格情報/トークンを扱う画面を表示しない
+  if (encryptionState !== 'unlocked') {
+    return (
+      <div className="App">
+        <main>
+          {error && (
+            <div>
+              <p style={{ color: 'red' }}>{error}</p>
+              <button onClick={fetchEncryptionState}>Retry</button>
+            </div>
+          )}
+          {encryptionState && <UnlockPage state={encryptionState} onUnlocked={fetchEncryptionState} />}
+        </main>
+      </div>
+    );
+  }
+
   return (
     <div className="App">
       <nav>
@@ -15,6 +102,12 @@ const App: React.FC = () => {
           <li>
             <Link to="/credentials">Credentials</Link>
           </li>
+          <li>
+            <Link to="/security">Security</Link>
+          </li>
+          <li>
+            <Link to="/backups">Backups</Link>
+          </li>
         </ul>
       </nav>
 
@@ -23,6 +116,8 @@ const App: React.FC = () => {
           <Route path="/" element={<HomePage />} />
           <Route path="/credentials" element={<CredentialsListPage />} />
           <Route path="/credentials/add" element={<AddCredentialPage />} />
+          <Route path="/security" element={<SecurityPage />} />
+          <Route path="/backups" element={<BackupPage />} />
         </Routes>
       </main>
     </div>
@@ -0,0 +1,40 @@
+// Backendのerror::AppErrorと型を合わせる。全コマンドは失敗時にこの形でrejectする
+export type ErrorCode =
+  | 'not_found'
+  | 'invalid_input'
+  | 'conflict'
+  | 'database_locked'
+  | 'incorrect_passphrase'
+  | 'needs_reauth'
+  | 'provider_error'
+  | 'network'
+  | 'database'
+  | 'internal'
+  | 'not_ready'
+  | 'startup_failed';
+
+export interface AppError {
+  code: ErrorCode;
+  message: string;
+  // 同じ操作を後で再試行すれば成功する可能性がある
+  retryable: boolean;
+  details?: Record<string, unknown>;
+}
+
+export const isAppError = (error: unknown): error is AppError =>
+  typeof error === 'object' &&
+  error !== null &&
+  typeof (error as AppError).code === 'string' &&
+  typeof (error as AppError).message === 'string';
+
+// invoke の reject 値やフロント側の Error を表示用の文字列にする
+export const errorMessage = (error: unknown): string => {
+  if (isAppError(error)) {
+    // プロバイダのエラーコード（invalid_client 等）は details にある
+    const providerError = error.code === 'provider_error' ? error.details?.error : undefined;
+    const message = typeof providerError === 'string' ? `${error.message} (${providerError})` : error.message;
+    return error.retryable ? `${message} (please try again)` : message;
+  }
+  if (error instanceof Error) return error.message;
+  return typeof error === 'string' ? error : 'An unknown error occurred';
+};
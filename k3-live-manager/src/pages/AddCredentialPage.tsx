@@ -1,13 +1,39 @@
-import React, { useState } from 'react';
+import React, { useState, useEffect } from 'react';
 import { useNavigate } from 'react-router-dom';
 import { invoke } from '@tauri-apps/api/core';
+import { errorMessage } from '../errors';
+
+// BackendのOAuthProvider構造体と型を合わせる
+interface OAuthProvider {
+  key: string;
+  display_name: string;
+  builtin: boolean;
+}
+
+// Backendの ClientSecretImport 構造体と型を合わせる
+interface ClientSecretImport {
+  credential: { id: number; service_name: string };
+  client_type: 'installed' | 'web';
+  redirect_ports: number[];
+  warnings: string[];
+}
 
 const AddCredentialPage: React.FC = () => {
   const navigate = useNavigate();
+  const [providers, setProviders] = useState<OAuthProvider[]>([]);
+  const [provider, setProvider] = useState('google');
   const [serviceName, setServiceName] = useState('');
   const [clientId, setClientId] = useState('');
   const [clientSecret, setClientSecret] = useState('');
+  const [scopes, setScopes] = useState('');
   const [error, setError] = useState('');
+  const [importWarnings, setImportWarnings] = useState<string[]>([]);
+
+  useEffect(() => {
+    invoke<OAuthProvider[]>('get_oauth_providers')
+      .then(setProviders)
+      .catch((err) => console.error("Failed to fetch providers:", err));
+  }, []);
 
   const handleSubmit = async (e: React.FormEvent) => {
     e.preventDefault();
@@ -24,19 +50,84 @@ const AddCredentialPage: React.FC = () => {
           service_name: serviceName,
           client_id: clientId,
           client_secret: clientSecret,
+          provider,
+          // 空欄の場合はプロバイダの既定スコープを使用
+          scopes: scopes.trim() ? scopes.trim().split(/\s+/) : null,
         },
       });
       navigate('/credentials'); // Navigate back to the list on success
     } catch (err) {
       console.error("Failed to add credential:", err);
-      setError(typeof err === 'string' ? err : 'An unknown error occurred');
+      setError(errorMessage(err));
+    }
+  };
+
+  // Google Cloud Console からダウンロードした client_secret_*.json を取り込む
+  const handleImport = async (e: React.ChangeEvent<HTMLInputElement>) => {
+    const file = e.target.files?.[0];
+    e.target.value = '';
+    if (!file) return;
+    setError('');
+    setImportWarnings([]);
+
+    try {
+      const result = await invoke<ClientSecretImport>('import_client_secret_json', {
+        payload: {
+          contents: await file.text(),
+          // 空欄の場合はファイルの project_id を使用
+          service_name: serviceName.trim() || null,
+          scopes: scopes.trim() ? scopes.trim().split(/\s+/) : null,
+        },
+      });
+      if (result.warnings.length === 0) {
+        navigate('/credentials');
+        return;
+      }
+      setImportWarnings(result.warnings);
+    } catch (err) {
+      console.error("Failed to import client secret:", err);
+      setError(errorMessage(err));
     }
   };
 
   return (
     <div>
       <h1>Add New Credential</h1>
+      <div>
+        <label htmlFor="clientSecretFile">Import Google client_secret.json:</label>
+        <input
+          id="clientSecretFile"
+          type="file"
+          accept="application/json,.json"
+          onChange={handleImport}
+        />
+      </div>
+      {importWarnings.length > 0 && (
+        <div>
+          <p>Credential imported. Check the OAuth client in Google Cloud Console:</p>
+          <ul>
+            {importWarnings.map((w) => (
+              <li key={w} style={{ color: 'orange' }}>{w}</li>
+            ))}
+          </ul>
+          <button type="button" onClick={() => navigate('/credentials')}>Go to credentials</button>
+        </div>
+      )}
       <form onSubmit={handleSubmit}>
+        <div>
+          <label htmlFor="provider">Provider:</label>
+          <select
+            id="provider"
+            value={provider}
+            onChange={(e) => setProvider(e.target.value)}
+          >
+            {providers.map((p) => (
+              <option key={p.key} value={p.key}>
+                {p.display_name}
+              </option>
+            ))}
+          </select>
+        </div>
         <div>
           <label htmlFor="serviceName">Service Name:</label>
           <input
@@ -64,6 +155,15 @@ const AddCredentialPage: React.FC = () => {
             onChange={(e) => setClientSecret(e.target.value)}
           />
         </div>
+        <div>
+          <label htmlFor="scopes">Scopes (optional, space separated):</label>
+          <input
+            id="scopes"
+            type="text"
+            value={scopes}
+            onChange={(e) => setScopes(e.target.value)}
+          />
+        </div>
         {error && <p style={{ color: 'red' }}>{error}</p>}
         <button type="submit">Save Credential</button>
       </form>
@@ -0,0 +1,110 @@
+import React, { useEffect, useState } from 'react';
+import { invoke } from '@tauri-apps/api/core';
+import { errorMessage } from '../errors';
+
+// BackendのBackupInfo構造体と型を合わせる
+interface BackupInfo {
+  file_name: string;
+  kind: 'manual' | 'scheduled';
+  created_at: string;
+  size_bytes: number;
+}
+
+interface RestoreStaged {
+  file_name: string;
+  schema_version: number;
+  restart_required: boolean;
+}
+
+// データベースのバックアップ作成・一覧・復元（復元は次回起動時に差し替え）
+const BackupPage: React.FC = () => {
+  const [backups, setBackups] = useState<BackupInfo[]>([]);
+  const [error, setError] = useState('');
+  const [message, setMessage] = useState('');
+  const [busy, setBusy] = useState(false);
+
+  const fetchBackups = async () => {
+    try {
+      setBackups(await invoke<BackupInfo[]>('list_backups'));
+    } catch (err) {
+      console.error("Failed to fetch backups:", err);
+      setError(errorMessage(err));
+    }
+  };
+
+  useEffect(() => {
+    fetchBackups();
+  }, []);
+
+  const handleCreate = async () => {
+    setError('');
+    setMessage('');
+    setBusy(true);
+    try {
+      const backup = await invoke<BackupInfo>('create_backup');
+      setMessage(`Backup created: ${backup.file_name}`);
+      fetchBackups();
+    } catch (err) {
+      setError(errorMessage(err));
+    } finally {
+      setBusy(false);
+    }
+  };
+
+  const handleRestore = async (backup: BackupInfo) => {
+    if (!confirm(`Restore ${backup.file_name}? Changes made after ${backup.created_at} (UTC) will be lost.`)) return;
+    setError('');
+    setMessage('');
+    setBusy(true);
+    try {
+      await invoke<RestoreStaged>('restore_backup', { fileName: backup.file_name });
+      // 差し替えは再起動時に行われる
+      if (confirm('The backup will be restored when the app restarts. Restart now?')) {
+        await invoke('restart_app');
+      } else {
+        setMessage('The backup will be restored on the next start.');
+      }
+    } catch (err) {
+      setError(errorMessage(err));
+    } finally {
+      setBusy(false);
+    }
+  };
+
+  return (
+    <div>
+      <h1>Backups</h1>
+      <button onClick={handleCreate} disabled={busy}>Create Backup</button>
+      {error && <p style={{ color: 'red' }}>{error}</p>}
+      {message && <p style={{ color: 'green' }}>{message}</p>}
+      {backups.length === 0 ? (
+        <p>No backups yet.</p>
+      ) : (
+        <table>
+          <thead>
+            <tr>
+              <th>Created (UTC)</th>
+              <th>Type</th>
+              <th>Size</th>
+              <th></th>
+            </tr>
+          </thead>
+          <tbody>
+            {backups.map((backup) => (
+              <tr key={backup.file_name}>
+                <td>{backup.created_at}</td>
+                <td>{backup.kind === 'manual' ? 'Manual' : 'Scheduled'}</td>
+                <td>{Math.ceil(backup.size_bytes / 1024)} KB</td>
+                <td>
+                  <button onClick={() => handleRestore(backup)} disabled={busy}>Restore</button>
+                </td>
+              </tr>
+            ))}
+          </tbody>
+        </table>
+      )}
+    </div>
+  );
+};
+
+export default BackupPage;
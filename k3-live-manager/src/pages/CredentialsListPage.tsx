@@ -1,23 +1,147 @@
 import React, { useState, useEffect } from 'react';
 import { Link } from 'react-router-dom';
 import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
 import { openUrl } from '@tauri-apps/plugin-opener';
+import { AppError, errorMessage, isAppError } from '../errors';
 
-// BackendのServiceCredential構造体と型を合わせる
+// BackendのServiceCredentialView構造体と型を合わせる（client_secret は含まれない）
 interface ServiceCredential {
   id: number;
   service_name: string;
   client_id: string;
-  client_secret: string;
+  provider: string;
+  scopes: string | null;
+  has_secret: boolean;
+  secret_hint: string | null;
 }
 
+interface OAuthFlowStarted {
+  flow_id: string;
+  auth_url: string;
+}
+
+interface AccountIdentity {
+  subject: string;
+  email: string | null;
+  name: string | null;
+  picture: string | null;
+}
+
+// BackendのLinkWarning（再認証で別のアカウントにサインインした場合）
+type LinkWarning =
+  | { kind: 'other_linked_account'; requested_account_id: number }
+  | { kind: 'identity_changed'; previous: AccountIdentity };
+
+// BackendのOauthAccount構造体（資格情報ごとの連携アカウント）
+interface LinkedAccount {
+  id: number;
+  credentials_id: number;
+  subject: string | null;
+  email: string | null;
+  name: string | null;
+  picture: string | null;
+}
+
+// BackendのTokenAttention構造体（要対応のトークン）
+interface TokenAttention {
+  credential_id: number;
+  service_name: string;
+  account_id: number;
+  email: string | null;
+  name: string | null;
+  status: 'active' | 'needs_reauth';
+  last_error: string | null;
+  last_error_at: string | null;
+}
+
+interface RevocationOutcome {
+  account_id: number;
+  revoked_at_provider: boolean;
+  local_token_removed: boolean;
+  // プロバイダ側の失効が失敗/省略された理由
+  provider_error: AppError | null;
+  // 連携解除できなかった（アカウントは残っている）
+  error: AppError | null;
+}
+
+// Backendの CredentialDeletion 構造体
+interface CredentialDeletion {
+  credential_id: number;
+  revocations: RevocationOutcome[];
+}
+
+interface DeviceFlowStarted {
+  flow_id: string;
+  user_code: string;
+  verification_uri: string;
+  verification_uri_complete: string | null;
+  expires_in: number;
+}
+
+interface OAuthFlowSnapshot {
+  flow_id: string;
+  credential_id: number;
+  account_id: number | null;
+  status: 'waiting' | 'exchanging' | 'succeeded' | 'failed' | 'cancelled' | 'timed_out';
+  error_code:
+    | 'state_mismatch'
+    | 'access_denied'
+    | 'provider_error'
+    | 'exchange_failed'
+    | 'missing_refresh_token'
+    | 'callback_server_stopped'
+    | null;
+  error: string | null;
+  granted_scopes: string[];
+  missing_scopes: string[];
+  account: AccountIdentity | null;
+  warning: LinkWarning | null;
+}
+
+const accountLabel = (account: AccountIdentity) => account.email ?? account.name ?? account.subject;
+
+const linkWarningMessage = (warning: LinkWarning, account: AccountIdentity | null) => {
+  const current = account ? accountLabel(account) : 'another account';
+  if (warning.kind === 'other_linked_account') {
+    return `Warning: you signed in as ${current}, which is already linked. That account was refreshed; the account you re-authorized is unchanged.`;
+  }
+  return `Warning: the account is now linked to ${current} instead of ${accountLabel(warning.previous)}.`;
+};
+
 const CredentialsListPage: React.FC = () => {
   const [credentials, setCredentials] = useState<ServiceCredential[]>([]);
+  const [flow, setFlow] = useState<OAuthFlowSnapshot | null>(null);
+  const [deviceCode, setDeviceCode] = useState<DeviceFlowStarted | null>(null);
+  const [accounts, setAccounts] = useState<Record<number, LinkedAccount[]>>({});
+  const [attention, setAttention] = useState<Record<number, TokenAttention>>({});
+  // Reveal で明示的に取得した client_secret。Hide で破棄する
+  const [revealed, setRevealed] = useState<Record<number, string>>({});
+
+  const fetchAttention = async () => {
+    try {
+      const items = await invoke<TokenAttention[]>('get_credentials_needing_attention');
+      setAttention(Object.fromEntries(items.map((item) => [item.account_id, item])));
+    } catch (error) {
+      console.error("Failed to fetch token status:", error);
+    }
+  };
+
+  const fetchAccounts = async (credentialId: number) => {
+    try {
+      const linked = await invoke<LinkedAccount[]>('get_linked_accounts', { credentialId });
+      setAccounts((prev) => ({ ...prev, [credentialId]: linked }));
+    } catch (error) {
+      console.error("Failed to fetch linked accounts:", error);
+    }
+  };
 
   const fetchCredentials = async () => {
     try {
       const creds = await invoke<ServiceCredential[]>('get_service_credentials');
       setCredentials(creds);
+      creds.forEach((cred) => fetchAccounts(cred.id));
+      fetchAttention();
     } catch (error) {
       console.error("Failed to fetch credentials:", error);
     }
@@ -27,11 +151,173 @@ const CredentialsListPage: React.FC = () => {
     fetchCredentials();
   }, []);
 
-  const handleAuthenticate = async (credentialId: number) => {
+  // バックエンドが送る oauth-flow イベントで進行中のフローの状態を更新
+  useEffect(() => {
+    const unlisten = listen<OAuthFlowSnapshot>('oauth-flow', (event) => {
+      setFlow((current) => (current && current.flow_id === event.payload.flow_id ? event.payload : current));
+      if (event.payload.status === 'succeeded') {
+        const { credential_id, account, warning } = event.payload;
+        fetchAccounts(credential_id);
+        fetchAttention();
+        if (warning) {
+          // 再認証で別のアカウント（チャンネル）にサインインした場合は誤連携の可能性を警告
+          alert(linkWarningMessage(warning, account));
+        }
+      }
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  // バックグラウンド更新の結果（失敗/要再認証）を反映
+  useEffect(() => {
+    const unlisten = listen('token-refresh', () => {
+      fetchAttention();
+    });
+    return () => {
+      unlisten.then((f) => f());
+    };
+  }, []);
+
+  // 失敗をアラート表示。not_found は一覧が古いので取り直す
+  const reportError = (action: string, error: unknown) => {
+    console.error(`Failed to ${action}:`, error);
+    alert(`Failed to ${action}: ${errorMessage(error)}`);
+    if (isAppError(error) && error.code === 'not_found') {
+      fetchCredentials();
+    }
+  };
+
+  const handleReveal = async (credentialId: number) => {
+    try {
+      const secret = await invoke<string>('reveal_client_secret', { credentialId });
+      setRevealed((prev) => ({ ...prev, [credentialId]: secret }));
+    } catch (error) {
+      reportError('reveal the client secret', error);
+    }
+  };
+
+  const handleHide = (credentialId: number) => {
+    setRevealed((prev) => {
+      const next = { ...prev };
+      delete next[credentialId];
+      return next;
+    });
+  };
+
+  const handleEdit = async (cred: ServiceCredential) => {
+    const serviceName = prompt('Service name', cred.service_name);
+    if (serviceName === null) return;
+    const clientId = prompt('Client ID', cred.client_id);
+    if (clientId === null) return;
+    try {
+      await invoke<ServiceCredential>('update_service_credential', {
+        credentialId: cred.id,
+        payload: { service_name: serviceName, client_id: clientId },
+      });
+      fetchCredentials();
+    } catch (error) {
+      reportError('update the credential', error);
+    }
+  };
+
+  // 連携済みアカウントのトークンはそのまま使える
+  const handleRotateSecret = async (cred: ServiceCredential) => {
+    const clientSecret = prompt(`New client secret for ${cred.service_name}`);
+    if (!clientSecret) return;
+    try {
+      await invoke<ServiceCredential>('rotate_client_secret', { credentialId: cred.id, clientSecret });
+      handleHide(cred.id);
+      fetchCredentials();
+    } catch (error) {
+      reportError('rotate the client secret', error);
+    }
+  };
+
+  // 連携アカウントとトークンも削除される。先にプロバイダ側で失効させるかを選べる
+  const handleDelete = async (cred: ServiceCredential) => {
+    if (!confirm(`Delete ${cred.service_name} and all of its linked accounts?`)) return;
+    const revokeTokens = (accounts[cred.id] ?? []).length > 0 && confirm('Also revoke the linked accounts at the provider?');
+    try {
+      const deletion = await invoke<CredentialDeletion>('delete_service_credential', { credentialId: cred.id, revokeTokens });
+      const failed = deletion.revocations.filter((outcome) => !outcome.revoked_at_provider);
+      if (failed.length > 0) {
+        alert(`Deleted, but the provider did not confirm revocation for ${failed.length} account(s): ${errorMessage(failed[0].provider_error)}`);
+      }
+      handleHide(cred.id);
+      fetchCredentials();
+    } catch (error) {
+      // 一部のアカウントを連携解除できなかった場合は資格情報を残す。解除済みのアカウントを反映する
+      const revocations = isAppError(error) ? (error.details?.revocations as RevocationOutcome[] | undefined) : undefined;
+      if (revocations) {
+        const failed = revocations.filter((outcome) => outcome.error);
+        alert(
+          `${errorMessage(error)}: ${revocations.length - failed.length} of ${revocations.length} account(s) were unlinked; ` +
+            `failed for account(s) ${failed.map((outcome) => outcome.account_id).join(', ')}.`,
+        );
+        fetchAccounts(cred.id);
+        return;
+      }
+      reportError('delete the credential', error);
+    }
+  };
+
+  const handleCancel = async () => {
+    if (!flow) return;
+    try {
+      setFlow(await invoke<OAuthFlowSnapshot>('cancel_oauth_flow', { flowId: flow.flow_id }));
+    } catch (error) {
+      console.error("Failed to cancel OAuth flow:", error);
+    }
+  };
+
+  const handleUnlink = async (account: LinkedAccount) => {
+    try {
+      const outcome = await invoke<RevocationOutcome>('revoke_account_token', { accountId: account.id });
+      fetchAccounts(account.credentials_id);
+      if (!outcome.revoked_at_provider) {
+        // ローカルのトークンは削除済み。プロバイダ側の失効のみ失敗
+        alert(`Unlinked locally, but the provider did not confirm revocation: ${errorMessage(outcome.provider_error)}`);
+      }
+    } catch (error) {
+      reportError('unlink', error);
+    }
+  };
+
+  const waitingFlow = (flowId: string, credentialId: number, accountId: number | null): OAuthFlowSnapshot => ({
+    flow_id: flowId,
+    credential_id: credentialId,
+    account_id: accountId,
+    status: 'waiting',
+    error_code: null,
+    error: null,
+    granted_scopes: [],
+    missing_scopes: [],
+    account: null,
+    warning: null,
+  });
+
+  // 別端末のブラウザで承認するデバイスフロー。ユーザーコードと確認URLを表示する
+  const handleDeviceLogin = async (credentialId: number, accountId: number | null = null) => {
+    try {
+      const started = await invoke<DeviceFlowStarted>('start_device_flow', { credentialId, accountId });
+      setDeviceCode(started);
+      setFlow(waitingFlow(started.flow_id, credentialId, accountId));
+    } catch (error) {
+      reportError('start device login', error);
+    }
+  };
+
+  // accountId を指定すると既存アカウントの再認証、未指定ならアカウントを追加
+  const handleAuthenticate = async (credentialId: number, accountId: number | null = null) => {
     try {
       console.log(`Starting authentication for credential ID: ${credentialId}`);
-      const authUrl = await invoke<string>('start_oauth_flow', { credentialId });
-      console.log(`Received auth URL: ${authUrl}`);
+      const started = await invoke<OAuthFlowStarted>('start_oauth_flow', { credentialId, accountId });
+      const authUrl = started.auth_url;
+      console.log(`Received auth URL for flow ${started.flow_id}`);
+      setDeviceCode(null);
+      setFlow(waitingFlow(started.flow_id, credentialId, accountId));
 
       if (authUrl) {
         // TauriのopenUrl APIで外部ブラウザで開く
@@ -41,9 +327,7 @@ const CredentialsListPage: React.FC = () => {
         throw new Error("Received an empty auth URL from the backend.");
       }
     } catch (error) {
-      const errorMessage = error instanceof Error ? error.message : String(error);
-      console.error("Failed to start OAuth flow:", errorMessage);
-      alert(`Failed to start authentication: ${errorMessage}`);
+      reportError('start authentication', error);
     }
   };
 
@@ -53,13 +337,63 @@ const CredentialsListPage: React.FC = () => {
       <Link to="/credentials/add">
         <button>Register New Credential</button>
       </Link>
+      {flow && (
+        <p>
+          OAuth flow (credential {flow.credential_id}): {flow.status}
+          {flow.error && ` - ${flow.error}`}
+          {flow.missing_scopes.length > 0 && ` (not granted: ${flow.missing_scopes.join(' ')})`}
+          {flow.status === 'waiting' && <button onClick={handleCancel}>Cancel</button>}
+        </p>
+      )}
+      {flow && deviceCode && deviceCode.flow_id === flow.flow_id && flow.status === 'waiting' && (
+        <p>
+          Open {deviceCode.verification_uri} on any device and enter the code <strong>{deviceCode.user_code}</strong>
+        </p>
+      )}
       <ul>
         {credentials.map((cred) => (
           <li key={cred.id}>
-            {cred.service_name} (ID: {cred.id})
+            {cred.service_name} [{cred.provider}] (ID: {cred.id})
+            {cred.has_secret && (
+              <span>
+                {' '}Secret: <code>{revealed[cred.id] ?? `••••${cred.secret_hint ?? ''}`}</code>
+                {revealed[cred.id] === undefined ? (
+                  <button onClick={() => handleReveal(cred.id)}>Reveal</button>
+                ) : (
+                  <button onClick={() => handleHide(cred.id)}>Hide</button>
+                )}
+              </span>
+            )}
             <button onClick={() => handleAuthenticate(cred.id)}>
-              Authenticate
+              Add Account
+            </button>
+            <button onClick={() => handleDeviceLogin(cred.id)}>
+              Device Login
             </button>
+            <button onClick={() => handleEdit(cred)}>Edit</button>
+            <button onClick={() => handleRotateSecret(cred)}>Rotate Secret</button>
+            <button onClick={() => handleDelete(cred)}>Delete</button>
+            <ul>
+              {(accounts[cred.id] ?? []).map((account) => (
+                <li key={account.id}>
+                  {account.email ?? account.name ?? account.subject ?? `Account ${account.id}`}
+                  {attention[account.id]?.status === 'needs_reauth' && (
+                    <strong> Needs re-authentication</strong>
+                  )}
+                  {attention[account.id]?.last_error && (
+                    <span title={attention[account.id].last_error_at ?? undefined}>
+                      {' '}(last refresh failed: {attention[account.id].last_error})
+                    </span>
+                  )}
+                  <button onClick={() => handleAuthenticate(cred.id, account.id)}>
+                    Re-authenticate
+                  </button>
+                  <button onClick={() => handleUnlink(account)}>
+                    Unlink
+                  </button>
+                </li>
+              ))}
+            </ul>
           </li>
         ))}
       </ul>
@@ -0,0 +1,80 @@
+import React, { useState } from 'react';
+import { invoke } from '@tauri-apps/api/core';
+import { errorMessage } from '../errors';
+
+// データベースのパスフレーズ変更（データキーを再ラップするだけで、保存値は再暗号化しない）
+const SecurityPage: React.FC = () => {
+  const [currentPassphrase, setCurrentPassphrase] = useState('');
+  const [newPassphrase, setNewPassphrase] = useState('');
+  const [confirmation, setConfirmation] = useState('');
+  const [error, setError] = useState('');
+  const [message, setMessage] = useState('');
+  const [busy, setBusy] = useState(false);
+
+  const handleSubmit = async (e: React.FormEvent) => {
+    e.preventDefault();
+    setError('');
+    setMessage('');
+
+    if (newPassphrase !== confirmation) {
+      setError('The new passphrases do not match.');
+      return;
+    }
+
+    setBusy(true);
+    try {
+      await invoke('change_database_passphrase', { currentPassphrase, newPassphrase });
+      setCurrentPassphrase('');
+      setNewPassphrase('');
+      setConfirmation('');
+      setMessage('Passphrase changed.');
+    } catch (err) {
+      setError(errorMessage(err));
+    } finally {
+      setBusy(false);
+    }
+  };
+
+  return (
+    <div>
+      <h1>Security</h1>
+      <h2>Change Database Passphrase</h2>
+      <form onSubmit={handleSubmit}>
+        <div>
+          <label htmlFor="currentPassphrase">Current Passphrase:</label>
+          <input
+            id="currentPassphrase"
+            type="password"
+            value={currentPassphrase}
+            onChange={(e) => setCurrentPassphrase(e.target.value)}
+          />
+        </div>
+        <div>
+          <label htmlFor="newPassphrase">New Passphrase:</label>
+          <input
+            id="newPassphrase"
+            type="password"
+            value={newPassphrase}
+            onChange={(e) => setNewPassphrase(e.target.value)}
+          />
+        </div>
+        <div>
+          <label htmlFor="confirmation">Confirm New Passphrase:</label>
+          <input
+            id="confirmation"
+            type="password"
+            value={confirmation}
+            onChange={(e) => setConfirmation(e.target.value)}
+          />
+        </div>
+        {error && <p style={{ color: 'red' }}>{error}</p>}
+        {message && <p style={{ color: 'green' }}>{message}</p>}
+        <button type="submit" disabled={busy || !currentPassphrase || !newPassphrase}>
+          Change Passphrase
+        </button>
+      </form>
+    </div>
+  );
+};
+
+export default SecurityPage;
@@ -0,0 +1,102 @@
+import React, { useState } from 'react';
+import { invoke } from '@tauri-apps/api/core';
+import { errorMessage, isAppError } from '../errors';
+
+// Backendの EncryptionState と型を合わせる
+export type EncryptionState = 'uninitialized' | 'locked' | 'unlocked';
+
+// Backendの PassphraseSet と型を合わせる
+interface PassphraseSet {
+  unencrypted_copies: string[];
+}
+
+interface UnlockPageProps {
+  state: Exclude<EncryptionState, 'unlocked'>;
+  onUnlocked: () => void;
+}
+
+// 資格情報/トークンは暗号化されており、パスフレーズで解錠するまで読めない
+const UnlockPage: React.FC<UnlockPageProps> = ({ state, onUnlocked }) => {
+  const [passphrase, setPassphrase] = useState('');
+  const [confirmation, setConfirmation] = useState('');
+  const [error, setError] = useState('');
+  const [busy, setBusy] = useState(false);
+  const isSetup = state === 'uninitialized';
+
+  const handleSubmit = async (e: React.FormEvent) => {
+    e.preventDefault();
+    setError('');
+
+    if (isSetup && passphrase !== confirmation) {
+      setError('The passphrases do not match.');
+      return;
+    }
+
+    setBusy(true);
+    try {
+      if (isSetup) {
+        const result = await invoke<PassphraseSet>('set_database_passphrase', { passphrase });
+        // 暗号化前に作られたコピーには秘密が平文のまま残っている
+        if (result.unencrypted_copies.length > 0) {
+          alert(
+            'These copies of the database were made before the passphrase was set and still contain client secrets and tokens unencrypted. '
+            + 'Delete them if they are no longer needed:\n\n' + result.unencrypted_copies.join('\n'),
+          );
+        }
+      } else {
+        await invoke('unlock_database', { passphrase });
+      }
+      setPassphrase('');
+      setConfirmation('');
+      onUnlocked();
+    } catch (err) {
+      // 誤ったパスフレーズは入力し直してもらう
+      if (isAppError(err) && err.code === 'incorrect_passphrase') {
+        setPassphrase('');
+      }
+      setError(errorMessage(err));
+    } finally {
+      setBusy(false);
+    }
+  };
+
+  return (
+    <div>
+      <h1>{isSetup ? 'Set a Database Passphrase' : 'Unlock Database'}</h1>
+      <p>
+        {isSetup
+          ? 'Client secrets and tokens are encrypted with a key protected by this passphrase. It cannot be recovered if you forget it.'
+          : 'Enter the passphrase to decrypt client secrets and tokens.'}
+      </p>
+      <form onSubmit={handleSubmit}>
+        <div>
+          <label htmlFor="passphrase">Passphrase:</label>
+          <input
+            id="passphrase"
+            type="password"
+            autoFocus
+            value={passphrase}
+            onChange={(e) => setPassphrase(e.target.value)}
+          />
+        </div>
+        {isSetup && (
+          <div>
+            <label htmlFor="confirmation">Confirm Passphrase:</label>
+            <input
+              id="confirmation"
+              type="password"
+              value={confirmation}
+              onChange={(e) => setConfirmation(e.target.value)}
+            />
+          </div>
+        )}
+        {error && <p style={{ color: 'red' }}>{error}</p>}
+        <button type="submit" disabled={busy || !passphrase}>
+          {isSetup ? 'Set Passphrase' : 'Unlock'}
+        </button>
+      </form>
+    </div>
+  );
+};
+
+export default UnlockPage;